@@ -1,16 +1,62 @@
 
 extern crate gg;
 
-use gg::err::GgResult;
+use gg::err::{GgError, GgResult};
+use gg::setup::{Transport, FoundServer, DISCOVERY_PORT, DISCOVERY_WAIT};
 use std::env;
+use std::time::Duration;
 
 pub fn main() -> GgResult { 
-    let args: Vec<String> = env::args().collect();
-    let server_addr = match args.len() {
-        2 => args[1].clone(),
-        _ => "etherdirect.co.uk:9001".to_string()
+    // watch the game rather than play in it
+    let is_spectator = env::args().any(|a| a == "--spectate");
+    // find a server on the local network rather than being told where one is
+    let is_lan = env::args().any(|a| a == "--lan");
+    let mut args: Vec<String> = env::args().filter(|a| a != "--spectate" && a != "--lan").collect();
+    // join the named room rather than the server's default one, opening it if no one is in it
+    let room = match args.iter().position(|a| a == "--room") {
+        Some(i) if i + 1 < args.len() => {
+            args.remove(i);
+            Some(args.remove(i))
+        },
+        Some(_) => return Err("--room should be followed by the name of a room".into()),
+        None => None
+    };
+    // ggc [--spectate] [--room <name>] [address] [tcp|udp] [interpolation delay in ms]
+    // ggc --lan [--spectate] [--room <name>] [interpolation delay in ms]
+    let (server_addr, transport, delay_arg) = if is_lan {
+        if args.len() > 2 {
+            return Err("--lan finds the server itself, so takes no address or transport".into());
+        }
+        let found_server = find_lan_server()?;
+        (found_server.addr.to_string(), found_server.info.transport.parse()?, args.get(1))
+    } else {
+        let server_addr = match args.len() {
+            2..=4 => args[1].clone(),
+            _ => "etherdirect.co.uk:9001".to_string()
+        };
+        let transport = match args.len() {
+            3 | 4 => args[2].parse()?,
+            _ => Transport::Tcp
+        };
+        (server_addr, transport, if args.len() == 4 { args.get(3) } else { None })
+    };
+
+    let interpolation_delay = match delay_arg {
+        Some(arg) => Duration::from_millis(arg.parse().map_err(|_| "the interpolation delay should be a number of milliseconds")?),
+        None => gg::setup::DEFAULT_INTERPOLATION_DELAY
     };
 
-    let mut env = gg::setup::new_client(&server_addr)?;
+    let mut env = gg::setup::new_client(&server_addr, transport, interpolation_delay, is_spectator, room)?;
     env.run()
-}
\ No newline at end of file
+}
+
+// lists the servers that answer a search of the local network and picks the first this build can play on
+fn find_lan_server() -> GgResult<FoundServer> {
+    let found_servers = gg::setup::discover(([255, 255, 255, 255], DISCOVERY_PORT).into(), DISCOVERY_WAIT)?;
+    for found_server in found_servers.iter() {
+        println!("found {}", found_server);
+    }
+    found_servers.into_iter()
+        .find(|found_server| found_server.info.is_compatible())
+        .ok_or_else(|| GgError::from("found no servers on the local network that this version can play on"))
+}
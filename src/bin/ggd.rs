@@ -2,17 +2,58 @@
 extern crate gg;
 #[cfg(feature = "server")]
 extern crate daemonize;
+#[cfg(feature = "server")]
+extern crate ctrlc;
 
 #[cfg(feature = "server")]
 use std::fs::File;
 #[cfg(feature = "server")]
 use daemonize::Daemonize;
+#[cfg(feature = "server")]
+use gg::setup::{Transport, PlayerLimit, DISCOVERY_PORT};
+#[cfg(feature = "server")]
+use gg::err::GgError;
+#[cfg(feature = "server")]
+use std::sync::Arc;
+#[cfg(feature = "server")]
+use std::sync::atomic::{AtomicBool, Ordering};
 use gg::err::GgResult;
 
 
 pub fn main() -> GgResult { 
     #[cfg(feature = "server")]
     {
+        // ggd [--lan] [--name <name>] [address] [tcp|udp] [max players per room] [max queue length per room]
+        // answer clients searching the local network for a server
+        let is_lan = std::env::args().any(|a| a == "--lan");
+        let mut args: Vec<String> = std::env::args().filter(|a| a != "--lan").collect();
+        // what clients searching the local network see the server as
+        let name = match args.iter().position(|a| a == "--name") {
+            Some(i) if i + 1 < args.len() => {
+                args.remove(i);
+                args.remove(i)
+            },
+            Some(_) => return Err("--name should be followed by the server's name".into()),
+            None => "ggd".to_string()
+        };
+        let addr = match args.get(1) {
+            Some(arg) => arg.parse().map_err(|_| GgError::Other(format!("invalid address '{}'", arg)))?,
+            None => "0.0.0.0:9001".parse().unwrap()
+        };
+        let transport = match args.get(2) {
+            Some(arg) => arg.parse()?,
+            None => Transport::Tcp
+        };
+        let parse_count = |arg: &String| arg.parse::<usize>().map_err(|_| GgError::Other(format!("invalid count '{}'", arg)));
+        // with no queue length, players beyond the limit are turned away
+        let player_limit = match (args.get(3), args.get(4)) {
+            (Some(max_players), max_queue_length) => Some(PlayerLimit{
+                max_players: parse_count(max_players)?,
+                max_queue_length: max_queue_length.map(parse_count).transpose()?.unwrap_or(0)
+            }),
+            (None, _) => None
+        };
+
         let stdout = File::create("/tmp/ggd.out").unwrap();
         let stderr = File::create("/tmp/ggd.err").unwrap();
 
@@ -24,10 +65,27 @@ pub fn main() -> GgResult {
 
         return match daemonize.start() {
             Ok(_) => {
-                let mut setup = gg::setup::new_server()?;
-                loop{
-                    setup.step().unwrap();
+                let mut setup = gg::setup::new_server(addr, transport, player_limit)?;
+                if is_lan {
+                    // another server on the same machine may have the port already
+                    if let Err(e) = setup.enable_discovery(&name, ([0, 0, 0, 0], DISCOVERY_PORT).into()) {
+                        println!("warning: clients on the local network won't find this server: {}", e);
+                    }
                 }
+
+                let is_stopping = Arc::new(AtomicBool::new(false));
+                let handler_is_stopping = is_stopping.clone();
+                ctrlc::set_handler(move || handler_is_stopping.store(true, Ordering::Relaxed))
+                    .map_err(|e| GgError::Other(e.to_string()))?;
+
+                while !is_stopping.load(Ordering::Relaxed) {
+                    if let Err(e) = setup.step() {
+                        setup.shutdown("the server crashed")?;
+                        return Err(e.into());
+                    }
+                }
+
+                setup.shutdown("the server is shutting down")
             },
             Err(e) => Err(e.into()),
         }
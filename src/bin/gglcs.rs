@@ -2,9 +2,25 @@
 extern crate gg;
 
 use gg::err::GgResult;
+use gg::setup::{LinkConditions, NetworkConditions};
+use std::env;
 use std::time::Duration;
 
+// each player's connection is a profile such as mobile, or an uplink and downlink profile such as
+// mobile/fibre. F1 swaps the two players' connections while playing
 pub fn main() -> GgResult { 
-    let mut environment = gg::setup::new_local_client_server(Duration::from_millis(50), true)?;
+    let args: Vec<String> = env::args().collect();
+    let default_link = LinkConditions::symmetric(NetworkConditions::ideal(Duration::from_millis(50)));
+    let client_1_link = match args.len() {
+        2 | 3 => args[1].parse()?,
+        _ => default_link.clone()
+    };
+    let client_2_link = match args.len() {
+        3 => args[2].parse()?,
+        2 => client_1_link.clone(),
+        _ => default_link
+    };
+
+    let mut environment = gg::setup::new_local_client_server(client_1_link, client_2_link, true)?;
     environment.run()
 }
@@ -7,9 +7,15 @@ use crate::component::body::radial::RadialBody;
 use crate::component::body::planar::PlanarBody;
 use crate::component::body::r#static::StaticBody;
 use serde::{Serialize, Deserialize};
+use std::collections::VecDeque;
+use std::time::Duration;
 
 pub const KEYFRAME_PERIOD: f32 = 0.25f32;
 
+// How far back the server remembers where everything was. A player lagging further behind than
+// this has their input resolved against the oldest state remembered
+pub const HISTORY_LENGTH: Duration = Duration::from_secs(1);
+
 #[derive(Clone)]
 #[derive(Deserialize)]
 #[derive(Serialize)]
@@ -85,4 +91,54 @@ impl Body {
             Body::Radial(b) => Body::Planar(b.to_planar())
         }
     }
-}
\ No newline at end of file
+}
+// where an entity's body was at each recent server tick, so that the server can look at the world
+// as a lagging player saw it
+pub struct History(pub VecDeque<(Duration, Body)>);
+
+impl History {
+    pub fn new() -> History {
+        History(VecDeque::new())
+    }
+
+    // adds the body as it is at the given time, forgetting anything more than HISTORY_LENGTH older
+    pub fn record(&mut self, time: Duration, body: Body) {
+        self.0.push_back((time, body));
+        while matches!(self.0.front(), Some((record_time, _)) if *record_time + HISTORY_LENGTH < time) {
+            self.0.pop_front();
+        }
+    }
+
+    // the body as it was at the given time, stepped on from the record before it. times before the
+    // oldest record get the oldest record
+    pub fn at(&self, time: Duration) -> Option<Body> {
+        match self.0.iter().rev().find(|(record_time, _)| *record_time <= time) {
+            Some((record_time, body)) => {
+                let mut body = body.clone();
+                body.step((time - *record_time).as_secs_f32());
+                Some(body)
+            },
+            None => self.0.front().map(|(_, body)| body.clone())
+        }
+    }
+}
+
+#[test]
+fn test_history() {
+    let mut subject = History::new();
+    assert_eq!(None, subject.at(Duration::from_secs(1)));
+
+    let body_at = |x: f32| Body::new_dynamic([x, 0.0].into(), [1.0, 0.0].into(), [0.0, 0.0].into());
+    for i in 0..=20 {
+        subject.record(Duration::from_millis(i * 100), body_at(i as f32 / 10.0));
+    }
+
+    // only the last second is kept
+    assert_eq!(11, subject.0.len());
+    assert_eq!(Some(body_at(1.0)), subject.at(Duration::from_millis(1000)));
+    assert_eq!(Some(body_at(1.0)), subject.at(Duration::from_millis(500)));
+
+    // and times between records are stepped on from the one before
+    let loc = subject.at(Duration::from_millis(1550)).unwrap().get_loc();
+    assert!((loc.x - 1.55).abs() < 0.001, "{}", loc);
+}
@@ -2,7 +2,199 @@ use crate::network::ClientMsg;
 use crate::network::RxChannel;
 use crate::network::ServerMsg;
 use crate::network::TxChannel;
+use crate::component::body::Body;
+use crate::component::sprite::Sprite;
+use crate::input::InputEvent;
+use crate::input::Button;
+use recs::EntityId;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
 
-pub struct Latency(pub f32);
+// How many inputs a client may send at once, and how many a second after that. Well beyond what a
+// player mashing both buttons can manage, even with a stalled connection delivering them together
+pub const INPUT_BURST: f32 = 20.0;
+pub const INPUT_RATE: f32 = 20.0;
 
-pub struct Client<TNetwork>(pub TNetwork) where TNetwork: TxChannel<ServerMsg> + RxChannel<ClientMsg>;
\ No newline at end of file
+// A client that sends this many invalid inputs within the window is kicked. The odd one is
+// forgiven, as a key released while the window was out of focus is enough to cause it
+pub const MAX_INPUT_VIOLATIONS: usize = 5;
+pub const INPUT_VIOLATION_WINDOW: Duration = Duration::from_secs(10);
+
+// when the server last received anything from this client
+pub struct LastHeard(pub Duration);
+
+// the last input the server applied to this client's gorilla, by the client's clock and the server's
+pub struct LastInput{
+    pub seq: u32,
+    pub client_time: Duration,
+    pub server_time: Duration
+}
+
+// how far behind the latest snapshot the client draws other entities
+pub struct InterpolationDelay(pub Duration);
+
+// a client watching the game rather than playing, and the gorilla its camera follows
+pub struct Spectator{
+    pub following: Option<EntityId>
+}
+
+// identifies the session that owns a gorilla so that a reconnecting client can reclaim it
+pub struct Session(pub u64);
+
+// a gorilla whose client has dropped out, kept in the game since the given time in case they come back
+pub struct Parked(pub Duration);
+
+// what the server has sent to this client, keyed by network id, so that only changes need sending.
+// it is all sent reliably so the client is bound to have it. bodies are not part of it: they go
+// unreliably, so the server can't know which one the client has, and are sent in full as keyframes
+#[derive(Default)]
+pub struct Baseline{
+    // the entities near enough to the client's gorilla to have been spawned on the client
+    pub entities: HashSet<u64>,
+    pub sprites: HashMap<u64, Sprite>,
+    pub focuses: HashSet<u64>,
+    pub anchors: HashSet<u64>
+}
+
+impl Baseline {
+    // for when the client no longer has the entity, so that it is sent in full should it come back
+    pub fn forget(&mut self, id: u64) {
+        self.entities.remove(&id);
+        self.sprites.remove(&id);
+        self.focuses.remove(&id);
+        self.anchors.remove(&id);
+    }
+}
+
+// what the server has seen of a client's input, to catch clients sending what no player could
+pub struct InputGuard{
+    buttons_down: HashSet<Button>,
+    last_seq: Option<u32>,
+    // how many more inputs the client may send right now, topped up at INPUT_RATE
+    allowance: f32,
+    last_update_time: Duration,
+    violation_times: VecDeque<Duration>
+}
+
+impl InputGuard {
+    pub fn new(time: Duration) -> InputGuard {
+        InputGuard{
+            buttons_down: HashSet::new(),
+            last_seq: None,
+            allowance: INPUT_BURST,
+            last_update_time: time,
+            violation_times: VecDeque::new()
+        }
+    }
+
+    // says why an input that arrived at the given time should be thrown away, if it should
+    pub fn check(&mut self, input_event: &InputEvent, time: Duration) -> Result<(), String> {
+        let elapsed = time.saturating_sub(self.last_update_time).as_secs_f32();
+        self.allowance = (self.allowance + elapsed * INPUT_RATE).min(INPUT_BURST);
+        self.last_update_time = time;
+
+        if self.allowance < 1.0 {
+            return Err("too many inputs".to_string());
+        }
+        self.allowance -= 1.0;
+
+        if matches!(self.last_seq, Some(last_seq) if input_event.seq <= last_seq) {
+            return Err(format!("input #{} is out of sequence", input_event.seq));
+        }
+        self.last_seq = Some(input_event.seq);
+
+        if input_event.is_down && !self.buttons_down.insert(input_event.button) {
+            return Err(format!("{:?} pressed while already down", input_event.button));
+        }
+        if !input_event.is_down && !self.buttons_down.remove(&input_event.button) {
+            return Err(format!("{:?} released without being pressed", input_event.button));
+        }
+
+        Ok(())
+    }
+
+    // returns whether the client has now broken the rules too often to stay
+    pub fn add_violation(&mut self, time: Duration) -> bool {
+        while matches!(self.violation_times.front(), Some(&t) if time.saturating_sub(t) > INPUT_VIOLATION_WINDOW) {
+            self.violation_times.pop_front();
+        }
+        self.violation_times.push_back(time);
+        self.violation_times.len() >= MAX_INPUT_VIOLATIONS
+    }
+}
+
+// bodies received from the server for an entity this client does not control, by the time they arrived
+pub struct Snapshots(pub VecDeque<(Duration, Body)>);
+
+pub struct Client<TNetwork>(pub TNetwork) where TNetwork: TxChannel<ServerMsg> + RxChannel<ClientMsg>;
+
+#[cfg(test)]
+fn input(button: Button, is_down: bool, seq: u32) -> InputEvent {
+    InputEvent{ button, is_down, seq, time: Duration::from_millis(0) }
+}
+
+#[test]
+fn test_input_guard() {
+    let mut subject = InputGuard::new(Duration::from_secs(0));
+    let time = Duration::from_secs(1);
+
+    // presses and releases that match up are fine, in any order across buttons
+    assert_eq!(Ok(()), subject.check(&input(Button::One, true, 1), time));
+    assert_eq!(Ok(()), subject.check(&input(Button::Two, true, 2), time));
+    assert_eq!(Ok(()), subject.check(&input(Button::One, false, 3), time));
+    assert_eq!(Ok(()), subject.check(&input(Button::Two, false, 4), time));
+
+    // but not ones that don't
+    assert!(subject.check(&input(Button::One, false, 5), time).is_err());
+    assert_eq!(Ok(()), subject.check(&input(Button::One, true, 6), time));
+    assert!(subject.check(&input(Button::One, true, 7), time).is_err());
+
+    // nor replays
+    assert!(subject.check(&input(Button::One, false, 7), time).is_err());
+    assert_eq!(Ok(()), subject.check(&input(Button::One, false, 8), time));
+}
+
+#[test]
+fn test_input_rate() {
+    let mut subject = InputGuard::new(Duration::from_secs(0));
+    let mut seq = 0;
+    let mut is_down = false;
+    let mut toggle = |subject: &mut InputGuard, time: Duration| {
+        seq += 1;
+        let result = subject.check(&input(Button::One, !is_down, seq), time);
+        if result.is_ok() {
+            is_down = !is_down;
+        }
+        result
+    };
+
+    // a burst is allowed up to a point
+    let time = Duration::from_secs(0);
+    for _ in 0..INPUT_BURST as u32 {
+        assert_eq!(Ok(()), toggle(&mut subject, time));
+    }
+    assert_eq!(Err("too many inputs".to_string()), toggle(&mut subject, time));
+
+    // after which the allowance comes back at the steady rate
+    let time = Duration::from_millis(100);
+    assert_eq!(Ok(()), toggle(&mut subject, time));
+    assert_eq!(Ok(()), toggle(&mut subject, time));
+    assert!(toggle(&mut subject, time).is_err());
+}
+
+#[test]
+fn test_input_violations() {
+    let mut subject = InputGuard::new(Duration::from_secs(0));
+
+    // violations spread out are forgiven
+    for i in 0..10 {
+        assert!(!subject.add_violation(INPUT_VIOLATION_WINDOW * i));
+    }
+
+    // but not ones close together
+    let time = INPUT_VIOLATION_WINDOW * 20;
+    for _ in 1..MAX_INPUT_VIOLATIONS {
+        assert!(!subject.add_violation(time));
+    }
+    assert!(subject.add_violation(time));
+}
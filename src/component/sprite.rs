@@ -27,4 +27,60 @@ impl Sprite{
             src_size
         }
     }
-}
\ No newline at end of file
+}
+// the fields of a sprite that have changed since it was last sent. location and orientation are
+// left out since clients work those out from the body
+#[derive(Clone)]
+#[derive(Deserialize)]
+#[derive(Serialize)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct SpriteDelta{
+    pub color: Option<Color>,
+    pub size: Option<Vector2<f32>>,
+    pub src_loc: Option<Vector2<f32>>,
+    pub src_size: Option<Vector2<f32>>
+}
+
+impl Sprite{
+    pub fn diff(&self, baseline: &Sprite) -> Option<SpriteDelta> {
+        let delta = SpriteDelta{
+            color: Some(self.color).filter(|&color| color != baseline.color),
+            size: Some(self.size).filter(|&size| size != baseline.size),
+            src_loc: Some(self.src_loc).filter(|&src_loc| src_loc != baseline.src_loc),
+            src_size: Some(self.src_size).filter(|&src_size| src_size != baseline.src_size)
+        };
+
+        if delta.color.is_none() && delta.size.is_none() && delta.src_loc.is_none() && delta.src_size.is_none() {
+            None
+        } else {
+            Some(delta)
+        }
+    }
+
+    pub fn apply(&mut self, delta: &SpriteDelta) {
+        if let Some(color) = delta.color { self.color = color; }
+        if let Some(size) = delta.size { self.size = size; }
+        if let Some(src_loc) = delta.src_loc { self.src_loc = src_loc; }
+        if let Some(src_size) = delta.src_size { self.src_size = src_size; }
+    }
+}
+
+#[test]
+fn test_diff() {
+    let baseline = Sprite::new(crate::colors::WHITE, [1.0, 1.0].into(), [0.0, 0.0].into(), [0.5, 0.5].into());
+
+    let mut moved = baseline.clone();
+    moved.location = [3.0, 4.0].into();
+    moved.orientation = 1.0;
+    assert_eq!(None, moved.diff(&baseline));
+
+    let mut tagged = baseline.clone();
+    tagged.src_loc = [0.0, 0.5].into();
+    let delta = tagged.diff(&baseline).unwrap();
+    assert_eq!(SpriteDelta{ color: None, size: None, src_loc: Some([0.0, 0.5].into()), src_size: None }, delta);
+
+    let mut applied = baseline.clone();
+    applied.apply(&delta);
+    assert_eq!(tagged, applied);
+}
@@ -45,6 +45,15 @@ impl<TContext> Engine<TContext> {
         Ok(())
     }
 
+    pub fn shutdown(&mut self, context: &TContext, reason: &str) -> GgResult {
+
+        for system in self.systems.iter_mut() {
+            system.shutdown(&mut self.state, context, reason)?;
+        }
+
+        Ok(())
+    }
+
     pub fn draw(&mut self, context: &mut TContext) -> GgResult {
 
         for system in self.systems.iter_mut() {
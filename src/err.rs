@@ -3,60 +3,79 @@ use ggez::GameError;
 use std::convert::From;
 
 #[derive(Debug)]
-pub struct GgError(pub String);
+pub enum GgError {
+    Other(String),
+    // a peer sent a frame longer than the receiving end allows
+    FrameTooLarge{ length: usize, max_length: usize },
+    // a peer sent a frame that could not be decoded
+    MalformedFrame(String),
+    // nothing has been received from the other end for this long
+    ConnectionTimedOut(std::time::Duration)
+}
+
+impl std::fmt::Display for GgError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            GgError::Other(msg) => write!(f, "{}", msg),
+            GgError::FrameTooLarge{ length, max_length } => write!(f, "frame of {} bytes exceeds the maximum of {} bytes", length, max_length),
+            GgError::MalformedFrame(msg) => write!(f, "malformed frame: {}", msg),
+            GgError::ConnectionTimedOut(silence) => write!(f, "connection timed out after {:?} of silence", silence)
+        }
+    }
+}
 
 impl From<GameError> for GgError {
     fn from(error: GameError) -> Self {
-        GgError(error.to_string())
+        GgError::Other(error.to_string())
     }
 }
 
 impl From<GgError> for GameError {
     fn from(error: GgError) -> Self {
-        ggez::GameError::FilesystemError(error.0) // TODO change?
+        ggez::GameError::FilesystemError(error.to_string()) // TODO change?
     }
 }
 
 impl From<&str> for GgError {
     fn from(error: &str) -> Self {
-        GgError(error.to_string())
+        GgError::Other(error.to_string())
     }
 }
 
 impl<T> From<SendError<T>> for GgError {
     fn from(error: SendError<T>) -> Self {
-        GgError(error.to_string())
+        GgError::Other(error.to_string())
     }
 }
 
 impl From<std::io::Error> for GgError {
     fn from(error: std::io::Error) -> Self {
-        GgError(error.to_string())
+        GgError::Other(error.to_string())
     }
 }
 
 impl From<serde_cbor::error::Error> for GgError {
     fn from(error: serde_cbor::error::Error) -> Self {
-        GgError(error.to_string())
+        GgError::Other(error.to_string())
     }
 }
 
 impl From<std::sync::mpsc::RecvError> for GgError {
     fn from(error: std::sync::mpsc::RecvError) -> Self {
-        GgError(error.to_string())
+        GgError::Other(error.to_string())
     }
 }
 
 impl From<recs::NotFound> for GgError {
     fn from(_: recs::NotFound) -> Self {
-        GgError("Entity not found".to_string())
+        GgError::Other("Entity not found".to_string())
     }
 }
 
 #[cfg(feature = "server")]
 impl From<daemonize::DaemonizeError> for GgError {
     fn from(error: daemonize::DaemonizeError) -> Self {
-        GgError(error.to_string())
+        GgError::Other(error.to_string())
     }
 }
 
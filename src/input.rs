@@ -1,12 +1,15 @@
 use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
 use ggez::input::keyboard::KeyCode;
+use std::time::Duration;
 
 #[derive(Clone)]
 #[derive(Copy)]
 #[derive(Deserialize)]
 #[derive(Serialize)]
 #[derive(PartialEq)]
+#[derive(Eq)]
+#[derive(Hash)]
 #[derive(Debug)]
 pub enum Button {
     One,
@@ -20,7 +23,11 @@ pub enum Button {
 #[derive(Debug)]
 pub struct InputEvent{
     pub button: Button,
-    pub is_down: bool
+    pub is_down: bool,
+    // numbered by the client so the server can say which inputs its state reflects
+    pub seq: u32,
+    // when the input happened by the client's clock
+    pub time: Duration
 }
 
 pub type KeyMapping = HashMap<KeyCode, Button>;
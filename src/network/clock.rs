@@ -0,0 +1,137 @@
+use crate::network::Pong;
+use std::time::Duration;
+
+// How often each end pings the other to keep its estimates up to date
+pub const CLOCK_SYNC_PERIOD: Duration = Duration::from_secs(1);
+
+// The sequenced streams that pings and pongs are sent on, well clear of any entity id. A lost ping
+// is better skipped than resent, since the resend would arrive late and spoil the measurement. Each
+// has a stream of its own so that a ping never supersedes a pong going the same way
+pub const CLOCK_PING_STREAM: u64 = u64::MAX;
+pub const CLOCK_PONG_STREAM: u64 = u64::MAX - 1;
+
+// How far each new sample moves the estimates, as in TCP's round trip time estimation (RFC 6298)
+const RTT_GAIN: f64 = 0.125;
+const JITTER_GAIN: f64 = 0.25;
+const OFFSET_GAIN: f64 = 0.125;
+
+// How many times the jitter a round trip may exceed the average by before its offset is ignored
+const OUTLIER_THRESHOLD: f64 = 4.0;
+
+// Estimates the round trip time to the other end of a connection, how much it varies, and how far
+// the other end's clock is ahead of ours, from the pongs it sends back in reply to our pings
+pub struct ClockSync {
+    // all in seconds, and the rtt is None until the first sample
+    rtt: Option<f64>,
+    jitter: f64,
+    offset: f64
+}
+
+impl ClockSync {
+    pub fn new() -> ClockSync {
+        ClockSync{
+            rtt: None,
+            jitter: 0.0,
+            offset: 0.0
+        }
+    }
+
+    // takes a pong that arrived at the given time by our clock
+    pub fn add_sample(&mut self, pong: &Pong, receive_time: Duration) {
+        if receive_time < pong.ping_time {
+            return;
+        }
+
+        let rtt = (receive_time - pong.ping_time).as_secs_f64();
+        // the other end replied at reply_time by its clock, which we take to be halfway through the
+        // round trip by ours. any difference between the up and down latency shows up as an error here
+        let offset = pong.reply_time.as_secs_f64() - (pong.ping_time.as_secs_f64() + rtt / 2.0);
+
+        match self.rtt {
+            None => {
+                self.rtt = Some(rtt);
+                self.jitter = rtt / 2.0;
+                self.offset = offset;
+            },
+            Some(average_rtt) => {
+                // a round trip that took much longer than usual was probably held up more in one
+                // direction than the other, which throws its offset out
+                if rtt <= average_rtt + OUTLIER_THRESHOLD * self.jitter {
+                    self.offset += OFFSET_GAIN * (offset - self.offset);
+                }
+                self.jitter += JITTER_GAIN * ((rtt - average_rtt).abs() - self.jitter);
+                self.rtt = Some(average_rtt + RTT_GAIN * (rtt - average_rtt));
+            }
+        }
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt.map(Duration::from_secs_f64)
+    }
+
+    // how long a message usually takes to get to the other end, assuming both ways take as long
+    pub fn latency(&self) -> Option<Duration> {
+        self.rtt.map(|rtt| Duration::from_secs_f64(rtt / 2.0))
+    }
+
+    // the average difference between one round trip and the average
+    pub fn jitter(&self) -> Duration {
+        Duration::from_secs_f64(self.jitter)
+    }
+
+    // the time by the other end's clock at the given time by ours
+    pub fn remote_time(&self, local_time: Duration) -> Option<Duration> {
+        self.rtt.map(|_| Duration::from_secs_f64((local_time.as_secs_f64() + self.offset).max(0.0)))
+    }
+
+    // the time by our clock at the given time by the other end's
+    pub fn local_time(&self, remote_time: Duration) -> Option<Duration> {
+        self.rtt.map(|_| Duration::from_secs_f64((remote_time.as_secs_f64() - self.offset).max(0.0)))
+    }
+}
+
+#[cfg(test)]
+fn exchange(clock_sync: &mut ClockSync, ping_time_ms: u64, up_ms: u64, down_ms: u64, remote_ahead_ms: u64) {
+    let ping_time = Duration::from_millis(ping_time_ms);
+    let reply_time = Duration::from_millis(ping_time_ms + up_ms + remote_ahead_ms);
+    let receive_time = Duration::from_millis(ping_time_ms + up_ms + down_ms);
+    clock_sync.add_sample(&Pong{ ping_time, reply_time }, receive_time);
+}
+
+#[test]
+fn test_clock_sync() {
+    let mut subject = ClockSync::new();
+    assert_eq!(None, subject.rtt());
+    assert_eq!(None, subject.remote_time(Duration::from_secs(1)));
+
+    // the other end's clock is 10s ahead and each round trip takes 100ms
+    exchange(&mut subject, 0, 50, 50, 10_000);
+    assert_eq!(Some(Duration::from_millis(100)), subject.rtt());
+    assert_eq!(Some(Duration::from_millis(50)), subject.latency());
+    assert_eq!(Some(Duration::from_millis(11_000)), subject.remote_time(Duration::from_secs(1)));
+    assert_eq!(Some(Duration::from_secs(1)), subject.local_time(Duration::from_millis(11_000)));
+
+    // steady conditions settle the jitter
+    for i in 1..50 {
+        exchange(&mut subject, i * 1000, 50, 50, 10_000);
+    }
+    assert!(subject.jitter() < Duration::from_millis(1));
+
+    // a single slow round trip only moves the average an eighth of the way, and its skewed offset is ignored
+    exchange(&mut subject, 50_000, 450, 50, 10_000);
+    assert!(subject.rtt().unwrap() <= Duration::from_millis(150));
+    assert!(subject.jitter() > Duration::from_millis(50));
+    assert_eq!(Some(Duration::from_millis(61_000)), subject.remote_time(Duration::from_secs(51)));
+
+    // while a lasting change in conditions is followed
+    for i in 51..100 {
+        exchange(&mut subject, i * 1000, 100, 100, 10_000);
+    }
+    let rtt = subject.rtt().unwrap();
+    assert!(rtt > Duration::from_millis(195) && rtt < Duration::from_millis(205), "{:?}", rtt);
+    assert_eq!(Some(Duration::from_millis(110_000)), subject.remote_time(Duration::from_secs(100)));
+
+    // a pong that can't be a reply to any ping of ours is ignored
+    subject.add_sample(&Pong{ ping_time: Duration::from_secs(200), reply_time: Duration::from_secs(0) }, Duration::from_secs(100));
+    assert_eq!(Some(Duration::from_millis(110_000)), subject.remote_time(Duration::from_secs(100)));
+}
@@ -0,0 +1,265 @@
+use crate::err::{GgError, GgResult};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::io::{Read, Write};
+#[cfg(test)]
+use crate::network::{ClientMsg, ServerMsg, Hello, Welcome, InputAck, Pong, CAPABILITIES};
+#[cfg(test)]
+use std::time::Duration;
+
+// Frames smaller than this gain too little from compression to be worth the time
+const COMPRESSION_THRESHOLD: usize = 1024;
+
+// The first byte of every frame says how the rest of it is encoded
+const CBOR_FRAME: u8 = 0;
+const BINCODE_FRAME: u8 = 1;
+const COMPRESSED_BINCODE_FRAME: u8 = 2;
+
+// How a RealNetwork turns messages into bytes once the handshake has agreed one. Since every frame
+// is tagged with the encoding used, a receiver can read any of them, and a sender can switch as soon
+// as the Welcome has gone. The handshake itself is always untagged CBOR.
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum Codec {
+    // self describing, and what a peer that offers nothing else gets
+    Cbor,
+    // smaller and quicker, but relies on both ends having identical message definitions
+    Bincode,
+    // bincode, with frames deflated when they are large, such as the snapshot sent on joining
+    CompressedBincode
+}
+
+// most preferred first
+const PREFERENCE: [Codec; 3] = [Codec::CompressedBincode, Codec::Bincode, Codec::Cbor];
+
+impl Codec {
+    // the Hello capability that offers this codec, if it has to be offered at all
+    pub fn capability(self) -> Option<&'static str> {
+        match self {
+            Codec::Cbor => None,
+            Codec::Bincode => Some("codec-bincode"),
+            Codec::CompressedBincode => Some("codec-compressed-bincode")
+        }
+    }
+
+    // the codec both ends use once the handshake has settled on the given capabilities
+    pub fn negotiate(capabilities: &[String]) -> Codec {
+        PREFERENCE.iter()
+            .cloned()
+            .find(|codec| match codec.capability() {
+                Some(capability) => capabilities.iter().any(|c| c == capability),
+                None => true
+            })
+            .unwrap_or(Codec::Cbor)
+    }
+
+    pub fn encode<TMsg>(self, msg: &TMsg) -> GgResult<Vec<u8>> where TMsg: Serialize {
+        match self {
+            Codec::Cbor => Ok(serde_cbor::to_vec(msg)?),
+            Codec::Bincode | Codec::CompressedBincode => bincode::serialize(msg)
+                .map_err(|e| GgError::Other(e.to_string()))
+        }
+    }
+
+    pub fn decode<TMsg>(self, bytes: &[u8]) -> GgResult<TMsg> where TMsg: DeserializeOwned {
+        match self {
+            Codec::Cbor => serde_cbor::from_slice(bytes)
+                .map_err(|e| GgError::MalformedFrame(e.to_string())),
+            Codec::Bincode | Codec::CompressedBincode => bincode::deserialize(bytes)
+                .map_err(|e| GgError::MalformedFrame(e.to_string()))
+        }
+    }
+
+    // appends the tag and the encoded messages of a frame, compressing them if it is worthwhile
+    pub fn seal_frame(self, msgs: &[u8], frame: &mut Vec<u8>) -> GgResult {
+        match self {
+            Codec::Cbor => frame.push(CBOR_FRAME),
+            Codec::Bincode => frame.push(BINCODE_FRAME),
+            Codec::CompressedBincode if msgs.len() < COMPRESSION_THRESHOLD => frame.push(BINCODE_FRAME),
+            Codec::CompressedBincode => {
+                frame.push(COMPRESSED_BINCODE_FRAME);
+                let mut encoder = flate2::write::DeflateEncoder::new(frame, flate2::Compression::fast());
+                encoder.write_all(msgs)?;
+                encoder.finish()?;
+                return Ok(());
+            }
+        }
+
+        frame.extend_from_slice(msgs);
+        Ok(())
+    }
+
+    // whether the frame starts with a codec tag. frames sent during the handshake are a bare CBOR
+    // Envelope instead, which starts with a map and so can't be mistaken for one
+    pub fn is_tagged_frame(frame: &[u8]) -> bool {
+        matches!(frame.first(), Some(&CBOR_FRAME) | Some(&BINCODE_FRAME) | Some(&COMPRESSED_BINCODE_FRAME))
+    }
+
+    // the codec that the messages in a frame were encoded with, and the messages themselves
+    pub fn open_frame(frame: &[u8], max_length: usize) -> GgResult<(Codec, Cow<'_, [u8]>)> {
+        match frame.split_first() {
+            Some((&CBOR_FRAME, msgs)) => Ok((Codec::Cbor, Cow::Borrowed(msgs))),
+            Some((&BINCODE_FRAME, msgs)) => Ok((Codec::Bincode, Cow::Borrowed(msgs))),
+            Some((&COMPRESSED_BINCODE_FRAME, compressed)) => {
+                // a tiny frame can inflate to something huge, so the limit applies after decompression too
+                let mut msgs = vec![];
+                flate2::read::DeflateDecoder::new(compressed)
+                    .take(max_length as u64 + 1)
+                    .read_to_end(&mut msgs)
+                    .map_err(|e| GgError::MalformedFrame(e.to_string()))?;
+                if msgs.len() > max_length {
+                    return Err(GgError::FrameTooLarge{ length: msgs.len(), max_length });
+                }
+                Ok((Codec::Bincode, Cow::Owned(msgs)))
+            },
+            Some((tag, _)) => Err(GgError::MalformedFrame(format!("unknown codec {}", tag))),
+            None => Err(GgError::MalformedFrame("empty frame".to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+fn all_server_msgs() -> Vec<ServerMsg> {
+    let body = crate::component::body::Body::new_dynamic([1.0, 2.0].into(), [3.0, 4.0].into(), [0.0, -10.0].into());
+    let sprite = crate::component::sprite::Sprite::new(crate::colors::WHITE, [1.0, 1.0].into(), [0.0, 0.5].into(), [0.5, 0.5].into());
+    let msgs = vec![
+        ServerMsg::Welcome(Welcome{ capabilities: vec!["codec-bincode".to_string()], session_token: 42 }),
+        ServerMsg::Reject("go away".to_string()),
+        ServerMsg::Queued(3),
+        ServerMsg::Shutdown{ reason: "maintenance".to_string() },
+        ServerMsg::Kill(1),
+        ServerMsg::Despawn(1),
+        ServerMsg::SetBody(2, body.clone(), Duration::from_millis(350)),
+        ServerMsg::SetBody(3, body.to_attached([0.0, 5.0].into()), Duration::from_millis(350)),
+        ServerMsg::SetBody(4, crate::component::body::Body::new_static([1.0, 1.0].into()), Duration::from_millis(350)),
+        ServerMsg::SetOwnBody(5, body, InputAck{ seq: 7, time: Duration::from_millis(350) }),
+        ServerMsg::SetSprite(6, sprite.clone()),
+        ServerMsg::UpdateSprite(6, sprite.diff(&crate::component::sprite::Sprite::new(crate::colors::RED, [1.0, 1.0].into(), [0.0, 0.0].into(), [0.5, 0.5].into())).unwrap()),
+        ServerMsg::SetFocus(7),
+        ServerMsg::SetAnchor(8),
+        ServerMsg::Ping(Duration::from_millis(1234)),
+        ServerMsg::Pong(Pong{ ping_time: Duration::from_millis(1234), reply_time: Duration::from_millis(5678) }),
+        ServerMsg::Heartbeat,
+        ServerMsg::Test(9)
+    ];
+
+    // fails to compile when a variant is added, as a reminder to add it above
+    for msg in msgs.iter() {
+        match msg {
+            ServerMsg::Welcome(_) | ServerMsg::Reject(_) | ServerMsg::Queued(_) | ServerMsg::Shutdown{ .. } | ServerMsg::Kill(_) | ServerMsg::Despawn(_) |
+            ServerMsg::SetBody(_, _, _) | ServerMsg::SetOwnBody(_, _, _) | ServerMsg::SetSprite(_, _) |
+            ServerMsg::UpdateSprite(_, _) | ServerMsg::SetFocus(_) | ServerMsg::SetAnchor(_) |
+            ServerMsg::Ping(_) | ServerMsg::Pong(_) | ServerMsg::Heartbeat | ServerMsg::Test(_) => {}
+        }
+    }
+
+    msgs
+}
+
+#[cfg(test)]
+fn all_client_msgs() -> Vec<ClientMsg> {
+    let input_event = crate::input::InputEvent{ button: crate::input::Button::One, is_down: true, seq: 3, time: Duration::from_millis(100) };
+    let msgs = vec![
+        ClientMsg::Hello(Hello::new(None)),
+        ClientMsg::Hello(Hello::new(Some(42))),
+        ClientMsg::Hello(Hello{ is_spectator: true, ..Hello::new(None) }),
+        ClientMsg::Hello(Hello{ room: Some("jungle".to_string()), ..Hello::new(None) }),
+        ClientMsg::Goodbye,
+        ClientMsg::Input(input_event),
+        ClientMsg::SetInterpolationDelay(Duration::from_millis(300)),
+        ClientMsg::CycleFocus{ forward: false },
+        ClientMsg::Ping(Duration::from_millis(1234)),
+        ClientMsg::Pong(Pong{ ping_time: Duration::from_millis(1234), reply_time: Duration::from_millis(5678) }),
+        ClientMsg::Heartbeat,
+        ClientMsg::Test(9)
+    ];
+
+    // fails to compile when a variant is added, as a reminder to add it above
+    for msg in msgs.iter() {
+        match msg {
+            ClientMsg::Hello(_) | ClientMsg::Goodbye | ClientMsg::Input(_) | ClientMsg::SetInterpolationDelay(_) | ClientMsg::CycleFocus{ .. } |
+            ClientMsg::Ping(_) | ClientMsg::Pong(_) | ClientMsg::Heartbeat | ClientMsg::Test(_) => {}
+        }
+    }
+
+    msgs
+}
+
+#[cfg(test)]
+fn expect_round_trip<TMsg>(codec: Codec, msgs: Vec<TMsg>) where TMsg: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug {
+    for msg in msgs {
+        let mut msgs = codec.encode(&msg).unwrap();
+        let mut frame = vec![];
+        codec.seal_frame(&msgs, &mut frame).unwrap();
+
+        let (frame_codec, opened) = Codec::open_frame(&frame, 4096).unwrap();
+        assert_eq!(&msgs[..], &opened[..]);
+        msgs = opened.into_owned();
+        assert_eq!(msg, frame_codec.decode::<TMsg>(&msgs).unwrap());
+    }
+}
+
+#[test]
+fn test_round_trip() {
+    for &codec in PREFERENCE.iter() {
+        expect_round_trip(codec, all_server_msgs());
+        expect_round_trip(codec, all_client_msgs());
+    }
+}
+
+#[test]
+fn test_compression() {
+    let msg = ServerMsg::Reject("x".repeat(5000));
+    let msgs = Codec::CompressedBincode.encode(&msg).unwrap();
+    let mut frame = vec![];
+    Codec::CompressedBincode.seal_frame(&msgs, &mut frame).unwrap();
+    assert!(frame.len() < 1000);
+
+    let (codec, opened) = Codec::open_frame(&frame, 8192).unwrap();
+    assert_eq!(msg, codec.decode::<ServerMsg>(&opened).unwrap());
+
+    // the frame limit covers what it inflates to
+    match Codec::open_frame(&frame, 4096) {
+        Err(GgError::FrameTooLarge{ max_length: 4096, .. }) => {},
+        result => panic!("expected the frame to be too large but got {:?}", result.map(|(codec, _)| codec))
+    }
+}
+
+#[test]
+fn test_negotiate() {
+    let offered = CAPABILITIES.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+    assert_eq!(Codec::CompressedBincode, Codec::negotiate(&offered));
+    assert_eq!(Codec::Bincode, Codec::negotiate(&["codec-bincode".to_string()]));
+    assert_eq!(Codec::Cbor, Codec::negotiate(&[]));
+
+    // every codec this build can negotiate is offered
+    for codec in PREFERENCE.iter() {
+        if let Some(capability) = codec.capability() {
+            assert!(CAPABILITIES.contains(&capability));
+        }
+    }
+}
+
+#[test]
+fn test_older_hello() {
+    // a Hello from before spectators and rooms, which the handshake must still be able to read so that it can
+    // report the version mismatch
+    #[derive(Serialize)]
+    struct OlderHello {
+        version: u32,
+        capabilities: Vec<String>,
+        session_token: Option<u64>
+    }
+    #[derive(Serialize)]
+    enum OlderClientMsg {
+        Hello(OlderHello)
+    }
+
+    let msg = OlderClientMsg::Hello(OlderHello{ version: 7, capabilities: vec![], session_token: Some(42) });
+    let encoded = Codec::Cbor.encode(&msg).unwrap();
+    let expected = ClientMsg::Hello(Hello{ version: 7, capabilities: vec![], session_token: Some(42), is_spectator: false, room: None });
+    assert_eq!(expected, Codec::Cbor.decode::<ClientMsg>(&encoded).unwrap());
+}
@@ -0,0 +1,288 @@
+use crate::network::PROTOCOL_VERSION;
+use crate::network::codec::Codec;
+use crate::err::GgResult;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+#[cfg(test)]
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// The port servers listen on for searches, whichever port they take games on
+pub const DISCOVERY_PORT: u16 = 9002;
+
+// How long a search waits for servers to answer
+pub const DISCOVERY_WAIT: Duration = Duration::from_secs(1);
+
+// Every discovery datagram starts with this, so that anything else sent to the port is ignored
+const DISCOVERY_TAG: &[u8] = b"gg-discovery";
+
+// An answer has to fit in one datagram, so a server with more rooms than this lists only the busiest
+const MAX_LISTED_ROOMS: usize = 64;
+
+// How many datagrams are read each call at most, so that a flood of searches can't hold up the
+// server's tick or have it send a flood of answers on to whoever the searches claim to be from
+const MAX_SEARCHES_PER_RESPOND: usize = 16;
+
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+// Discovery datagrams are CBOR, which is self describing, so that a server and client that don't
+// share a protocol version can still find out that they don't
+#[derive(Deserialize)]
+#[derive(Serialize)]
+#[derive(Debug)]
+enum DiscoveryMsg {
+    Search,
+    Found(ServerInfo)
+}
+
+#[derive(Clone)]
+#[derive(Deserialize)]
+#[derive(Serialize)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct RoomInfo {
+    pub name: String,
+    // everyone connected to the room, spectators and those waiting for a slot included
+    pub player_count: usize
+}
+
+// What a server says about itself in answer to a search
+#[derive(Clone)]
+#[derive(Deserialize)]
+#[derive(Serialize)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct ServerInfo {
+    pub name: String,
+    pub protocol_version: u32,
+    // tcp or udp
+    pub transport: String,
+    // the port games are played on, at the address the answer came from
+    pub port: u16,
+    pub rooms: Vec<RoomInfo>
+}
+
+impl ServerInfo {
+    pub fn new(name: &str, transport: String, port: u16, mut rooms: Vec<RoomInfo>) -> ServerInfo {
+        rooms.sort_by(|a, b| b.player_count.cmp(&a.player_count).then_with(|| a.name.cmp(&b.name)));
+        rooms.truncate(MAX_LISTED_ROOMS);
+        ServerInfo{
+            name: name.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            transport,
+            port,
+            rooms
+        }
+    }
+
+    pub fn player_count(&self) -> usize {
+        self.rooms.iter().map(|room| room.player_count).sum()
+    }
+
+    // whether this build can play on the server
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+    }
+}
+
+// A server that answered a search, and the address to connect to it on
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct FoundServer {
+    pub addr: SocketAddr,
+    pub info: ServerInfo
+}
+
+impl fmt::Display for FoundServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {} over {}, {} players", self.info.name, self.addr, self.info.transport, self.info.player_count())?;
+        for (i, room) in self.info.rooms.iter().enumerate() {
+            write!(f, "{} {} ({})", if i == 0 { ":" } else { "," }, room.name, room.player_count)?;
+        }
+        if !self.info.is_compatible() {
+            write!(f, ", needs protocol version {}", self.info.protocol_version)?;
+        }
+        Ok(())
+    }
+}
+
+// Answers searches for servers on the local network. It never blocks, so a server can check for
+// searches once a tick
+pub struct DiscoveryResponder {
+    socket: UdpSocket,
+    buffer: Vec<u8>
+}
+
+impl DiscoveryResponder {
+    pub fn new(addr: SocketAddr) -> GgResult<DiscoveryResponder> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(DiscoveryResponder{
+            socket,
+            buffer: vec![0u8; MAX_DATAGRAM_SIZE]
+        })
+    }
+
+    // answers the searches that have arrived since the last call from the local network, leaving any
+    // beyond the limit for the next. the server's details are only worked out if someone is asking
+    pub fn respond<F>(&mut self, get_info: F) -> GgResult where F: FnOnce() -> ServerInfo {
+        let mut get_info = Some(get_info);
+        let mut answer = None;
+        for _ in 0..MAX_SEARCHES_PER_RESPOND {
+            let (length, addr) = match self.socket.recv_from(&mut self.buffer) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e.into())
+            };
+
+            // a search from further afield is either a mistake or someone else's address forged
+            if !is_local(addr.ip()) {
+                continue;
+            }
+
+            if let Some(DiscoveryMsg::Search) = decode(&self.buffer[..length]) {
+                if answer.is_none() {
+                    let info = get_info.take().unwrap()();
+                    answer = Some(encode(&DiscoveryMsg::Found(info))?);
+                }
+                // the searcher may have given up already
+                let _ = self.socket.send_to(answer.as_ref().unwrap(), addr);
+            }
+        }
+        Ok(())
+    }
+}
+
+// whether the address is one that only a machine on the local network could have
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_local(IpAddr::V4(ip)),
+            // unique local (fc00::/7) or link local (fe80::/10)
+            None => ip.is_loopback() || (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+// Asks every server listening at the given address, usually a broadcast address, to say what it is,
+// and gathers the answers that arrive within the wait
+pub fn discover(addr: SocketAddr, wait: Duration) -> GgResult<Vec<FoundServer>> {
+    let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+    socket.set_broadcast(true)?;
+    socket.send_to(&encode(&DiscoveryMsg::Search)?, addr)?;
+
+    let mut found_servers: Vec<FoundServer> = vec![];
+    let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+    let deadline = Instant::now() + wait;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let (length, from) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => break,
+            Err(e) => return Err(e.into())
+        };
+
+        if let Some(DiscoveryMsg::Found(info)) = decode(&buffer[..length]) {
+            let addr = SocketAddr::new(from.ip(), info.port);
+            if !found_servers.iter().any(|found_server| found_server.addr == addr) {
+                found_servers.push(FoundServer{ addr, info });
+            }
+        }
+    }
+
+    Ok(found_servers)
+}
+
+fn encode(msg: &DiscoveryMsg) -> GgResult<Vec<u8>> {
+    let mut datagram = DISCOVERY_TAG.to_vec();
+    datagram.extend(Codec::Cbor.encode(msg)?);
+    Ok(datagram)
+}
+
+// None for anything that isn't a discovery datagram this build understands
+fn decode(datagram: &[u8]) -> Option<DiscoveryMsg> {
+    if !datagram.starts_with(DISCOVERY_TAG) {
+        return None;
+    }
+    Codec::Cbor.decode(&datagram[DISCOVERY_TAG.len()..]).ok()
+}
+
+#[test]
+fn test_discovery() {
+    let mut subject = DiscoveryResponder::new("127.0.0.1:0".parse().unwrap()).unwrap();
+    let responder_addr = subject.socket.local_addr().unwrap();
+    let rooms = vec![
+        RoomInfo{ name: "main".to_string(), player_count: 1 },
+        RoomInfo{ name: "jungle".to_string(), player_count: 3 }
+    ];
+    let info = ServerInfo::new("test server", "udp".to_string(), 9001, rooms);
+
+    let is_stopping = Arc::new(AtomicBool::new(false));
+    let responder_is_stopping = Arc::clone(&is_stopping);
+    let responder_info = info.clone();
+    let responder = std::thread::spawn(move || {
+        while !responder_is_stopping.load(Ordering::Relaxed) {
+            subject.respond(|| responder_info.clone()).unwrap();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    });
+
+    // stray datagrams are ignored
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.send_to(b"hello?", responder_addr).unwrap();
+
+    let found_servers = discover(responder_addr, Duration::from_millis(500)).unwrap();
+    is_stopping.store(true, Ordering::Relaxed);
+    responder.join().unwrap();
+
+    assert_eq!(vec![FoundServer{ addr: "127.0.0.1:9001".parse().unwrap(), info: info.clone() }], found_servers);
+    assert_eq!(4, info.player_count());
+    assert!(info.is_compatible());
+    // the busiest rooms are listed first
+    assert_eq!("test server at 127.0.0.1:9001 over udp, 4 players: jungle (3), main (1)", found_servers[0].to_string());
+
+    let older_server = FoundServer{ info: ServerInfo{ protocol_version: PROTOCOL_VERSION - 1, rooms: vec![], ..info }, ..found_servers[0].clone() };
+    assert!(!older_server.info.is_compatible());
+    assert!(older_server.to_string().ends_with(&format!("0 players, needs protocol version {}", PROTOCOL_VERSION - 1)));
+}
+
+#[test]
+fn test_respond_limit() {
+    let mut subject = DiscoveryResponder::new("127.0.0.1:0".parse().unwrap()).unwrap();
+    let responder_addr = subject.socket.local_addr().unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    for _ in 0..MAX_SEARCHES_PER_RESPOND + 1 {
+        socket.send_to(&encode(&DiscoveryMsg::Search).unwrap(), responder_addr).unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(50));
+
+    let count_answers = |subject: &mut DiscoveryResponder| {
+        subject.respond(|| ServerInfo::new("test server", "udp".to_string(), 9001, vec![])).unwrap();
+        let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+        std::iter::from_fn(|| socket.recv_from(&mut buffer).ok()).count()
+    };
+
+    // searches beyond the limit wait for the next call
+    assert_eq!(MAX_SEARCHES_PER_RESPOND, count_answers(&mut subject));
+    assert_eq!(1, count_answers(&mut subject));
+}
+
+#[test]
+fn test_is_local() {
+    for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.20", "169.254.0.5", "::1", "fd12:3456::1", "fe80::1", "::ffff:192.168.1.20"].iter() {
+        assert!(is_local(ip.parse().unwrap()), "{}", ip);
+    }
+    for ip in ["8.8.8.8", "172.32.0.1", "2001:db8::1", "::ffff:8.8.8.8"].iter() {
+        assert!(!is_local(ip.parse().unwrap()), "{}", ip);
+    }
+}
@@ -0,0 +1,281 @@
+use crate::network::{Server, TxChannel, RxChannel, ClientMsg, ServerMsg, Delivery, Hello, HANDSHAKE_TIMEOUT};
+use crate::network::codec::Codec;
+use crate::network::stats::ChannelStats;
+use crate::err::GgResult;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+#[cfg(test)]
+use std::cell::Cell;
+
+// The room a client joins when it doesn't ask for one
+pub const DEFAULT_ROOM: &str = "main";
+
+// Room names end up in the log, so they are kept short and printable
+const MAX_ROOM_NAME_LENGTH: usize = 32;
+
+// A connection that has not yet said hello. The lobby holds it until it says which room it wants,
+// and the room's server until it has been welcomed
+pub struct PendingClient<TNetwork> {
+    pub network: TNetwork,
+    pub connect_time: Duration
+}
+
+impl<TNetwork> PendingClient<TNetwork> where TNetwork: TxChannel<ServerMsg> {
+    pub fn has_timed_out(&self, time: Duration) -> bool {
+        time - self.connect_time > HANDSHAKE_TIMEOUT
+    }
+
+    // turns the client away, telling it why. dropping the connection flushes the Reject
+    pub fn reject(mut self, reason: String) {
+        println!("rejected a client: {}", reason);
+        let _ = self.network.enqueue(ServerMsg::Reject(reason), Delivery::ReliableOrdered);
+    }
+}
+
+// Takes new connections off a server and sorts them by the room asked for in their Hello, leaving
+// the rest of the handshake to the server of that room
+pub struct Lobby<TServer, TNetwork> {
+    server: TServer,
+    new_client_buffer: Vec::<TNetwork>,
+    pending_clients: Vec::<PendingClient<TNetwork>>,
+    msg_buffer: Vec::<ClientMsg>
+}
+
+impl<TServer, TNetwork> Lobby<TServer, TNetwork> where TServer: Server<TNetwork>, TNetwork: TxChannel<ServerMsg> + RxChannel<ClientMsg> {
+    pub fn new(server: TServer) -> Lobby<TServer, TNetwork> {
+        Lobby{
+            server,
+            new_client_buffer: vec![],
+            pending_clients: vec![],
+            msg_buffer: vec![]
+        }
+    }
+
+    // the clients that have said which room they want since the last call, with the room's name
+    pub fn get_arrivals(&mut self, time: Duration, arrivals: &mut Vec<(String, Greeted<TNetwork>)>) {
+        arrivals.clear();
+        self.new_client_buffer.clear();
+        self.server.get_new_clients(&mut self.new_client_buffer);
+        for network in self.new_client_buffer.drain(..) {
+            self.pending_clients.push(PendingClient{ network, connect_time: time });
+        }
+
+        let pending_clients = std::mem::take(&mut self.pending_clients);
+        for mut pending_client in pending_clients {
+            if pending_client.network.dequeue(&mut self.msg_buffer).is_err() {
+                println!("a client disconnected before choosing a room");
+                continue;
+            }
+
+            let room = self.msg_buffer.iter().find_map(|msg| match msg {
+                ClientMsg::Hello(hello) => Some(check_room(hello)),
+                _ => None
+            });
+
+            match room {
+                Some(Ok(room)) => {
+                    let held_msgs = self.msg_buffer.drain(..).collect();
+                    arrivals.push((room, Greeted{ network: pending_client.network, held_msgs }));
+                },
+                Some(Err(reason)) => pending_client.reject(reason),
+                None => {
+                    if pending_client.has_timed_out(time) {
+                        pending_client.reject(timeout_reason());
+                    } else {
+                        self.pending_clients.push(pending_client);
+                    }
+                }
+            }
+        }
+    }
+
+    // tells the clients that have yet to choose a room why the server is going away
+    pub fn shutdown(&mut self, reason: &str) {
+        for mut pending_client in self.pending_clients.drain(..) {
+            let _ = pending_client.network.enqueue(ServerMsg::Shutdown{ reason: reason.to_string() }, Delivery::ReliableOrdered);
+            pending_client.network.close();
+        }
+    }
+}
+
+// why a client that has been connected for longer than HANDSHAKE_TIMEOUT without saying hello is
+// turned away
+pub fn timeout_reason() -> String {
+    format!("no Hello within {:?} of connecting", HANDSHAKE_TIMEOUT)
+}
+
+// A connection whose first messages, its Hello among them, were read in the lobby. The first
+// dequeue hands them over again so that the room's server sees the handshake from the start
+pub struct Greeted<TNetwork> {
+    network: TNetwork,
+    held_msgs: Vec::<ClientMsg>
+}
+
+impl<TNetwork> TxChannel<ServerMsg> for Greeted<TNetwork> where TNetwork: TxChannel<ServerMsg> {
+    fn enqueue(&mut self, msg: ServerMsg, delivery: Delivery) -> GgResult {
+        self.network.enqueue(msg, delivery)
+    }
+
+    fn flush(&mut self) -> GgResult {
+        self.network.flush()
+    }
+
+    fn set_codec(&mut self, codec: Codec) {
+        self.network.set_codec(codec)
+    }
+
+    fn stats(&self) -> ChannelStats {
+        self.network.stats()
+    }
+}
+
+impl<TNetwork> RxChannel<ClientMsg> for Greeted<TNetwork> where TNetwork: RxChannel<ClientMsg> {
+    fn dequeue(&mut self, buffer: &mut Vec::<ClientMsg>) -> GgResult {
+        self.network.dequeue(buffer)?;
+        if !self.held_msgs.is_empty() {
+            buffer.splice(0..0, self.held_msgs.drain(..));
+        }
+        Ok(())
+    }
+}
+
+struct DoorState<TNetwork> {
+    arrivals: Vec::<TNetwork>,
+    client_count: usize
+}
+
+// The way into a room. The lobby lets clients in at one side and the room's server takes them
+// from the other, saying in return how many clients it still has
+pub struct Door<TNetwork> {
+    state: Rc<RefCell<DoorState<TNetwork>>>
+}
+
+impl<TNetwork> Door<TNetwork> {
+    pub fn admit(&self, client: TNetwork) {
+        self.state.borrow_mut().arrivals.push(client);
+    }
+
+    // how many clients the room's server said it had when it last updated
+    pub fn client_count(&self) -> usize {
+        self.state.borrow().client_count
+    }
+
+    // whether no one has come in since the room's server last took its clients, and it has none left
+    pub fn is_empty(&self) -> bool {
+        let state = self.state.borrow();
+        state.arrivals.is_empty() && state.client_count == 0
+    }
+}
+
+impl<TNetwork> Default for Door<TNetwork> {
+    fn default() -> Door<TNetwork> {
+        Door{
+            state: Rc::new(RefCell::new(DoorState{ arrivals: vec![], client_count: 0 }))
+        }
+    }
+}
+
+impl<TNetwork> Clone for Door<TNetwork> {
+    fn clone(&self) -> Door<TNetwork> {
+        Door{
+            state: Rc::clone(&self.state)
+        }
+    }
+}
+
+impl<TNetwork> Server<TNetwork> for Door<TNetwork> where TNetwork: TxChannel<ServerMsg> + RxChannel<ClientMsg> {
+    fn get_new_clients(&mut self, buffer: &mut Vec<TNetwork>) {
+        buffer.clear();
+        buffer.append(&mut self.state.borrow_mut().arrivals);
+    }
+
+    fn set_client_count(&mut self, client_count: usize) {
+        self.state.borrow_mut().client_count = client_count;
+    }
+}
+
+fn check_room(hello: &Hello) -> Result<String, String> {
+    match &hello.room {
+        None => Ok(DEFAULT_ROOM.to_string()),
+        Some(room) if room.is_empty() || room.chars().count() > MAX_ROOM_NAME_LENGTH || room.chars().any(char::is_control) =>
+            Err(format!("room names must be 1 to {} printable characters", MAX_ROOM_NAME_LENGTH)),
+        Some(room) => Ok(room.clone())
+    }
+}
+
+#[test]
+fn test_lobby() {
+    let time = Rc::new(Cell::new(Duration::from_millis(0u64)));
+    let mut server = crate::network::sim::SimServer::new(Duration::from_millis(0), Rc::clone(&time));
+    let mut default_client = server.connect();
+    let mut jungle_client = server.connect();
+    let mut bad_client = server.connect();
+    let mut silent_client = server.connect();
+    let mut subject = Lobby::new(server);
+
+    let jungle_hello = Hello{ room: Some("jungle".to_string()), ..Hello::new(None) };
+    default_client.enqueue(ClientMsg::Hello(Hello::new(None)), Delivery::ReliableOrdered).unwrap();
+    jungle_client.enqueue(ClientMsg::Hello(jungle_hello.clone()), Delivery::ReliableOrdered).unwrap();
+    bad_client.enqueue(ClientMsg::Hello(Hello{ room: Some("x".repeat(MAX_ROOM_NAME_LENGTH + 1)), ..Hello::new(None) }), Delivery::ReliableOrdered).unwrap();
+
+    let mut arrivals = vec![];
+    subject.get_arrivals(time.get(), &mut arrivals);
+    let rooms = arrivals.iter().map(|(room, _)| room.as_str()).collect::<Vec<_>>();
+    assert_eq!(vec![DEFAULT_ROOM, "jungle"], rooms);
+
+    let mut server_msgs = vec![];
+    bad_client.dequeue(&mut server_msgs).unwrap();
+    match &server_msgs[..] {
+        [ServerMsg::Reject(reason)] => assert!(reason.contains("room names")),
+        msgs => panic!("expected a Reject but got {:?}", msgs)
+    }
+
+    // the room's server gets the Hello as though it had been the first to read it
+    jungle_client.enqueue(ClientMsg::Heartbeat, Delivery::ReliableOrdered).unwrap();
+    let mut client_msgs = vec![];
+    arrivals[1].1.dequeue(&mut client_msgs).unwrap();
+    assert_eq!(vec![ClientMsg::Hello(jungle_hello), ClientMsg::Heartbeat], client_msgs);
+    arrivals[1].1.dequeue(&mut client_msgs).unwrap();
+    assert!(client_msgs.is_empty());
+
+    // a client that never says which room it wants is given up on
+    time.set(HANDSHAKE_TIMEOUT + Duration::from_millis(1));
+    subject.get_arrivals(time.get(), &mut arrivals);
+    assert!(arrivals.is_empty());
+    assert!(subject.pending_clients.is_empty());
+    silent_client.dequeue(&mut server_msgs).unwrap();
+    assert_eq!(vec![ServerMsg::Reject(timeout_reason())], server_msgs);
+
+    // and one still choosing when the server goes away is told so
+    let mut late_client = subject.server.connect();
+    subject.get_arrivals(time.get(), &mut arrivals);
+    subject.shutdown("maintenance");
+    late_client.dequeue(&mut server_msgs).unwrap();
+    assert_eq!(vec![ServerMsg::Shutdown{ reason: "maintenance".to_string() }], server_msgs);
+}
+
+#[test]
+fn test_door() {
+    let time = Rc::new(Cell::new(Duration::from_millis(0u64)));
+    let mut server = crate::network::sim::SimServer::new(Duration::from_millis(0), Rc::clone(&time));
+    let _client = server.connect();
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+
+    let subject = Door::default();
+    let mut room_end = subject.clone();
+    assert!(subject.is_empty());
+
+    // a client on its way in keeps the room open until the room's server is looking after it
+    subject.admit(new_clients.pop().unwrap());
+    assert!(!subject.is_empty());
+    room_end.get_new_clients(&mut new_clients);
+    assert_eq!(1, new_clients.len());
+    assert!(subject.is_empty());
+    room_end.set_client_count(1);
+    assert_eq!(1, subject.client_count());
+    assert!(!subject.is_empty());
+    room_end.set_client_count(0);
+    assert!(subject.is_empty());
+}
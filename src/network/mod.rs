@@ -1,38 +1,139 @@
 pub mod sim;
 pub mod real;
+pub mod udp;
+pub mod sequence;
+pub mod codec;
+pub mod clock;
+pub mod stats;
+pub mod lobby;
+pub mod discovery;
 
 use std::time::Duration;
 use crate::input::InputEvent;
-use crate::component::sprite::Sprite;
+use crate::component::sprite::{Sprite, SpriteDelta};
 use crate::component::body::Body;
 use crate::err::GgResult;
+use crate::network::codec::Codec;
+use crate::network::stats::ChannelStats;
 use serde::Serialize;
 use serde::Deserialize;
 
+// Bump whenever ServerMsg or ClientMsg change in a way that an older build could not decode.
+// Hello and Reject must keep their shape, and so must the unbatched CBOR frames that carry the
+// handshake until the Welcome agrees a codec, so that a mismatch can always be reported.
+pub const PROTOCOL_VERSION: u32 = 10;
+
+// Optional protocol features this build supports. The client offers them in its Hello and the
+// server's Welcome says which of them will be used.
+pub const CAPABILITIES: &[&str] = &["codec-bincode", "codec-compressed-bincode"];
+
+// How often each end sends a Heartbeat, so that a healthy connection is never silent for long
+pub const KEEPALIVE_PERIOD: Duration = Duration::from_secs(1);
+
+// How long a connection may go without receiving anything before the other end is assumed gone
+pub const DEFAULT_CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long closing a connection waits for what was enqueued on it, such as a goodbye, to be written
+// before the process exits
+pub const CLOSE_FLUSH_WAIT: Duration = Duration::from_millis(5);
+
+// How long a new connection has to send its Hello
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub trait Server<TNetwork>
     where TNetwork: TxChannel<ServerMsg> + RxChannel<ClientMsg> {
     fn get_new_clients(&mut self, buffer: &mut Vec<TNetwork>);
+
+    // told after every update how many clients are still being looked after, counting those not
+    // yet welcomed and those whose gorillas are being held for them. servers that don't care need
+    // not implement it
+    fn set_client_count(&mut self, _: usize) {}
 }
 
 pub trait TxChannel<TMsg>{
-    fn enqueue(&mut self, msg: TMsg) -> GgResult;
+    fn enqueue(&mut self, msg: TMsg, delivery: Delivery) -> GgResult;
+
+    // hands everything enqueued since the last flush to the transport, which may send it all
+    // together. transports that send each message straight away need not implement it
+    fn flush(&mut self) -> GgResult {
+        Ok(())
+    }
+
+    // hands everything enqueued to the transport and gives it a moment to send it, for when the
+    // process is about to exit. a connection that is only dropped sends it in its own time, without
+    // holding anything up. transports that send each message straight away need not implement it
+    fn close(&mut self) {
+        let _ = self.flush();
+    }
+
+    // switches to a codec that the handshake has shown the other end understands. transports
+    // with a fixed encoding need not implement it
+    fn set_codec(&mut self, _: Codec) {}
+
+    // what has gone over the connection in both directions so far. transports that keep no count
+    // need not implement it
+    fn stats(&self) -> ChannelStats {
+        ChannelStats::default()
+    }
 }
 
 pub trait RxChannel<TMsg>{
     fn dequeue(&mut self, buffer: &mut Vec::<TMsg>) -> GgResult;
 }
 
+// Names a message's variant, for counting what a connection carries
+pub trait MsgKind {
+    fn kind(&self) -> &'static str;
+}
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Deserialize)]
+#[derive(Serialize)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum Delivery {
+    // delivered exactly once and in the order sent, relative to other reliable messages
+    ReliableOrdered,
+    // may be lost, and is dropped by the receiver if a newer message on the same stream
+    // (usually an entity id) has already been received
+    UnreliableSequenced(u64)
+}
+
 #[derive(Clone)]
 #[derive(Deserialize)]
 #[derive(Serialize)]
 #[derive(PartialEq)]
 #[derive(Debug)]
 pub enum ServerMsg{
+    Welcome(Welcome),
+    Reject(String),
+    // the server is full and the client is waiting for a slot at the given position in the queue,
+    // counting from 1. it is welcomed once its turn comes
+    Queued(u32),
+    // the server is going away and the connection will close
+    Shutdown{ reason: String },
+    // the entity has been destroyed
     Kill(u64),
-    SetBody(u64, Body),
+    // the entity is still in the game but has gone too far from the client's gorilla to matter,
+    // and is sent in full again should it come back
+    Despawn(u64),
+    // with the server's time when the body was taken, so that snapshots can be played back at the
+    // pace they were taken rather than the pace they happened to arrive
+    SetBody(u64, Body, Duration),
+    // the state of a client's own gorilla, sent only to that client
+    SetOwnBody(u64, Body, InputAck),
     SetSprite(u64, Sprite),
+    // changes to a sprite the client has already been sent
+    UpdateSprite(u64, SpriteDelta),
     SetFocus(u64),
+    // the entity is something a gorilla can throw a rope at
+    SetAnchor(u64),
+    // either end can ping the other, with the time by its own clock, to measure the round trip and
+    // how far apart their clocks are
     Ping(Duration),
+    Pong(Pong),
+    Heartbeat,
     #[cfg(test)]
     Test(u32)
 }
@@ -43,9 +144,123 @@ pub enum ServerMsg{
 #[derive(PartialEq)]
 #[derive(Debug)]
 pub enum ClientMsg{
+    Hello(Hello),
+    // the player quit, as opposed to the connection failing
+    Goodbye,
     Input(InputEvent),
-    Pong(Duration),
+    // how far behind the present the client draws other entities, so that the server can see what
+    // the player saw when resolving their input
+    SetInterpolationDelay(Duration),
+    // a spectator wants to follow the next player round, or the previous one
+    CycleFocus{ forward: bool },
+    Ping(Duration),
+    Pong(Pong),
+    Heartbeat,
     #[cfg(test)]
     Test(u32)
 }
 
+impl MsgKind for ServerMsg {
+    fn kind(&self) -> &'static str {
+        match self {
+            ServerMsg::Welcome(_) => "Welcome",
+            ServerMsg::Reject(_) => "Reject",
+            ServerMsg::Queued(_) => "Queued",
+            ServerMsg::Shutdown{ .. } => "Shutdown",
+            ServerMsg::Kill(_) => "Kill",
+            ServerMsg::Despawn(_) => "Despawn",
+            ServerMsg::SetBody(_, _, _) => "SetBody",
+            ServerMsg::SetOwnBody(_, _, _) => "SetOwnBody",
+            ServerMsg::SetSprite(_, _) => "SetSprite",
+            ServerMsg::UpdateSprite(_, _) => "UpdateSprite",
+            ServerMsg::SetFocus(_) => "SetFocus",
+            ServerMsg::SetAnchor(_) => "SetAnchor",
+            ServerMsg::Ping(_) => "Ping",
+            ServerMsg::Pong(_) => "Pong",
+            ServerMsg::Heartbeat => "Heartbeat",
+            #[cfg(test)]
+            ServerMsg::Test(_) => "Test"
+        }
+    }
+}
+
+impl MsgKind for ClientMsg {
+    fn kind(&self) -> &'static str {
+        match self {
+            ClientMsg::Hello(_) => "Hello",
+            ClientMsg::Goodbye => "Goodbye",
+            ClientMsg::Input(_) => "Input",
+            ClientMsg::SetInterpolationDelay(_) => "SetInterpolationDelay",
+            ClientMsg::CycleFocus{ .. } => "CycleFocus",
+            ClientMsg::Ping(_) => "Ping",
+            ClientMsg::Pong(_) => "Pong",
+            ClientMsg::Heartbeat => "Heartbeat",
+            #[cfg(test)]
+            ClientMsg::Test(_) => "Test"
+        }
+    }
+}
+
+#[derive(Clone)]
+#[derive(Deserialize)]
+#[derive(Serialize)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct InputAck{
+    // the last input that the accompanying state reflects
+    pub seq: u32,
+    // the client's clock time that the accompanying state corresponds to
+    pub time: Duration
+}
+
+#[derive(Clone)]
+#[derive(Deserialize)]
+#[derive(Serialize)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct Pong{
+    // the time in the Ping being replied to, by the pinging end's clock
+    pub ping_time: Duration,
+    // when the reply was sent, by the replying end's clock
+    pub reply_time: Duration
+}
+
+#[derive(Clone)]
+#[derive(Deserialize)]
+#[derive(Serialize)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct Hello{
+    pub version: u32,
+    pub capabilities: Vec<String>,
+    // set when reconnecting, to get back the gorilla from an earlier session
+    pub session_token: Option<u64>,
+    // to watch the game rather than play. missing from an older Hello, which still decodes
+    #[serde(default)]
+    pub is_spectator: bool,
+    // the room to join, which is opened if no one is in it yet. None for the default room
+    #[serde(default)]
+    pub room: Option<String>
+}
+
+impl Hello{
+    pub fn new(session_token: Option<u64>) -> Hello{
+        Hello{
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            session_token,
+            is_spectator: false,
+            room: None
+        }
+    }
+}
+
+#[derive(Clone)]
+#[derive(Deserialize)]
+#[derive(Serialize)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct Welcome{
+    pub capabilities: Vec<String>,
+    pub session_token: u64
+}
@@ -4,122 +4,357 @@ use crate::network::ServerMsg;
 use std::thread::JoinHandle;
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::Sender;
-use std::net::{TcpStream, TcpListener};
+use std::net::{SocketAddr, TcpStream, TcpListener, Shutdown};
 use crate::network::RxChannel;
 use crate::network::TxChannel;
+use crate::network::Delivery;
+use crate::network::MsgKind;
+use crate::network::stats::{ChannelStats, TrafficStats};
+use crate::network::sequence::{Envelope, Sequencer, SequenceFilter, drop_superseded};
+use crate::network::codec::Codec;
 use std::marker::PhantomData;
-use crate::err::GgResult;
+use std::time::Duration;
+use crate::err::{GgError, GgResult};
 use std::sync::mpsc::channel;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
-use std::io::Read;
+use std::io::{Read, Write};
 use byteorder::{ReadBytesExt, WriteBytesExt};
 
+// Frames longer than this are refused unless a connection is given its own limit
+pub const MAX_FRAME_LENGTH: usize = 64 * 1024;
+
+// Each frame is a u32 length followed by that many bytes: a Codec tag, then the messages, each of
+// which is itself a u32 length followed by an Envelope encoded with that codec. Until the handshake
+// has agreed a codec, each message goes in a frame of its own as a bare CBOR Envelope, which is how
+// every build since the handshake was added has sent them, so that a peer on another protocol
+// version can still read a Reject. Builds from before the handshake frame bare messages and don't
+// know what a Reject is, so there is no telling them anything
+const LENGTH_PREFIX: usize = 4;
+const CODEC_TAG: usize = 1;
+
+// A peer that accepts no data for this long is treated as gone, which also bounds how long
+// dropping a connection can block while it flushes
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long the listener waits before accepting again after an accept has failed
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+// what each flush hands the tx thread, with the codec agreed so far
+type Flushed<TTx> = (Option<Codec>, Vec<Envelope<TTx>>);
+
+// counted by the tx and rx threads
+#[derive(Default)]
+struct SharedStats {
+    sent: TrafficStats,
+    received: TrafficStats,
+    // flushed to the tx thread but not yet written
+    unsent: usize,
+    last_receive_time: Option<Instant>
+}
+
 pub struct RealNetwork<TTx, TRx>{
     is_closed: Arc<AtomicBool>,
-    tx_q_out: Option<Sender<TTx>>,
+    stats: Arc<Mutex<SharedStats>>,
+    tx_q_out: Option<Sender<Flushed<TTx>>>,
+    // enqueued since the last flush
+    unflushed: Vec<Envelope<TTx>>,
+    // None until the handshake has agreed one
+    codec: Option<Codec>,
     rx_q_in: Option<Receiver<TRx>>,
+    // disconnected once the tx thread has written everything and exited
+    tx_finished: Receiver<()>,
+    sequencer: Sequencer,
 
     phantom1: PhantomData<TTx>,
     phantom2: PhantomData<TRx>,
 }
 
 fn rx_loop<TRx>(
-    mut tcp_stream: TcpStream, 
-    rx_q_out: Sender<TRx>
-) -> GgResult where TRx: DeserializeOwned + std::fmt::Debug {
+    tcp_stream: &mut TcpStream, 
+    rx_q_out: Sender<TRx>,
+    max_frame_length: usize,
+    stats: &Mutex<SharedStats>
+) -> GgResult where TRx: DeserializeOwned + MsgKind + std::fmt::Debug {
     let mut buffer = vec![0u8; 2048];
+    let mut sequence_filter = SequenceFilter::new();
     loop {
-        let msg_length = tcp_stream.read_u32::<byteorder::BigEndian>()?;
-        let msg_buffer = &mut buffer[0..msg_length as usize];
-        tcp_stream.read_exact(msg_buffer)?;
-        let msg: TRx = serde_cbor::from_slice(msg_buffer)?;
+        let frame_length = tcp_stream.read_u32::<byteorder::BigEndian>()? as usize;
+        if frame_length > max_frame_length {
+            return Err(GgError::FrameTooLarge{ length: frame_length, max_length: max_frame_length });
+        }
+        if frame_length > buffer.len() {
+            buffer.resize(frame_length, 0);
+        }
+        tcp_stream.read_exact(&mut buffer[0..frame_length])?;
+        {
+            let mut stats = stats.lock().unwrap();
+            stats.received.record_bytes(LENGTH_PREFIX + frame_length);
+            stats.last_receive_time = Some(Instant::now());
+        }
+        if !Codec::is_tagged_frame(&buffer[0..frame_length]) {
+            let envelope = Codec::Cbor.decode(&buffer[0..frame_length])?;
+            receive_envelope(envelope, frame_length, &rx_q_out, &mut sequence_filter, stats)?;
+            continue;
+        }
+
+        let (codec, msgs) = Codec::open_frame(&buffer[0..frame_length], max_frame_length)?;
+        let mut frame = &msgs[..];
+
+        while !frame.is_empty() {
+            let msg_length = frame.read_u32::<byteorder::BigEndian>()
+                .map_err(|_| GgError::MalformedFrame("truncated message length".to_string()))? as usize;
+            if msg_length > frame.len() {
+                return Err(GgError::MalformedFrame(format!("message of {} bytes overruns the frame", msg_length)));
+            }
+            let (msg_buffer, rest) = frame.split_at(msg_length);
+            frame = rest;
+
+            let envelope = codec.decode(msg_buffer)?;
+            receive_envelope(envelope, msg_length, &rx_q_out, &mut sequence_filter, stats)?;
+        }
+    }
+}
+
+fn receive_envelope<TRx>(
+    envelope: Envelope<TRx>,
+    msg_length: usize,
+    rx_q_out: &Sender<TRx>,
+    sequence_filter: &mut SequenceFilter,
+    stats: &Mutex<SharedStats>
+) -> GgResult where TRx: MsgKind + std::fmt::Debug {
+    stats.lock().unwrap().received.record_msg(envelope.msg().kind(), msg_length);
 
-        #[cfg(debug)]
-        println!("<-- {:?} {}", &msg, msg_length);
+    #[cfg(debug)]
+    println!("<-- {:?} {}", &envelope, msg_length);
 
+    if let Some(msg) = sequence_filter.open(envelope) {
         rx_q_out.send(msg)?;
     }
+    Ok(())
 }
 
 fn tx_loop<TTx>(
     mut tcp_stream: TcpStream, 
-    tx_q_in: Receiver<TTx>
-) -> GgResult where TTx: Serialize + std::fmt::Debug {
+    tx_q_in: Receiver<Flushed<TTx>>,
+    max_frame_length: usize,
+    stats: &Mutex<SharedStats>
+) -> GgResult where TTx: Serialize + MsgKind + std::fmt::Debug {
+    let mut batch = vec![];
+    let mut msgs = vec![];
+    let mut frame = vec![];
     loop {
-        let msg = tx_q_in.recv()?;
-        let msg_buffer = serde_cbor::to_vec(&msg)?;
-        let msg_length = msg_buffer.len();
-        tcp_stream.write_u32::<byteorder::BigEndian>(msg_length as u32)?;
-        serde_cbor::to_writer(&mut tcp_stream, &msg)?;
+        // everything that queued up while the last write was blocked is sent together,
+        // minus any sequenced messages that have since been superseded
+        let (mut codec, flushed) = tx_q_in.recv()?;
+        batch.extend(flushed);
+        for (later_codec, flushed) in tx_q_in.try_iter() {
+            // the peer can read any codec we would have switched to, but handshake messages must
+            // not be swept into a batch
+            codec = codec.and(later_codec);
+            batch.extend(flushed);
+        }
+        let taken = batch.len();
+        drop_superseded(&mut batch);
+
+        let codec = match codec {
+            Some(codec) => codec,
+            None => {
+                for envelope in batch.drain(..) {
+                    let written = write_handshake_frame(&mut tcp_stream, &envelope, max_frame_length)?;
+                    let mut stats = stats.lock().unwrap();
+                    stats.sent.record_msg(envelope.msg().kind(), written - LENGTH_PREFIX);
+                    stats.sent.record_bytes(written);
+
+                    #[cfg(debug)]
+                    println!("--> {:?} {}", &envelope, written);
+                }
+                stats.lock().unwrap().unsent -= taken;
+                continue;
+            }
+        };
+
+        // the batch goes out in as few frames as the peer's limit allows
+        for envelope in batch.drain(..) {
+            let msg_buffer = codec.encode(&envelope)?;
+            let msg_length = msg_buffer.len();
+            // the peer would refuse it anyway
+            if CODEC_TAG + LENGTH_PREFIX + msg_length > max_frame_length {
+                return Err(GgError::FrameTooLarge{ length: CODEC_TAG + LENGTH_PREFIX + msg_length, max_length: max_frame_length });
+            }
+            if CODEC_TAG + msgs.len() + LENGTH_PREFIX + msg_length > max_frame_length {
+                let written = write_frame(&mut tcp_stream, codec, &mut msgs, &mut frame, max_frame_length)?;
+                stats.lock().unwrap().sent.record_bytes(written);
+            }
+            msgs.write_u32::<byteorder::BigEndian>(msg_length as u32)?;
+            msgs.extend_from_slice(&msg_buffer);
+            stats.lock().unwrap().sent.record_msg(envelope.msg().kind(), msg_length);
+
+            #[cfg(debug)]
+            println!("--> {:?} {}", &envelope, msg_length);
+        }
+        let written = write_frame(&mut tcp_stream, codec, &mut msgs, &mut frame, max_frame_length)?;
+        let mut stats = stats.lock().unwrap();
+        stats.sent.record_bytes(written);
+        stats.unsent -= taken;
+    }
+}
+
+// sends a message in a frame of its own as a bare CBOR Envelope, returning the number of bytes written
+fn write_handshake_frame<TTx>(tcp_stream: &mut TcpStream, envelope: &Envelope<TTx>, max_frame_length: usize) -> GgResult<usize> where TTx: Serialize {
+    let msg_buffer = Codec::Cbor.encode(envelope)?;
+    if msg_buffer.len() > max_frame_length {
+        return Err(GgError::FrameTooLarge{ length: msg_buffer.len(), max_length: max_frame_length });
+    }
+    tcp_stream.write_u32::<byteorder::BigEndian>(msg_buffer.len() as u32)?;
+    tcp_stream.write_all(&msg_buffer)?;
+    Ok(LENGTH_PREFIX + msg_buffer.len())
+}
+
+// sends the messages gathered so far as one frame and makes way for the next, returning the number
+// of bytes written
+fn write_frame(tcp_stream: &mut TcpStream, codec: Codec, msgs: &mut Vec<u8>, frame: &mut Vec<u8>, max_frame_length: usize) -> GgResult<usize> {
+    if msgs.is_empty() {
+        return Ok(0);
+    }
 
-        #[cfg(debug)]
-        println!("--> {:?} {}", &msg, msg_length);
+    frame.clear();
+    frame.extend_from_slice(&[0u8; LENGTH_PREFIX]);
+    codec.seal_frame(msgs, frame)?;
+    let frame_length = frame.len() - LENGTH_PREFIX;
+    if frame_length > max_frame_length {
+        return Err(GgError::FrameTooLarge{ length: frame_length, max_length: max_frame_length });
     }
+    (&mut frame[0..LENGTH_PREFIX]).write_u32::<byteorder::BigEndian>(frame_length as u32)?;
+    tcp_stream.write_all(frame)?;
+
+    msgs.clear();
+    Ok(frame.len())
 }
 
 impl<TTx, TRx> RealNetwork<TTx, TRx> 
     where 
-        TTx: 'static + Send + Serialize + std::fmt::Debug, 
-        TRx: 'static + Send + DeserializeOwned + std::fmt::Debug{
+        TTx: 'static + Send + Serialize + MsgKind + std::fmt::Debug, 
+        TRx: 'static + Send + DeserializeOwned + MsgKind + std::fmt::Debug{
 
-    pub fn new(tcp_stream: TcpStream) -> GgResult<RealNetwork<TTx, TRx>> {
+    pub fn new(tcp_stream: TcpStream, max_frame_length: usize) -> GgResult<RealNetwork<TTx, TRx>> {
 
         tcp_stream.set_nodelay(true)?;
+        tcp_stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
 
-        let (tx_q_out, tx_q_in) = channel::<TTx>();
+        let (tx_q_out, tx_q_in) = channel::<Flushed<TTx>>();
         let (rx_q_out, rx_q_in) = channel::<TRx>();
 
         let is_closed = Arc::new(AtomicBool::new(false));
         let tx_is_closed = is_closed.clone();
         let rx_is_closed = is_closed.clone();
 
+        let stats = Arc::new(Mutex::new(SharedStats::default()));
+        let tx_stats = stats.clone();
+        let rx_stats = stats.clone();
+
         let tx_stream = tcp_stream.try_clone()?;
-        let rx_stream = tcp_stream.try_clone()?;
+        let mut rx_stream = tcp_stream.try_clone()?;
+        let (tx_finished_send, tx_finished) = channel::<()>();
 
+        // the tx thread outlives the RealNetwork to finish writing whatever was enqueued, then closes
+        // the connection, which also ends the rx thread
         std::thread::spawn(move || {
-            let result = tx_loop(tx_stream, tx_q_in);
+            let _tx_finished_send = tx_finished_send;
+            let result = tx_loop(tx_stream.try_clone()?, tx_q_in, max_frame_length, &tx_stats);
             
             #[cfg(debug)]
             println!("tx loop exited: {:?}", result);
             
+            let _ = tx_stream.shutdown(Shutdown::Both);
             tx_is_closed.store(true, Ordering::Relaxed);
             result
         });
 
         std::thread::spawn(move || {
-            let result = rx_loop(rx_stream, rx_q_out);
+            let result = rx_loop(&mut rx_stream, rx_q_out, max_frame_length, &rx_stats);
 
             #[cfg(debug)]
             println!("rx loop exited: {:?}", result);
 
+            // a bad frame leaves the stream at an unknown position so the connection can't be kept
+            let _ = rx_stream.shutdown(Shutdown::Both);
+
             rx_is_closed.store(true, Ordering::Relaxed);
             result
         });
 
         Ok(RealNetwork{
             is_closed,
+            stats,
             tx_q_out: Some(tx_q_out),
+            unflushed: vec![],
+            codec: None,
             rx_q_in: Some(rx_q_in),
+            tx_finished,
+            sequencer: Sequencer::new(),
             phantom1: PhantomData{},
             phantom2: PhantomData{}
         })
     }
 }
 
+impl<TTx, TRx> Drop for RealNetwork<TTx, TRx> {
+    // hands anything already enqueued, such as a goodbye, to the tx thread to write before it closes
+    // the connection
+    fn drop(&mut self) {
+        let _ = self.flush();
+        self.tx_q_out = None;
+    }
+}
+
 impl<TTx, TRx> TxChannel<TTx> for RealNetwork<TTx, TRx> {
-    fn enqueue(&mut self, msg: TTx) -> GgResult{
+    fn enqueue(&mut self, msg: TTx, delivery: Delivery) -> GgResult{
         let is_closed = self.is_closed.load(std::sync::atomic::Ordering::Relaxed);
         if is_closed {
             Err("channel closed".into())
         } else {
-            self.tx_q_out.as_ref().unwrap().send(msg)?;
+            let envelope = self.sequencer.seal(msg, delivery);
+            self.unflushed.push(envelope);
             Ok(())
         }
     }
+
+    fn flush(&mut self) -> GgResult {
+        if self.unflushed.is_empty() {
+            return Ok(());
+        }
+
+        let batch = std::mem::take(&mut self.unflushed);
+        self.stats.lock().unwrap().unsent += batch.len();
+        self.tx_q_out.as_ref().unwrap().send((self.codec, batch))?;
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        let _ = self.flush();
+        self.is_closed.store(true, Ordering::Relaxed);
+        self.tx_q_out = None;
+        let _ = self.tx_finished.recv_timeout(crate::network::CLOSE_FLUSH_WAIT);
+    }
+
+    fn set_codec(&mut self, codec: Codec) {
+        self.codec = Some(codec);
+    }
+
+    fn stats(&self) -> ChannelStats {
+        let stats = self.stats.lock().unwrap();
+        ChannelStats{
+            sent: stats.sent.clone(),
+            received: stats.received.clone(),
+            queue_depth: self.unflushed.len() + stats.unsent,
+            since_last_receive: stats.last_receive_time.map(|time| time.elapsed())
+        }
+    }
 }
 
 impl<TTx, TRx> RxChannel<TRx> for RealNetwork<TTx, TRx> {
@@ -138,13 +373,13 @@ impl<TTx, TRx> RxChannel<TRx> for RealNetwork<TTx, TRx> {
 #[test]
 fn test_real_network() {
 
-    let mut server = RealServer::new().unwrap();
+    let mut server = RealServer::new("127.0.0.1:0".parse().unwrap(), MAX_FRAME_LENGTH).unwrap();
     std::thread::sleep(std::time::Duration::from_millis(50));
-    let client_1_stream = TcpStream::connect("127.0.0.1:9001").unwrap();
-    let mut client_1 = RealNetwork::<ClientMsg, ServerMsg>::new(client_1_stream).unwrap();
+    let client_1_stream = TcpStream::connect(server.local_addr()).unwrap();
+    let mut client_1 = RealNetwork::<ClientMsg, ServerMsg>::new(client_1_stream, MAX_FRAME_LENGTH).unwrap();
 
-    let client_2_stream = TcpStream::connect("127.0.0.1:9001").unwrap();
-    let mut client_2 = RealNetwork::<ClientMsg, ServerMsg>::new(client_2_stream).unwrap();
+    let client_2_stream = TcpStream::connect(server.local_addr()).unwrap();
+    let mut client_2 = RealNetwork::<ClientMsg, ServerMsg>::new(client_2_stream, MAX_FRAME_LENGTH).unwrap();
 
     std::thread::sleep(std::time::Duration::from_millis(50));
 
@@ -152,12 +387,14 @@ fn test_real_network() {
     server.get_new_clients(&mut new_clients);
     assert_eq!(2, new_clients.len());
 
-    client_1.enqueue(ClientMsg::Test(1)).unwrap();
-    client_2.enqueue(ClientMsg::Test(2)).unwrap();
-    client_1.enqueue(ClientMsg::Test(3)).unwrap();
-    client_2.enqueue(ClientMsg::Test(4)).unwrap();
-    client_1.enqueue(ClientMsg::Test(5)).unwrap();
-    client_2.enqueue(ClientMsg::Test(6)).unwrap();
+    client_1.enqueue(ClientMsg::Test(1), Delivery::ReliableOrdered).unwrap();
+    client_2.enqueue(ClientMsg::Test(2), Delivery::ReliableOrdered).unwrap();
+    client_1.enqueue(ClientMsg::Test(3), Delivery::ReliableOrdered).unwrap();
+    client_2.enqueue(ClientMsg::Test(4), Delivery::ReliableOrdered).unwrap();
+    client_1.enqueue(ClientMsg::Test(5), Delivery::ReliableOrdered).unwrap();
+    client_2.enqueue(ClientMsg::Test(6), Delivery::ReliableOrdered).unwrap();
+    client_1.flush().unwrap();
+    client_2.flush().unwrap();
 
     std::thread::sleep(std::time::Duration::from_millis(50));
 
@@ -179,32 +416,174 @@ fn test_real_network() {
     assert_eq!(0, new_clients.len());
 }
 
+#[test]
+fn test_frame_length() {
+
+    let mut server = RealServer::new("127.0.0.1:0".parse().unwrap(), 4096).unwrap();
+
+    // a frame bigger than the initial buffer but within the limit gets through
+    let client_1_stream = TcpStream::connect(server.local_addr()).unwrap();
+    let mut client_1 = RealNetwork::<ClientMsg, ServerMsg>::new(client_1_stream, 4096).unwrap();
+
+    // a frame declaring a length over the limit closes only that connection
+    let mut client_2_stream = TcpStream::connect(server.local_addr()).unwrap();
+
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+    assert_eq!(2, new_clients.len());
+
+    client_1.enqueue(ClientMsg::Test(1), Delivery::ReliableOrdered).unwrap();
+    new_clients[0].enqueue(ServerMsg::Reject("x".repeat(3000)), Delivery::ReliableOrdered).unwrap();
+    client_1.flush().unwrap();
+    new_clients[0].flush().unwrap();
+    client_2_stream.write_u32::<byteorder::BigEndian>(u32::MAX).unwrap();
+
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    let mut server_msg_buffer = vec![];
+    new_clients[0].dequeue(&mut server_msg_buffer).unwrap();
+    assert_eq!(vec![ClientMsg::Test(1)], server_msg_buffer);
+    assert!(new_clients[1].dequeue(&mut server_msg_buffer).is_err());
+
+    let mut client_msg_buffer = vec![];
+    client_1.dequeue(&mut client_msg_buffer).unwrap();
+    assert_eq!(vec![ServerMsg::Reject("x".repeat(3000))], client_msg_buffer);
+}
+
+#[test]
+fn test_handshake_frames() {
+
+    let mut server = RealServer::new("127.0.0.1:0".parse().unwrap(), MAX_FRAME_LENGTH).unwrap();
+    // a client built before messages were batched, which frames each one as a bare CBOR Envelope
+    let mut client_stream = TcpStream::connect(server.local_addr()).unwrap();
+    client_stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let read_frame = |stream: &mut TcpStream| {
+        let frame_length = stream.read_u32::<byteorder::BigEndian>().unwrap() as usize;
+        let mut frame = vec![0u8; frame_length];
+        stream.read_exact(&mut frame).unwrap();
+        frame
+    };
+
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+
+    let hello = serde_cbor::to_vec(&Envelope::ReliableOrdered(ClientMsg::Test(1))).unwrap();
+    client_stream.write_u32::<byteorder::BigEndian>(hello.len() as u32).unwrap();
+    client_stream.write_all(&hello).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let mut server_msg_buffer = vec![];
+    new_clients[0].dequeue(&mut server_msg_buffer).unwrap();
+    assert_eq!(vec![ClientMsg::Test(1)], server_msg_buffer);
+
+    // is answered the same way, one message to a frame, so that it can read why it was turned away
+    new_clients[0].enqueue(ServerMsg::Test(2), Delivery::ReliableOrdered).unwrap();
+    new_clients[0].enqueue(ServerMsg::Reject("too old".to_string()), Delivery::ReliableOrdered).unwrap();
+    new_clients[0].flush().unwrap();
+    for expected in [ServerMsg::Test(2), ServerMsg::Reject("too old".to_string())] {
+        let envelope: Envelope<ServerMsg> = serde_cbor::from_slice(&read_frame(&mut client_stream)).unwrap();
+        assert_eq!(Envelope::ReliableOrdered(expected), envelope);
+    }
+
+    // frames are only batched and tagged once the handshake has agreed a codec
+    new_clients[0].set_codec(Codec::Bincode);
+    new_clients[0].enqueue(ServerMsg::Test(3), Delivery::ReliableOrdered).unwrap();
+    new_clients[0].flush().unwrap();
+    assert!(Codec::is_tagged_frame(&read_frame(&mut client_stream)));
+}
+
+#[test]
+fn test_batching() {
+
+    let mut server = RealServer::new("127.0.0.1:0".parse().unwrap(), 4096).unwrap();
+    let client_stream = TcpStream::connect(server.local_addr()).unwrap();
+    let mut client = RealNetwork::<ClientMsg, ServerMsg>::new(client_stream, 4096).unwrap();
+
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+
+    // nothing goes out until the end of the tick
+    new_clients[0].set_codec(Codec::CompressedBincode);
+    for i in 0..10 {
+        new_clients[0].enqueue(ServerMsg::Reject(i.to_string().repeat(1000)), Delivery::ReliableOrdered).unwrap();
+    }
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let mut client_msg_buffer = vec![];
+    client.dequeue(&mut client_msg_buffer).unwrap();
+    assert!(client_msg_buffer.is_empty());
+
+    // then it all arrives in order, split over as many frames as the limit requires
+    new_clients[0].flush().unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    client.dequeue(&mut client_msg_buffer).unwrap();
+    let expected = (0..10).map(|i| ServerMsg::Reject(i.to_string().repeat(1000))).collect::<Vec<_>>();
+    assert_eq!(expected, client_msg_buffer);
+
+    // each end counts the same messages, but the compressed frames are much smaller than the messages
+    let sent = new_clients[0].stats();
+    let received = client.stats();
+    assert_eq!(0, sent.queue_depth);
+    assert_eq!(10, sent.sent.by_kind["Reject"].msgs);
+    assert_eq!(sent.sent, received.received);
+    assert!(received.received.bytes < received.received.by_kind["Reject"].bytes / 10);
+    assert!(received.since_last_receive.unwrap() < Duration::from_secs(1));
+}
+
 pub struct RealServer {
     #[allow(dead_code)]
     listen_thread: JoinHandle<GgResult>,
-    new_client_recv: Receiver<RealNetwork<ServerMsg, ClientMsg>>
+    new_client_recv: Receiver<RealNetwork<ServerMsg, ClientMsg>>,
+    local_addr: SocketAddr
 }
 
 impl RealServer {
-    pub fn new() -> GgResult<RealServer> {
+    // binding to port 0 picks any free port, use local_addr to find out which
+    pub fn new(addr: SocketAddr, max_frame_length: usize) -> GgResult<RealServer> {
         let (new_client_send, new_client_recv) = channel();
-        let listener = TcpListener::bind("0.0.0.0:9001")?;
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
         let listen_thread = std::thread::spawn(move || {
             for stream in listener.incoming() {
-                let client = RealNetwork::<ServerMsg, ClientMsg>::new(stream.unwrap()).unwrap();
+                // a failed accept, such as when the process is out of file descriptors, costs only
+                // that connection. pausing stops an error that persists from spinning the thread
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        println!("failed to accept a connection: {}", e);
+                        std::thread::sleep(ACCEPT_RETRY_DELAY);
+                        continue;
+                    }
+                };
+                let client = match RealNetwork::<ServerMsg, ClientMsg>::new(stream, max_frame_length) {
+                    Ok(client) => client,
+                    Err(e) => {
+                        println!("failed to set up a connection: {}", e);
+                        continue;
+                    }
+                };
                 new_client_send.send(client)?;
             }
 
             Ok(())
         });
 
-        println!("ggs is listening on port 9001");
+        println!("ggs is listening on {}", local_addr);
 
         Ok(RealServer {
             listen_thread,
-            new_client_recv
+            new_client_recv,
+            local_addr
         })
     }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
 }
 
 impl Server<RealNetwork<ServerMsg, ClientMsg>> for RealServer {
@@ -212,4 +591,24 @@ impl Server<RealNetwork<ServerMsg, ClientMsg>> for RealServer {
         buffer.clear();
         buffer.extend(self.new_client_recv.try_iter());
     }
-}
\ No newline at end of file
+}
+#[test]
+fn test_drop_does_not_block() {
+    let mut server = RealServer::new("127.0.0.1:0".parse().unwrap(), MAX_FRAME_LENGTH).unwrap();
+    // a peer that never reads, so that writes to it soon block
+    let _client_stream = TcpStream::connect(server.local_addr()).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+
+    for _ in 0..300 {
+        new_clients[0].enqueue(ServerMsg::Reject("x".repeat(60_000)), Delivery::ReliableOrdered).unwrap();
+    }
+    new_clients[0].flush().unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    // the server's tick doesn't wait for it at all
+    let start = Instant::now();
+    drop(new_clients);
+    assert!(start.elapsed() < crate::network::CLOSE_FLUSH_WAIT);
+}
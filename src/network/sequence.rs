@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use crate::network::Delivery;
+use serde::{Serialize, Deserialize};
+
+// A message as it travels over the wire, tagged with what the receiver needs to honour its delivery class
+#[derive(Clone)]
+#[derive(Deserialize)]
+#[derive(Serialize)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum Envelope<TMsg> {
+    ReliableOrdered(TMsg),
+    UnreliableSequenced(u64, u32, TMsg)
+}
+
+impl<TMsg> Envelope<TMsg> {
+    pub fn msg(&self) -> &TMsg {
+        match self {
+            Envelope::ReliableOrdered(msg) => msg,
+            Envelope::UnreliableSequenced(_, _, msg) => msg
+        }
+    }
+}
+
+// Numbers the messages sent on each sequenced stream
+pub struct Sequencer {
+    last_seqs: HashMap<u64, u32>
+}
+
+impl Sequencer {
+    pub fn new() -> Sequencer {
+        Sequencer{
+            last_seqs: HashMap::new()
+        }
+    }
+
+    pub fn next(&mut self, stream: u64) -> u32 {
+        let seq = self.last_seqs.entry(stream).or_insert(0);
+        *seq += 1;
+        *seq
+    }
+
+    pub fn seal<TMsg>(&mut self, msg: TMsg, delivery: Delivery) -> Envelope<TMsg> {
+        match delivery {
+            Delivery::ReliableOrdered => Envelope::ReliableOrdered(msg),
+            Delivery::UnreliableSequenced(stream) => Envelope::UnreliableSequenced(stream, self.next(stream), msg)
+        }
+    }
+}
+
+// Remembers the newest message received on each sequenced stream so that older ones can be dropped
+pub struct SequenceFilter {
+    newest_seqs: HashMap<u64, u32>
+}
+
+impl SequenceFilter {
+    pub fn new() -> SequenceFilter {
+        SequenceFilter{
+            newest_seqs: HashMap::new()
+        }
+    }
+
+    pub fn accept(&mut self, stream: u64, seq: u32) -> bool {
+        let newest_seq = self.newest_seqs.entry(stream).or_insert(0);
+        if seq <= *newest_seq {
+            return false;
+        }
+
+        *newest_seq = seq;
+        true
+    }
+
+    pub fn open<TMsg>(&mut self, envelope: Envelope<TMsg>) -> Option<TMsg> {
+        match envelope {
+            Envelope::ReliableOrdered(msg) => Some(msg),
+            Envelope::UnreliableSequenced(stream, seq, msg) => if self.accept(stream, seq) { Some(msg) } else { None }
+        }
+    }
+}
+
+// Removes sequenced messages from a batch waiting to be sent when a later message on the same stream
+// is also in the batch, so a slow connection skips stale state rather than falling further behind
+pub fn drop_superseded<TMsg>(batch: &mut Vec<Envelope<TMsg>>) {
+    let mut seen_streams = HashSet::new();
+    let mut is_superseded = vec![false; batch.len()];
+    for (i, envelope) in batch.iter().enumerate().rev() {
+        if let Envelope::UnreliableSequenced(stream, _, _) = envelope {
+            is_superseded[i] = !seen_streams.insert(*stream);
+        }
+    }
+
+    let mut i = 0;
+    batch.retain(|_| {
+        i += 1;
+        !is_superseded[i - 1]
+    });
+}
@@ -1,36 +1,335 @@
 use std::collections::VecDeque;
 use std::time::Duration;
 use std::cell::{Cell, RefCell};
+use std::str::FromStr;
 use crate::network::Server;
 use crate::network::ServerMsg;
 use crate::network::ClientMsg;
 use crate::network::RxChannel;
 use crate::network::TxChannel;
-use crate::err::GgResult;
+use crate::network::Delivery;
+use crate::network::MsgKind;
+use crate::network::stats::{ChannelStats, TrafficStats};
+use crate::network::sequence::{Envelope, Sequencer, SequenceFilter};
+use crate::err::{GgError, GgResult};
+use serde::Serialize;
 use std::rc::Rc;
 
-type SimMsg<T> = (T, Duration);
+// a message with its arrival time and encoded length
+type SimMsg<T> = (Envelope<T>, Duration, usize);
+
+// How the extra delay on top of the base latency is spread
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum Jitter {
+    None,
+    // anywhere up to the given amount, equally likely
+    Uniform(Duration),
+    // usually small but now and then much longer, averaging the given amount
+    Exponential(Duration)
+}
+
+// What the simulated network does to the messages sent over it, in each direction
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct NetworkConditions {
+    pub latency: Duration,
+    pub jitter: Jitter,
+    // the chance of a message being lost. reliable messages are resent, which costs a round trip,
+    // unless the chance is 1 and the link is down altogether
+    pub loss: f32,
+    // the chance of an unreliable message being held back long enough for later ones to overtake it
+    pub reordering: f32,
+    // the chance of an unreliable message arriving twice
+    pub duplication: f32,
+    // bytes per second, messages queue behind each other once it is used up
+    pub bandwidth: Option<usize>,
+    // the same seed gives the same losses and delays on every run
+    pub seed: u64
+}
+
+impl NetworkConditions {
+    // a fixed delay and nothing else going wrong
+    pub fn ideal(latency: Duration) -> NetworkConditions {
+        NetworkConditions{
+            latency,
+            jitter: Jitter::None,
+            loss: 0.0,
+            reordering: 0.0,
+            duplication: 0.0,
+            bandwidth: None,
+            seed: 1
+        }
+    }
+}
+
+// the names of the profiles that NetworkConditions can be parsed from
+pub const PROFILES: &[&str] = &["ideal", "lan", "fibre", "broadband", "mobile", "terrible"];
+
+impl FromStr for NetworkConditions {
+    type Err = GgError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ms = Duration::from_millis;
+        match s {
+            "ideal" => Ok(NetworkConditions::ideal(ms(50))),
+            "lan" => Ok(NetworkConditions{
+                jitter: Jitter::Uniform(ms(1)),
+                ..NetworkConditions::ideal(ms(2))
+            }),
+            "fibre" => Ok(NetworkConditions{
+                jitter: Jitter::Uniform(ms(2)),
+                ..NetworkConditions::ideal(ms(10))
+            }),
+            "broadband" => Ok(NetworkConditions{
+                jitter: Jitter::Uniform(ms(10)),
+                loss: 0.01,
+                bandwidth: Some(1_000_000),
+                ..NetworkConditions::ideal(ms(30))
+            }),
+            "mobile" => Ok(NetworkConditions{
+                jitter: Jitter::Exponential(ms(30)),
+                loss: 0.03,
+                reordering: 0.02,
+                duplication: 0.01,
+                bandwidth: Some(100_000),
+                ..NetworkConditions::ideal(ms(80))
+            }),
+            "terrible" => Ok(NetworkConditions{
+                jitter: Jitter::Exponential(ms(100)),
+                loss: 0.1,
+                reordering: 0.05,
+                duplication: 0.05,
+                bandwidth: Some(20_000),
+                ..NetworkConditions::ideal(ms(200))
+            }),
+            _ => Err(GgError::Other(format!("unknown network profile '{}', expected one of {}", s, PROFILES.join(", "))))
+        }
+    }
+}
+
+// The conditions in each direction of a connection, which needn't be the same
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct LinkConditions {
+    // from the client to the server
+    pub uplink: NetworkConditions,
+    // from the server to the client
+    pub downlink: NetworkConditions
+}
+
+impl LinkConditions {
+    pub fn symmetric(conditions: NetworkConditions) -> LinkConditions {
+        LinkConditions{
+            uplink: conditions.clone(),
+            downlink: conditions
+        }
+    }
+}
+
+// either a single profile for both directions or an uplink and a downlink profile, as in mobile/fibre
+impl FromStr for LinkConditions {
+    type Err = GgError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('/') {
+            Some((uplink, downlink)) => Ok(LinkConditions{ uplink: uplink.parse()?, downlink: downlink.parse()? }),
+            None => Ok(LinkConditions::symmetric(s.parse()?))
+        }
+    }
+}
+
+// Changes the conditions of a connection while it is in use. Messages already on their way are
+// unaffected.
+#[derive(Clone)]
+pub struct SimLink {
+    uplink: Rc<RefCell<NetworkConditions>>,
+    downlink: Rc<RefCell<NetworkConditions>>
+}
+
+impl SimLink {
+    pub fn get(&self) -> LinkConditions {
+        LinkConditions{
+            uplink: self.uplink.borrow().clone(),
+            downlink: self.downlink.borrow().clone()
+        }
+    }
+
+    // the seed is only used when connecting, so changing it here has no effect
+    pub fn set(&self, conditions: LinkConditions) {
+        self.set_uplink(conditions.uplink);
+        self.set_downlink(conditions.downlink);
+    }
+
+    pub fn set_uplink(&self, conditions: NetworkConditions) {
+        *self.uplink.borrow_mut() = conditions;
+    }
+
+    pub fn set_downlink(&self, conditions: NetworkConditions) {
+        *self.downlink.borrow_mut() = conditions;
+    }
+}
+
+// xorshift64*, which is plenty for deciding the fate of simulated packets
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // the state must never be zero
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    // uniformly distributed in [0, 1)
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    fn chance(&mut self, probability: f32) -> bool {
+        self.next_f32() < probability
+    }
+}
 
 struct SimTxChannel<TMsg> {
     time: Rc<Cell<Duration>>,
-    latency: Duration,
+    conditions: Rc<RefCell<NetworkConditions>>,
+    rng: Rng,
+    // when the link will have finished sending what is already queued on it
+    link_free_time: Duration,
+    // reliable messages can't overtake each other however long their resends take
+    last_reliable_arrival_time: Duration,
+    // when each message still waiting for the link will leave
+    departure_times: VecDeque<Duration>,
+    sequencer: Sequencer,
+    stats: TrafficStats,
     pipe: Rc<RefCell<VecDeque<SimMsg<TMsg>>>>
 }
 
-impl<TMsg> TxChannel<TMsg> for SimTxChannel<TMsg> {
-    fn enqueue(&mut self, msg: TMsg) -> GgResult {
-        let arrival_time = self.time.get() + self.latency;
-        self.pipe.borrow_mut().push_back((msg, arrival_time));
+impl<TMsg> SimTxChannel<TMsg> {
+    fn new(time: Rc<Cell<Duration>>, conditions: Rc<RefCell<NetworkConditions>>, seed: u64, pipe: Rc<RefCell<VecDeque<SimMsg<TMsg>>>>) -> SimTxChannel<TMsg> {
+        SimTxChannel{
+            time,
+            conditions,
+            rng: Rng::new(seed),
+            link_free_time: Duration::from_millis(0),
+            last_reliable_arrival_time: Duration::from_millis(0),
+            departure_times: VecDeque::new(),
+            sequencer: Sequencer::new(),
+            stats: TrafficStats::default(),
+            pipe
+        }
+    }
+
+    fn jitter(&mut self, jitter: Jitter) -> Duration {
+        match jitter {
+            Jitter::None => Duration::from_millis(0),
+            Jitter::Uniform(max) => max.mul_f32(self.rng.next_f32()),
+            Jitter::Exponential(mean) => mean.mul_f32(-(1.0 - self.rng.next_f32()).ln())
+        }
+    }
+
+    // the time a message leaves once everything ahead of it on the link has gone
+    fn transmit(&mut self, length: usize, bandwidth: Option<usize>) -> Duration {
+        let start_time = self.link_free_time.max(self.time.get());
+        self.link_free_time = match bandwidth {
+            Some(bandwidth) => start_time + Duration::from_secs_f64(length as f64 / bandwidth as f64),
+            None => start_time
+        };
+        self.link_free_time
+    }
+
+    // keeps the pipe in order of arrival, with messages that arrive together kept in the order sent
+    fn deliver(&mut self, envelope: Envelope<TMsg>, arrival_time: Duration, length: usize) {
+        let mut pipe = self.pipe.borrow_mut();
+        let index = pipe.iter().rposition(|(_, t, _)| *t <= arrival_time).map_or(0, |i| i + 1);
+        pipe.insert(index, (envelope, arrival_time, length));
+    }
+
+    fn queue_depth(&self) -> usize {
+        let time = self.time.get();
+        self.departure_times.iter().filter(|&&departure_time| departure_time > time).count()
+    }
+}
+
+impl<TMsg> TxChannel<TMsg> for SimTxChannel<TMsg> where TMsg: Clone + Serialize + MsgKind {
+    fn enqueue(&mut self, msg: TMsg, delivery: Delivery) -> GgResult {
+        let conditions = self.conditions.borrow().clone();
+        let envelope = self.sequencer.seal(msg, delivery);
+        let length = serde_cbor::to_vec(&envelope)?.len();
+        self.stats.record_msg(envelope.msg().kind(), length);
+        self.stats.record_bytes(length);
+
+        let departure_time = self.transmit(length, conditions.bandwidth);
+        let time = self.time.get();
+        while matches!(self.departure_times.front(), Some(&t) if t <= time) {
+            self.departure_times.pop_front();
+        }
+        self.departure_times.push_back(departure_time);
+        let mut arrival_time = departure_time + conditions.latency + self.jitter(conditions.jitter);
+
+        match delivery {
+            Delivery::ReliableOrdered => {
+                // however often it is resent, nothing gets over a link that is down
+                if conditions.loss >= 1.0 {
+                    return Ok(());
+                }
+                // each loss is noticed and made good a round trip later
+                while self.rng.chance(conditions.loss) {
+                    arrival_time += conditions.latency * 2 + self.jitter(conditions.jitter);
+                }
+                arrival_time = arrival_time.max(self.last_reliable_arrival_time);
+                self.last_reliable_arrival_time = arrival_time;
+            },
+            Delivery::UnreliableSequenced(_) => {
+                if self.rng.chance(conditions.loss) {
+                    return Ok(());
+                }
+                if self.rng.chance(conditions.reordering) {
+                    arrival_time += conditions.latency;
+                }
+                if self.rng.chance(conditions.duplication) {
+                    let duplicate_arrival_time = departure_time + conditions.latency + self.jitter(conditions.jitter);
+                    self.deliver(envelope.clone(), duplicate_arrival_time, length);
+                }
+            }
+        }
+
+        self.deliver(envelope, arrival_time, length);
         Ok(())
     }
 }
 
 struct SimRxChannel<TMsg> {
     time: Rc<Cell<Duration>>,
+    sequence_filter: SequenceFilter,
+    stats: TrafficStats,
+    last_arrival_time: Option<Duration>,
     pipe: Rc<RefCell<VecDeque<SimMsg<TMsg>>>>
 }
 
-impl<TMsg> RxChannel<TMsg> for SimRxChannel<TMsg> {
+impl<TMsg> SimRxChannel<TMsg> {
+    fn new(time: Rc<Cell<Duration>>, pipe: Rc<RefCell<VecDeque<SimMsg<TMsg>>>>) -> SimRxChannel<TMsg> {
+        SimRxChannel{
+            time,
+            sequence_filter: SequenceFilter::new(),
+            stats: TrafficStats::default(),
+            last_arrival_time: None,
+            pipe
+        }
+    }
+}
+
+impl<TMsg> RxChannel<TMsg> for SimRxChannel<TMsg> where TMsg: MsgKind {
     fn dequeue(&mut self, buffer: &mut Vec::<TMsg>) -> GgResult {
         buffer.clear();
 
@@ -47,7 +346,13 @@ impl<TMsg> RxChannel<TMsg> for SimRxChannel<TMsg> {
                         return Ok(())
                     }
 
-                    buffer.push(pipe.pop_front().unwrap().0);
+                    let (envelope, arrival_time, length) = pipe.pop_front().unwrap();
+                    self.stats.record_msg(envelope.msg().kind(), length);
+                    self.stats.record_bytes(length);
+                    self.last_arrival_time = Some(arrival_time);
+                    if let Some(msg) = self.sequence_filter.open(envelope) {
+                        buffer.push(msg);
+                    }
                 },
                 None => return Ok(())
             }
@@ -60,13 +365,22 @@ pub struct SimNetworkEnd<TTx, TRx> {
     rx: SimRxChannel<TRx>
 }
 
-impl<TTx, TRx> TxChannel<TTx> for SimNetworkEnd<TTx, TRx>  {
-    fn enqueue(&mut self, msg: TTx) -> GgResult {
-        self.tx.enqueue(msg)
+impl<TTx, TRx> TxChannel<TTx> for SimNetworkEnd<TTx, TRx> where TTx: Clone + Serialize + MsgKind {
+    fn enqueue(&mut self, msg: TTx, delivery: Delivery) -> GgResult {
+        self.tx.enqueue(msg, delivery)
+    }
+
+    fn stats(&self) -> ChannelStats {
+        ChannelStats{
+            sent: self.tx.stats.clone(),
+            received: self.rx.stats.clone(),
+            queue_depth: self.tx.queue_depth(),
+            since_last_receive: self.rx.last_arrival_time.map(|time| self.rx.time.get() - time)
+        }
     }
 }
 
-impl<TTx, TRx> RxChannel<TRx> for SimNetworkEnd<TTx, TRx> {
+impl<TTx, TRx> RxChannel<TRx> for SimNetworkEnd<TTx, TRx> where TRx: MsgKind {
     fn dequeue(&mut self, buffer: &mut Vec::<TRx>) -> GgResult {
         self.rx.dequeue(buffer)
     }
@@ -74,50 +388,52 @@ impl<TTx, TRx> RxChannel<TRx> for SimNetworkEnd<TTx, TRx> {
 
 pub struct SimServer {
     time: Rc<Cell<Duration>>,
-    latency: Duration,
+    // for tests that connect clients without conditions of their own
+    #[cfg(test)]
+    conditions: NetworkConditions,
+    // mixed into each channel's seed so that two connections with the same conditions aren't equally unlucky
+    connection_count: u64,
     new_clients: Vec::<SimNetworkEnd<ServerMsg, ClientMsg>>
 }
 
 impl SimServer {
-    pub fn new(latency: Duration, time: Rc<Cell<Duration>>) -> SimServer {
+    pub fn with_time(time: Rc<Cell<Duration>>) -> SimServer {
         SimServer{
             time,
-            latency,
+            #[cfg(test)]
+            conditions: NetworkConditions::ideal(Duration::from_millis(0)),
+            connection_count: 0,
             new_clients: vec![]
         }
     }
 
-    pub fn connect(&mut self) -> SimNetworkEnd<ClientMsg, ServerMsg> {
+    // connects a client whose conditions can be changed later through the returned link
+    pub fn connect_with(&mut self, conditions: LinkConditions) -> (SimNetworkEnd<ClientMsg, ServerMsg>, SimLink) {
+
+        let uplink_seed = conditions.uplink.seed.wrapping_add(self.connection_count * 2);
+        let downlink_seed = conditions.downlink.seed.wrapping_add(self.connection_count * 2 + 1);
+        self.connection_count += 1;
+
+        let link = SimLink{
+            uplink: Rc::new(RefCell::new(conditions.uplink)),
+            downlink: Rc::new(RefCell::new(conditions.downlink))
+        };
 
         let pipe_up = Rc::new(RefCell::from(VecDeque::<SimMsg::<ClientMsg>>::new()));
         let pipe_down = Rc::new(RefCell::from(VecDeque::<SimMsg::<ServerMsg>>::new()));
 
-        let client_tx_channel = SimTxChannel{
-            time: Rc::clone(&self.time),
-            latency: self.latency,
-            pipe: Rc::clone(&pipe_up)
-        };
+        let client_tx_channel = SimTxChannel::new(Rc::clone(&self.time), Rc::clone(&link.uplink), uplink_seed, Rc::clone(&pipe_up));
 
-        let client_rx_channel = SimRxChannel{
-            time: Rc::clone(&self.time),
-            pipe: Rc::clone(&pipe_down)
-        };
+        let client_rx_channel = SimRxChannel::new(Rc::clone(&self.time), Rc::clone(&pipe_down));
 
         let client_end = SimNetworkEnd{
             tx: client_tx_channel,
             rx: client_rx_channel
         };
 
-        let server_tx_channel = SimTxChannel{
-            time: Rc::clone(&self.time),
-            latency: self.latency,
-            pipe: Rc::clone(&pipe_down)
-        };
+        let server_tx_channel = SimTxChannel::new(Rc::clone(&self.time), Rc::clone(&link.downlink), downlink_seed, Rc::clone(&pipe_down));
 
-        let server_rx_channel = SimRxChannel{
-            time: Rc::clone(&self.time),
-            pipe: Rc::clone(&pipe_up)
-        };
+        let server_rx_channel = SimRxChannel::new(Rc::clone(&self.time), Rc::clone(&pipe_up));
 
         let server_end = SimNetworkEnd{
             tx: server_tx_channel,
@@ -126,7 +442,26 @@ impl SimServer {
 
         self.new_clients.push(server_end);
 
-        client_end
+        (client_end, link)
+    }
+}
+
+#[cfg(test)]
+impl SimServer {
+    pub fn new(latency: Duration, time: Rc<Cell<Duration>>) -> SimServer {
+        SimServer::with_conditions(NetworkConditions::ideal(latency), time)
+    }
+
+    pub fn with_conditions(conditions: NetworkConditions, time: Rc<Cell<Duration>>) -> SimServer {
+        SimServer{
+            conditions,
+            ..SimServer::with_time(time)
+        }
+    }
+
+    pub fn connect(&mut self) -> SimNetworkEnd<ClientMsg, ServerMsg> {
+        let conditions = LinkConditions::symmetric(self.conditions.clone());
+        self.connect_with(conditions).0
     }
 }
 
@@ -187,11 +522,11 @@ fn _test_sim_server(latency: u64, client_actions: Vec::<Vec::<u32>>, server_acti
     for step in 0..(client_msgs.len() + latency as usize) {
         if step < client_msgs.len() {
             for client_action in client_msgs[step].iter().map(|m| m.clone()) {
-                client_end.enqueue(client_action).unwrap();
+                client_end.enqueue(client_action, Delivery::ReliableOrdered).unwrap();
             }
 
             for server_action in server_msgs[step].iter().map(|m| m.clone()) {
-                server_end.enqueue(server_action).unwrap();
+                server_end.enqueue(server_action, Delivery::ReliableOrdered).unwrap();
             }
         }
 
@@ -221,4 +556,214 @@ fn _test_sim_server(latency: u64, client_actions: Vec::<Vec::<u32>>, server_acti
 
         time.set(time.get() + Duration::from_millis(1));
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_sim_sequenced_delivery() {
+    let time = Rc::new(Cell::new(Duration::from_millis(0u64)));
+    let mut server = SimServer::new(Duration::from_millis(0), Rc::clone(&time));
+
+    let mut client_end = server.connect();
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+    let server_end = &mut new_clients[0];
+
+    server_end.enqueue(ServerMsg::Test(1), Delivery::UnreliableSequenced(1)).unwrap();
+    server_end.enqueue(ServerMsg::Test(2), Delivery::UnreliableSequenced(1)).unwrap();
+    server_end.enqueue(ServerMsg::Test(3), Delivery::UnreliableSequenced(2)).unwrap();
+    server_end.enqueue(ServerMsg::Test(4), Delivery::ReliableOrdered).unwrap();
+
+    // the two messages on stream 1 arrive out of order
+    server_end.tx.pipe.borrow_mut().swap(0, 1);
+
+    let mut client_msg_buffer = vec![];
+    client_end.dequeue(&mut client_msg_buffer).unwrap();
+
+    // the older message on stream 1 is dropped because a newer one was already received
+    assert_eq!(vec![ServerMsg::Test(2), ServerMsg::Test(3), ServerMsg::Test(4)], client_msg_buffer);
+}
+
+#[cfg(test)]
+fn send_and_receive(conditions: NetworkConditions, delivery: fn(u32) -> Delivery) -> Vec<(Duration, ServerMsg)> {
+    let time = Rc::new(Cell::new(Duration::from_millis(0u64)));
+    let mut server = SimServer::with_conditions(conditions, Rc::clone(&time));
+    let mut client_end = server.connect();
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+
+    // a message every 10ms for 10s, then time for the stragglers to arrive
+    let mut received = vec![];
+    let mut buffer = vec![];
+    for step in 0..2000u32 {
+        if step < 1000 {
+            new_clients[0].enqueue(ServerMsg::Test(step), delivery(step)).unwrap();
+        }
+        client_end.dequeue(&mut buffer).unwrap();
+        received.extend(buffer.drain(..).map(|msg| (time.get(), msg)));
+        time.set(time.get() + Duration::from_millis(10));
+    }
+
+    received
+}
+
+#[cfg(test)]
+fn bad_network() -> NetworkConditions {
+    NetworkConditions{
+        jitter: Jitter::Exponential(Duration::from_millis(50)),
+        loss: 0.2,
+        reordering: 0.1,
+        duplication: 0.1,
+        ..NetworkConditions::ideal(Duration::from_millis(100))
+    }
+}
+
+#[test]
+fn test_unreliable_conditions() {
+    // every message on its own stream, so that none are dropped for being out of date
+    let received = send_and_receive(bad_network(), |step| Delivery::UnreliableSequenced(step as u64));
+
+    let mut seqs = received.iter().map(|(_, msg)| match msg { ServerMsg::Test(seq) => *seq, _ => panic!() }).collect::<Vec<_>>();
+    assert!(seqs.windows(2).any(|pair| pair[0] > pair[1]), "expected some reordering");
+
+    // duplicates are removed by the receiver like any other out of date message
+    let count = seqs.len();
+    seqs.sort();
+    seqs.dedup();
+    assert_eq!(count, seqs.len());
+    assert!(count > 700 && count < 880, "expected about 20% loss but {} of 1000 arrived", count);
+
+    // and the same seed gives the same outcome
+    assert_eq!(received, send_and_receive(bad_network(), |step| Delivery::UnreliableSequenced(step as u64)));
+    let reseeded = NetworkConditions{ seed: 2, ..bad_network() };
+    assert_ne!(received, send_and_receive(reseeded, |step| Delivery::UnreliableSequenced(step as u64)));
+}
+
+#[test]
+fn test_reliable_conditions() {
+    let received = send_and_receive(bad_network(), |_| Delivery::ReliableOrdered);
+
+    // everything arrives, once and in order, though some of it late
+    let seqs = received.iter().map(|(_, msg)| match msg { ServerMsg::Test(seq) => *seq, _ => panic!() }).collect::<Vec<_>>();
+    assert_eq!((0..1000).collect::<Vec<_>>(), seqs);
+    assert!(received.iter().enumerate().any(|(i, (time, _))| *time > Duration::from_millis(i as u64 * 10 + 300)));
+}
+
+#[test]
+fn test_dead_link() {
+    let dead_network = || NetworkConditions{ loss: 1.0, ..NetworkConditions::ideal(Duration::from_millis(100)) };
+    assert!(send_and_receive(dead_network(), |_| Delivery::ReliableOrdered).is_empty());
+    assert!(send_and_receive(dead_network(), |step| Delivery::UnreliableSequenced(step as u64)).is_empty());
+}
+
+#[test]
+fn test_bandwidth() {
+    let time = Rc::new(Cell::new(Duration::from_millis(0u64)));
+    let conditions = NetworkConditions{ bandwidth: Some(10_000), ..NetworkConditions::ideal(Duration::from_millis(0)) };
+    let mut server = SimServer::with_conditions(conditions, Rc::clone(&time));
+    let mut client_end = server.connect();
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+
+    // 10 messages of about 1kB take about a second to get through a 10kB/s link
+    for _ in 0..10 {
+        new_clients[0].enqueue(ServerMsg::Reject("x".repeat(1000)), Delivery::ReliableOrdered).unwrap();
+    }
+
+    let mut buffer = vec![];
+    time.set(Duration::from_millis(500));
+    client_end.dequeue(&mut buffer).unwrap();
+    assert_eq!(4, buffer.len());
+
+    time.set(Duration::from_millis(1100));
+    client_end.dequeue(&mut buffer).unwrap();
+    assert_eq!(6, buffer.len());
+}
+
+#[test]
+fn test_profiles() {
+    for profile in PROFILES.iter() {
+        assert!(profile.parse::<NetworkConditions>().is_ok());
+    }
+    assert!("dialup".parse::<NetworkConditions>().is_err());
+}
+
+#[test]
+fn test_link_conditions() {
+    let time = Rc::new(Cell::new(Duration::from_millis(0u64)));
+    let mut server = SimServer::new(Duration::from_millis(0), Rc::clone(&time));
+    let uplink = NetworkConditions::ideal(Duration::from_millis(100));
+    let downlink = NetworkConditions::ideal(Duration::from_millis(20));
+    let (mut client_end, link) = server.connect_with(LinkConditions{ uplink, downlink });
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+
+    client_end.enqueue(ClientMsg::Test(1), Delivery::ReliableOrdered).unwrap();
+    new_clients[0].enqueue(ServerMsg::Test(1), Delivery::ReliableOrdered).unwrap();
+
+    let mut client_msg_buffer = vec![];
+    let mut server_msg_buffer = vec![];
+    time.set(Duration::from_millis(20));
+    new_clients[0].dequeue(&mut client_msg_buffer).unwrap();
+    client_end.dequeue(&mut server_msg_buffer).unwrap();
+    assert!(client_msg_buffer.is_empty());
+    assert_eq!(vec![ServerMsg::Test(1)], server_msg_buffer);
+
+    time.set(Duration::from_millis(100));
+    new_clients[0].dequeue(&mut client_msg_buffer).unwrap();
+    assert_eq!(vec![ClientMsg::Test(1)], client_msg_buffer);
+
+    // the client moves somewhere with a better connection while a message is on its way
+    client_end.enqueue(ClientMsg::Test(2), Delivery::ReliableOrdered).unwrap();
+    link.set_uplink(NetworkConditions::ideal(Duration::from_millis(10)));
+    client_end.enqueue(ClientMsg::Test(3), Delivery::ReliableOrdered).unwrap();
+    client_end.enqueue(ClientMsg::Test(4), Delivery::UnreliableSequenced(0)).unwrap();
+
+    // the unreliable message can overtake the slow one, but the reliable one can't
+    time.set(Duration::from_millis(110));
+    new_clients[0].dequeue(&mut client_msg_buffer).unwrap();
+    assert_eq!(vec![ClientMsg::Test(4)], client_msg_buffer);
+
+    time.set(Duration::from_millis(200));
+    new_clients[0].dequeue(&mut client_msg_buffer).unwrap();
+    assert_eq!(vec![ClientMsg::Test(2), ClientMsg::Test(3)], client_msg_buffer);
+    assert_eq!(Duration::from_millis(10), link.get().uplink.latency);
+
+    assert_eq!(LinkConditions{ uplink: "mobile".parse().unwrap(), downlink: "fibre".parse().unwrap() }, "mobile/fibre".parse().unwrap());
+    assert!("mobile/".parse::<LinkConditions>().is_err());
+}
+
+#[test]
+fn test_stats() {
+    let time = Rc::new(Cell::new(Duration::from_millis(0u64)));
+    let conditions = NetworkConditions{ bandwidth: Some(10_000), ..NetworkConditions::ideal(Duration::from_millis(0)) };
+    let mut server = SimServer::with_conditions(conditions, Rc::clone(&time));
+    let mut client_end = server.connect();
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+
+    for _ in 0..10 {
+        new_clients[0].enqueue(ServerMsg::Reject("x".repeat(1000)), Delivery::ReliableOrdered).unwrap();
+    }
+    new_clients[0].enqueue(ServerMsg::Heartbeat, Delivery::ReliableOrdered).unwrap();
+
+    // what the link hasn't got round to yet is still queued
+    let stats = new_clients[0].stats();
+    assert_eq!(11, stats.sent.msgs);
+    assert_eq!(10, stats.sent.by_kind["Reject"].msgs);
+    assert!(stats.sent.by_kind["Reject"].bytes > 10_000);
+    assert_eq!(1, stats.sent.by_kind["Heartbeat"].msgs);
+    assert_eq!(11, stats.queue_depth);
+    assert_eq!(None, stats.since_last_receive);
+
+    time.set(Duration::from_millis(500));
+    assert_eq!(7, new_clients[0].stats().queue_depth);
+
+    // and the other end receives exactly what was sent
+    time.set(Duration::from_millis(1500));
+    let mut buffer = vec![];
+    client_end.dequeue(&mut buffer).unwrap();
+    let client_stats = client_end.stats();
+    assert_eq!(0, new_clients[0].stats().queue_depth);
+    assert_eq!(new_clients[0].stats().sent, client_stats.received);
+    assert!(client_stats.since_last_receive.unwrap() > Duration::from_millis(300));
+}
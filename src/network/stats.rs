@@ -0,0 +1,92 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+use std::fmt;
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Default)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct KindStats {
+    pub msgs: u64,
+    pub bytes: u64
+}
+
+// What has gone over a connection in one direction
+#[derive(Clone)]
+#[derive(Default)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct TrafficStats {
+    pub msgs: u64,
+    // everything that went over the wire, framing and compression included
+    pub bytes: u64,
+    // by message variant, with each message's size as encoded on its own
+    pub by_kind: BTreeMap<&'static str, KindStats>
+}
+
+impl TrafficStats {
+    pub fn record_msg(&mut self, kind: &'static str, bytes: usize) {
+        self.msgs += 1;
+        let kind_stats = self.by_kind.entry(kind).or_default();
+        kind_stats.msgs += 1;
+        kind_stats.bytes += bytes as u64;
+    }
+
+    pub fn record_bytes(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+    }
+}
+
+impl fmt::Display for TrafficStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} msgs, {} bytes", self.msgs, self.bytes)?;
+        // the biggest spenders first
+        let mut kinds = self.by_kind.iter().collect::<Vec<_>>();
+        kinds.sort_by_key(|(_, kind_stats)| std::cmp::Reverse(kind_stats.bytes));
+        for (kind, kind_stats) in kinds {
+            write!(f, ", {} {}/{}B", kind, kind_stats.msgs, kind_stats.bytes)?;
+        }
+        Ok(())
+    }
+}
+
+// What a connection has cost so far, and how it is keeping up
+#[derive(Clone)]
+#[derive(Default)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct ChannelStats {
+    pub sent: TrafficStats,
+    pub received: TrafficStats,
+    // messages enqueued but not yet sent
+    pub queue_depth: usize,
+    // None until something has been received
+    pub since_last_receive: Option<Duration>
+}
+
+impl fmt::Display for ChannelStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sent {}; received {}; {} queued", self.sent, self.received, self.queue_depth)?;
+        if let Some(since_last_receive) = self.since_last_receive {
+            write!(f, "; last heard {:?} ago", since_last_receive)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_traffic_stats() {
+    let mut subject = TrafficStats::default();
+    subject.record_msg("SetBody", 40);
+    subject.record_msg("SetBody", 44);
+    subject.record_msg("Kill", 6);
+    subject.record_bytes(100);
+
+    assert_eq!(3, subject.msgs);
+    assert_eq!(100, subject.bytes);
+    assert_eq!(KindStats{ msgs: 2, bytes: 84 }, subject.by_kind["SetBody"]);
+    assert_eq!(KindStats{ msgs: 1, bytes: 6 }, subject.by_kind["Kill"]);
+    assert!(!subject.by_kind.contains_key("Heartbeat"));
+    assert_eq!("3 msgs, 100 bytes, SetBody 2/84B, Kill 1/6B", subject.to_string());
+}
@@ -0,0 +1,656 @@
+use crate::network::Server;
+use crate::network::ClientMsg;
+use crate::network::ServerMsg;
+use crate::network::Delivery;
+use crate::network::sequence::{Sequencer, SequenceFilter};
+use crate::network::RxChannel;
+use crate::network::TxChannel;
+use crate::err::GgResult;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::channel;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use serde::de::DeserializeOwned;
+use serde::{Serialize, Deserialize};
+
+// how long to wait for an ack before sending a reliable datagram again
+const RESEND_PERIOD: Duration = Duration::from_millis(100);
+// how long a reliable datagram can go unacknowledged before the connection is considered lost
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_DATAGRAM_SIZE: usize = 65507;
+// how often the server forgets peers whose connections have closed, whether or not they are heard from
+const PEER_SWEEP_PERIOD: Duration = Duration::from_secs(1);
+// how many peers the server takes on before they have acked anything. anyone can send a Connect
+// with someone else's address, but only the address's owner can ack what the server sends back
+const MAX_UNPROVEN_PEERS: usize = 64;
+
+// Reliable datagrams carry a per-connection sequence number and are resent until acked, then
+// delivered in order. Unreliable datagrams carry a sequence number per stream and are
+// delivered only if they are newer than anything already delivered on that stream.
+#[derive(Deserialize)]
+#[derive(Serialize)]
+#[derive(Debug)]
+enum Datagram<TMsg> {
+    Connect,
+    Accept,
+    Reliable(u32, TMsg),
+    Unreliable(u64, u32, TMsg),
+    Ack(u32)
+}
+
+struct Unacked {
+    seq: u32,
+    first_sent: Instant,
+    last_sent: Instant,
+    datagram: Vec<u8>
+}
+
+struct Outbox {
+    is_accepted: bool,
+    last_connect_attempt: Option<Instant>,
+    next_reliable_seq: u32,
+    sequencer: Sequencer,
+    unacked: VecDeque<Unacked>
+}
+
+impl Outbox {
+    fn new(is_accepted: bool) -> Outbox {
+        Outbox{
+            is_accepted,
+            last_connect_attempt: None,
+            next_reliable_seq: 0,
+            sequencer: Sequencer::new(),
+            unacked: VecDeque::new()
+        }
+    }
+
+    fn encode<TMsg>(&mut self, msg: TMsg, delivery: Delivery) -> GgResult<Vec<u8>> where TMsg: Serialize {
+        match delivery {
+            Delivery::UnreliableSequenced(stream) => {
+                let seq = self.sequencer.next(stream);
+                Ok(serde_cbor::to_vec(&Datagram::Unreliable(stream, seq, msg))?)
+            },
+            Delivery::ReliableOrdered => {
+                let seq = self.next_reliable_seq;
+                self.next_reliable_seq += 1;
+                let datagram = serde_cbor::to_vec(&Datagram::Reliable(seq, msg))?;
+                let now = Instant::now();
+                self.unacked.push_back(Unacked{
+                    seq,
+                    first_sent: now,
+                    last_sent: now,
+                    datagram: datagram.clone()
+                });
+                Ok(datagram)
+            }
+        }
+    }
+
+    // the receiver acks with the next reliable sequence number it expects, so
+    // everything before that has arrived
+    fn ack(&mut self, next_expected_seq: u32) {
+        while let Some(unacked) = self.unacked.front() {
+            if unacked.seq >= next_expected_seq {
+                break;
+            }
+            self.unacked.pop_front();
+        }
+    }
+
+    fn collect_overdue(&mut self, now: Instant, buffer: &mut Vec<Vec<u8>>) -> GgResult {
+        for unacked in self.unacked.iter_mut() {
+            if now - unacked.first_sent > CONNECTION_TIMEOUT {
+                return Err("connection timed out".into());
+            }
+
+            if now - unacked.last_sent >= RESEND_PERIOD {
+                unacked.last_sent = now;
+                buffer.push(unacked.datagram.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn is_connect_due(&mut self, now: Instant) -> bool {
+        if self.is_accepted {
+            return false;
+        }
+
+        let is_due = match self.last_connect_attempt {
+            Some(last) => now - last >= RESEND_PERIOD,
+            None => true
+        };
+
+        if is_due {
+            self.last_connect_attempt = Some(now);
+        }
+
+        is_due
+    }
+}
+
+struct Inbox<TMsg> {
+    next_reliable_seq: u32,
+    early: BTreeMap<u32, TMsg>,
+    sequence_filter: SequenceFilter
+}
+
+impl<TMsg> Inbox<TMsg> {
+    fn new() -> Inbox<TMsg> {
+        Inbox{
+            next_reliable_seq: 0,
+            early: BTreeMap::new(),
+            sequence_filter: SequenceFilter::new()
+        }
+    }
+
+    fn receive_reliable(&mut self, seq: u32, msg: TMsg, buffer: &mut Vec<TMsg>) {
+        if seq < self.next_reliable_seq {
+            // duplicate of something already delivered
+            return;
+        }
+
+        self.early.insert(seq, msg);
+        while let Some(msg) = self.early.remove(&self.next_reliable_seq) {
+            buffer.push(msg);
+            self.next_reliable_seq += 1;
+        }
+    }
+
+    fn receive_unreliable(&mut self, stream: u64, seq: u32, msg: TMsg, buffer: &mut Vec<TMsg>) {
+        if self.sequence_filter.accept(stream, seq) {
+            buffer.push(msg);
+        }
+    }
+}
+
+// handles a datagram from an established peer, delivering any messages that are now ready
+fn receive<TRx>(
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    datagram: Datagram<TRx>,
+    inbox: &mut Inbox<TRx>,
+    outbox: &Mutex<Outbox>,
+    rx_q_out: &Sender<TRx>
+) -> GgResult where TRx: std::fmt::Debug {
+    let mut ready = vec![];
+
+    match datagram {
+        Datagram::Reliable(seq, msg) => {
+            inbox.receive_reliable(seq, msg, &mut ready);
+            let ack = serde_cbor::to_vec(&Datagram::<()>::Ack(inbox.next_reliable_seq))?;
+            socket.send_to(&ack, peer)?;
+        },
+        Datagram::Unreliable(stream, seq, msg) => inbox.receive_unreliable(stream, seq, msg, &mut ready),
+        Datagram::Ack(next_expected_seq) => outbox.lock().unwrap().ack(next_expected_seq),
+        Datagram::Accept => outbox.lock().unwrap().is_accepted = true,
+        Datagram::Connect => {}
+    }
+
+    for msg in ready.drain(..) {
+        #[cfg(debug)]
+        println!("<-- {:?}", &msg);
+
+        rx_q_out.send(msg)?;
+    }
+
+    Ok(())
+}
+
+fn tx_loop<TTx>(
+    socket: UdpSocket,
+    peer: SocketAddr,
+    tx_q_in: Receiver<(TTx, Delivery)>,
+    outbox: Arc<Mutex<Outbox>>
+) -> GgResult where TTx: Serialize + std::fmt::Debug {
+    let mut overdue = vec![];
+    let connect = serde_cbor::to_vec(&Datagram::<()>::Connect)?;
+    loop {
+        let now = Instant::now();
+        {
+            let mut outbox = outbox.lock().unwrap();
+            if outbox.is_connect_due(now) {
+                overdue.push(connect.clone());
+            }
+            outbox.collect_overdue(now, &mut overdue)?;
+        }
+
+        for datagram in overdue.drain(..) {
+            socket.send_to(&datagram, peer)?;
+        }
+
+        match tx_q_in.recv_timeout(RESEND_PERIOD) {
+            Ok((msg, delivery)) => {
+                #[cfg(debug)]
+                println!("--> {:?}", &msg);
+
+                let datagram = outbox.lock().unwrap().encode(msg, delivery)?;
+                socket.send_to(&datagram, peer)?;
+            },
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => return Ok(())
+        }
+    }
+}
+
+fn client_rx_loop<TRx>(
+    socket: UdpSocket,
+    server: SocketAddr,
+    rx_q_out: Sender<TRx>,
+    outbox: Arc<Mutex<Outbox>>,
+    is_closed: Arc<AtomicBool>
+) -> GgResult where TRx: DeserializeOwned + std::fmt::Debug {
+    let mut inbox = Inbox::new();
+    let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+    socket.set_read_timeout(Some(RESEND_PERIOD))?;
+    loop {
+        if is_closed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let (length, addr) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e.into())
+        };
+
+        if addr != server {
+            continue;
+        }
+
+        // a corrupt datagram is dropped just like a lost one would be
+        if let Ok(datagram) = serde_cbor::from_slice::<Datagram<TRx>>(&buffer[0..length]) {
+            receive(&socket, server, datagram, &mut inbox, &outbox, &rx_q_out)?;
+        }
+    }
+}
+
+pub struct UdpNetwork<TTx, TRx>{
+    is_closed: Arc<AtomicBool>,
+    tx_q_out: Option<Sender<(TTx, Delivery)>>,
+    rx_q_in: Option<Receiver<TRx>>,
+    // disconnected once the tx thread has sent everything and exited
+    tx_finished: Receiver<()>,
+
+    phantom1: PhantomData<TTx>,
+    phantom2: PhantomData<TRx>,
+}
+
+impl<TTx, TRx> UdpNetwork<TTx, TRx>
+    where
+        TTx: 'static + Send + Serialize + std::fmt::Debug,
+        TRx: 'static + Send + DeserializeOwned + std::fmt::Debug{
+
+    pub fn connect<TAddr>(server_addr: TAddr) -> GgResult<UdpNetwork<TTx, TRx>> where TAddr: ToSocketAddrs {
+        let server = match server_addr.to_socket_addrs()?.next() {
+            Some(addr) => addr,
+            None => return Err("could not resolve server address".into())
+        };
+
+        let socket = UdpSocket::bind(if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+
+        let (tx_q_out, tx_q_in) = channel::<(TTx, Delivery)>();
+        let (rx_q_out, rx_q_in) = channel::<TRx>();
+
+        let is_closed = Arc::new(AtomicBool::new(false));
+        let tx_is_closed = is_closed.clone();
+        let rx_is_closed = is_closed.clone();
+
+        let outbox = Arc::new(Mutex::new(Outbox::new(false)));
+        let tx_outbox = outbox.clone();
+
+        let tx_socket = socket.try_clone()?;
+        let (tx_finished_send, tx_finished) = channel::<()>();
+
+        std::thread::spawn(move || {
+            let _tx_finished_send = tx_finished_send;
+            let result = tx_loop(tx_socket, server, tx_q_in, tx_outbox);
+
+            #[cfg(debug)]
+            println!("tx loop exited: {:?}", result);
+
+            tx_is_closed.store(true, Ordering::Relaxed);
+            result
+        });
+
+        std::thread::spawn(move || {
+            let result = client_rx_loop(socket, server, rx_q_out, outbox, rx_is_closed.clone());
+
+            #[cfg(debug)]
+            println!("rx loop exited: {:?}", result);
+
+            rx_is_closed.store(true, Ordering::Relaxed);
+            result
+        });
+
+        Ok(UdpNetwork{
+            is_closed,
+            tx_q_out: Some(tx_q_out),
+            rx_q_in: Some(rx_q_in),
+            tx_finished,
+            phantom1: PhantomData{},
+            phantom2: PhantomData{}
+        })
+    }
+}
+
+impl<TTx, TRx> Drop for UdpNetwork<TTx, TRx> {
+    // give anything already enqueued, such as a goodbye, one chance to be sent, which the tx thread
+    // does on its own
+    fn drop(&mut self) {
+        self.tx_q_out = None;
+    }
+}
+
+impl<TTx, TRx> TxChannel<TTx> for UdpNetwork<TTx, TRx> {
+    fn enqueue(&mut self, msg: TTx, delivery: Delivery) -> GgResult{
+        let is_closed = self.is_closed.load(std::sync::atomic::Ordering::Relaxed);
+        if is_closed {
+            Err("channel closed".into())
+        } else {
+            self.tx_q_out.as_ref().unwrap().send((msg, delivery))?;
+            Ok(())
+        }
+    }
+
+    fn close(&mut self) {
+        self.is_closed.store(true, Ordering::Relaxed);
+        self.tx_q_out = None;
+        let _ = self.tx_finished.recv_timeout(crate::network::CLOSE_FLUSH_WAIT);
+    }
+}
+
+impl<TTx, TRx> RxChannel<TRx> for UdpNetwork<TTx, TRx> {
+    fn dequeue(&mut self, buffer: &mut Vec::<TRx>) -> GgResult{
+        let is_closed = self.is_closed.load(std::sync::atomic::Ordering::Relaxed);
+        if is_closed {
+            Err("channel closed".into())
+        } else {
+            buffer.clear();
+            buffer.extend(self.rx_q_in.as_ref().unwrap().try_iter());
+            Ok(())
+        }
+    }
+}
+
+// the server end of a connection, as seen by the listen thread
+struct Peer {
+    is_closed: Arc<AtomicBool>,
+    outbox: Arc<Mutex<Outbox>>,
+    inbox: Inbox<ClientMsg>,
+    rx_q_out: Sender<ClientMsg>,
+    // whether it has acked anything we sent, and so really is at its address
+    is_proven: bool
+}
+
+fn accept_peer(socket: &UdpSocket, addr: SocketAddr) -> GgResult<(Peer, UdpNetwork<ServerMsg, ClientMsg>)> {
+    let (tx_q_out, tx_q_in) = channel::<(ServerMsg, Delivery)>();
+    let (rx_q_out, rx_q_in) = channel::<ClientMsg>();
+
+    let is_closed = Arc::new(AtomicBool::new(false));
+    let tx_is_closed = is_closed.clone();
+
+    let outbox = Arc::new(Mutex::new(Outbox::new(true)));
+    let tx_outbox = outbox.clone();
+
+    let tx_socket = socket.try_clone()?;
+    let (tx_finished_send, tx_finished) = channel::<()>();
+
+    std::thread::spawn(move || {
+        let _tx_finished_send = tx_finished_send;
+        let result = tx_loop(tx_socket, addr, tx_q_in, tx_outbox);
+
+        #[cfg(debug)]
+        println!("tx loop exited: {:?}", result);
+
+        tx_is_closed.store(true, Ordering::Relaxed);
+        result
+    });
+
+    let peer = Peer{
+        is_closed: is_closed.clone(),
+        outbox,
+        inbox: Inbox::new(),
+        rx_q_out,
+        is_proven: false
+    };
+
+    let network = UdpNetwork{
+        is_closed,
+        tx_q_out: Some(tx_q_out),
+        rx_q_in: Some(rx_q_in),
+        tx_finished,
+        phantom1: PhantomData{},
+        phantom2: PhantomData{}
+    };
+
+    Ok((peer, network))
+}
+
+fn listen_loop(
+    socket: UdpSocket,
+    new_client_send: Sender<UdpNetwork<ServerMsg, ClientMsg>>
+) -> GgResult {
+    let mut peers = HashMap::<SocketAddr, Peer>::new();
+    let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+    let accept = serde_cbor::to_vec(&Datagram::<()>::Accept)?;
+    // so that closed peers are swept even while nothing arrives
+    socket.set_read_timeout(Some(PEER_SWEEP_PERIOD))?;
+    let mut last_sweep = Instant::now();
+    loop {
+        // errors here are about a single datagram or peer (eg. ICMP port unreachable
+        // on Windows) so they must not bring down the whole server
+        let received = socket.recv_from(&mut buffer);
+
+        if last_sweep.elapsed() >= PEER_SWEEP_PERIOD {
+            peers.retain(|_, peer| !peer.is_closed.load(Ordering::Relaxed));
+            last_sweep = Instant::now();
+        }
+
+        let (length, addr) = match received {
+            Ok(received) => received,
+            Err(_) => continue
+        };
+
+        let datagram = match serde_cbor::from_slice::<Datagram<ClientMsg>>(&buffer[0..length]) {
+            Ok(datagram) => datagram,
+            Err(_) => continue
+        };
+
+        let is_closed = peers.get(&addr).map(|peer| peer.is_closed.load(Ordering::Relaxed));
+        if is_closed == Some(true) {
+            peers.remove(&addr);
+        }
+
+        if let Datagram::Connect = datagram {
+            // the client keeps sending Connect until it sees an Accept, which may have been lost
+            if !peers.contains_key(&addr) {
+                let unproven_count = peers.values().filter(|peer| !peer.is_proven).count();
+                // the client will try again, by when some of the others may have been proven or swept
+                if unproven_count >= MAX_UNPROVEN_PEERS {
+                    continue;
+                }
+
+                let (peer, network) = match accept_peer(&socket, addr) {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        println!("failed to set up a connection from {}: {}", addr, e);
+                        continue;
+                    }
+                };
+                if let Err(e) = new_client_send.send(network) {
+                    println!("failed to hand over a connection from {}: {}", addr, e);
+                    continue;
+                }
+                peers.insert(addr, peer);
+            }
+            let _ = socket.send_to(&accept, addr);
+            continue;
+        }
+
+        if let Some(peer) = peers.get_mut(&addr) {
+            if let Datagram::Ack(_) = datagram {
+                peer.is_proven = true;
+            }
+            if receive(&socket, addr, datagram, &mut peer.inbox, &peer.outbox, &peer.rx_q_out).is_err() {
+                peer.is_closed.store(true, Ordering::Relaxed);
+                peers.remove(&addr);
+            }
+        }
+    }
+}
+
+pub struct UdpServer {
+    #[allow(dead_code)]
+    listen_thread: JoinHandle<GgResult>,
+    new_client_recv: Receiver<UdpNetwork<ServerMsg, ClientMsg>>,
+    local_addr: SocketAddr
+}
+
+impl UdpServer {
+    // binding to port 0 picks any free port, use local_addr to find out which
+    pub fn new(addr: SocketAddr) -> GgResult<UdpServer> {
+        let (new_client_send, new_client_recv) = channel();
+        let socket = UdpSocket::bind(addr)?;
+        let local_addr = socket.local_addr()?;
+        let listen_thread = std::thread::spawn(move || listen_loop(socket, new_client_send));
+
+        println!("ggs is listening on udp {}", local_addr);
+
+        Ok(UdpServer {
+            listen_thread,
+            new_client_recv,
+            local_addr
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Server<UdpNetwork<ServerMsg, ClientMsg>> for UdpServer {
+    fn get_new_clients(&mut self, buffer: &mut Vec<UdpNetwork<ServerMsg, ClientMsg>>) {
+        buffer.clear();
+        buffer.extend(self.new_client_recv.try_iter());
+    }
+}
+
+#[test]
+fn test_udp_network() {
+
+    let mut server = UdpServer::new("127.0.0.1:0".parse().unwrap()).unwrap();
+    let mut new_clients = vec![];
+
+    // connect one at a time so that the server end of each client is known
+    let mut client_1 = UdpNetwork::<ClientMsg, ServerMsg>::connect(server.local_addr()).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    server.get_new_clients(&mut new_clients);
+    assert_eq!(1, new_clients.len());
+    let mut server_end_1 = new_clients.pop().unwrap();
+
+    let mut client_2 = UdpNetwork::<ClientMsg, ServerMsg>::connect(server.local_addr()).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    server.get_new_clients(&mut new_clients);
+    assert_eq!(1, new_clients.len());
+    let mut server_end_2 = new_clients.pop().unwrap();
+
+    client_1.enqueue(ClientMsg::Test(1), Delivery::ReliableOrdered).unwrap();
+    client_2.enqueue(ClientMsg::Test(2), Delivery::ReliableOrdered).unwrap();
+    client_1.enqueue(ClientMsg::Test(3), Delivery::ReliableOrdered).unwrap();
+    client_2.enqueue(ClientMsg::Test(4), Delivery::ReliableOrdered).unwrap();
+    client_1.enqueue(ClientMsg::Test(5), Delivery::ReliableOrdered).unwrap();
+    client_2.enqueue(ClientMsg::Test(6), Delivery::ReliableOrdered).unwrap();
+
+    server_end_1.enqueue(ServerMsg::Test(7), Delivery::ReliableOrdered).unwrap();
+    server_end_2.enqueue(ServerMsg::Test(8), Delivery::ReliableOrdered).unwrap();
+
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    let mut server_msg_buffer = vec![];
+
+    server_end_1.dequeue(&mut server_msg_buffer).unwrap();
+    assert_eq!(vec![ClientMsg::Test(1), ClientMsg::Test(3), ClientMsg::Test(5)], server_msg_buffer);
+
+    server_end_2.dequeue(&mut server_msg_buffer).unwrap();
+    assert_eq!(vec![ClientMsg::Test(2), ClientMsg::Test(4), ClientMsg::Test(6)], server_msg_buffer);
+
+    let mut client_msg_buffer = vec![];
+
+    client_1.dequeue(&mut client_msg_buffer).unwrap();
+    assert_eq!(vec![ServerMsg::Test(7)], client_msg_buffer);
+
+    client_2.dequeue(&mut client_msg_buffer).unwrap();
+    assert_eq!(vec![ServerMsg::Test(8)], client_msg_buffer);
+
+    server.get_new_clients(&mut new_clients);
+    assert_eq!(0, new_clients.len());
+}
+
+#[test]
+fn test_peer_limit() {
+    let mut server = UdpServer::new("127.0.0.1:0".parse().unwrap()).unwrap();
+    let server_addr = server.local_addr();
+    let connect = serde_cbor::to_vec(&Datagram::<()>::Connect).unwrap();
+    let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut is_accepted = |socket: &UdpSocket| {
+        socket.send_to(&connect, server_addr).unwrap();
+        match socket.recv_from(&mut buffer) {
+            Ok((length, _)) => matches!(serde_cbor::from_slice(&buffer[0..length]), Ok(Datagram::<()>::Accept)),
+            Err(_) => false
+        }
+    };
+
+    // peers that never ack anything, as if their addresses were forged
+    let sockets = (0..=MAX_UNPROVEN_PEERS).map(|_| {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        socket
+    }).collect::<Vec<_>>();
+    for socket in sockets[..MAX_UNPROVEN_PEERS].iter() {
+        assert!(is_accepted(socket));
+    }
+    assert!(!is_accepted(&sockets[MAX_UNPROVEN_PEERS]));
+
+    // once their connections are closed they are forgotten, even though they send nothing more
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+    assert_eq!(MAX_UNPROVEN_PEERS, new_clients.len());
+    drop(new_clients);
+    std::thread::sleep(PEER_SWEEP_PERIOD + Duration::from_millis(300));
+    assert!(is_accepted(&sockets[MAX_UNPROVEN_PEERS]));
+}
+
+#[test]
+fn test_inbox() {
+    let mut subject = Inbox::<u32>::new();
+    let mut buffer = vec![];
+
+    // reliable messages are held back until the gap before them is filled
+    subject.receive_reliable(1, 11, &mut buffer);
+    assert_eq!(Vec::<u32>::new(), buffer);
+    subject.receive_reliable(0, 10, &mut buffer);
+    assert_eq!(vec![10, 11], buffer);
+
+    // and are delivered only once
+    buffer.clear();
+    subject.receive_reliable(1, 11, &mut buffer);
+    assert_eq!(Vec::<u32>::new(), buffer);
+
+    // unreliable messages are dropped if something newer with the same key was delivered
+    subject.receive_unreliable(42, 2, 22, &mut buffer);
+    subject.receive_unreliable(42, 1, 21, &mut buffer);
+    subject.receive_unreliable(43, 1, 31, &mut buffer);
+    assert_eq!(vec![22, 31], buffer);
+}
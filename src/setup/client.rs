@@ -1,4 +1,6 @@
 use crate::network::real::RealNetwork;
+use crate::network::udp::UdpNetwork;
+use crate::setup::Transport;
 use ggez::event::KeyMods;
 use ggez::event::KeyCode;
 use crate::err::GgResult;
@@ -8,18 +10,31 @@ use ggez::event::EventHandler;
 use crate::engine::Engine;
 use ggez::GameResult;
 use std::net::TcpStream;
+use std::time::Duration;
 use crate::system::client::ClientSystem;
+use crate::network::{ClientMsg, ServerMsg, RxChannel, TxChannel};
 
 pub struct ClientSetup {
     engine: Engine<ggez::Context>
 }
 
 impl ClientSetup {
-    pub fn new(context: &mut ggez::Context, server_addr: &str) -> GgResult<ClientSetup> {
-        let tcp_stream = TcpStream::connect(server_addr)?;
-        let network = RealNetwork::new(tcp_stream)?;
+    pub fn new(context: &mut ggez::Context, server_addr: &str, transport: Transport, interpolation_delay: Duration, is_spectator: bool, room: Option<String>) -> GgResult<ClientSetup> {
+        let server_addr = server_addr.to_string();
+        let client_system = match transport {
+            Transport::Tcp => client_system(
+                move || RealNetwork::<ClientMsg, ServerMsg>::new(TcpStream::connect(&server_addr)?, crate::network::real::MAX_FRAME_LENGTH),
+                interpolation_delay,
+                is_spectator,
+                room)?,
+            Transport::Udp => client_system(
+                move || UdpNetwork::<ClientMsg, ServerMsg>::connect(&server_addr),
+                interpolation_delay,
+                is_spectator,
+                room)?
+        };
         let systems: Vec<Box<dyn System<ggez::Context>>> = vec![
-            Box::new(ClientSystem::new(network, crate::input::default_key_mapping())),
+            client_system,
             Box::new(crate::system::physics::PhysicsSystem{}),
             Box::new(crate::system::render::RenderSystem::new(context)?),
         ];
@@ -31,6 +46,19 @@ impl ClientSetup {
     }
 }
 
+// the client system for a connection made with the given function, which also makes a new one
+// should it be lost
+fn client_system<TNetwork, F>(mut connect: F, interpolation_delay: Duration, is_spectator: bool, room: Option<String>) -> GgResult<Box<dyn System<ggez::Context>>>
+    where TNetwork: 'static + TxChannel<ClientMsg> + RxChannel<ServerMsg>, F: 'static + FnMut() -> GgResult<TNetwork> {
+    let network = connect()?;
+    let mut client_system = ClientSystem::new(network, crate::input::default_key_mapping(), crate::network::DEFAULT_CONNECTION_TIMEOUT);
+    client_system.set_reconnect(Box::new(connect));
+    client_system.set_interpolation_delay(interpolation_delay);
+    client_system.set_is_spectator(is_spectator);
+    client_system.set_room(room);
+    Ok(Box::new(client_system))
+}
+
 impl EventHandler for ClientSetup {
     fn update(&mut self, context: &mut Context) -> GameResult {
         self.engine.update(context)?;
@@ -55,4 +83,10 @@ impl EventHandler for ClientSetup {
     fn key_up_event(&mut self, context: &mut Context, keycode: KeyCode, keymod: KeyMods) {
         self.engine.key_up_event(context, keycode, keymod);
     }
+
+    fn quit_event(&mut self, context: &mut Context) -> bool {
+        // the connection may already be gone, in which case there is no one to say goodbye to
+        let _ = self.engine.shutdown(context, "window closed");
+        false
+    }
 }
\ No newline at end of file
@@ -10,34 +10,39 @@ use ggez::GameResult;
 use std::rc::Rc;
 use std::cell::Cell;
 use std::time::Duration;
-use crate::network::sim::SimServer;
+use crate::network::sim::{SimServer, SimLink, LinkConditions};
 
 pub struct LocalClientServerSetup{
     client_1_engine: Option<Engine<ggez::Context>>,
     client_2_engine: Option<Engine<ggez::Context>>,
     server_engine: Option<Engine<ggez::Context>>,
     network_time: Rc::<Cell::<Duration>>,
+    client_links: Vec<SimLink>,
 }
 
 impl LocalClientServerSetup {
-    pub fn new(context: &mut ggez::Context, network_latency: Duration, is_latency_compensation_enabled: bool) -> GgResult<LocalClientServerSetup>{
+    pub fn new(context: &mut ggez::Context, client_1_link: LinkConditions, client_2_link: LinkConditions, is_latency_compensation_enabled: bool) -> GgResult<LocalClientServerSetup>{
 
         let mut result = LocalClientServerSetup{
             client_1_engine: None,
             client_2_engine: None,
             server_engine: None,
-            network_time: Rc::new(Cell::new(Duration::from_millis(0)))
+            network_time: Rc::new(Cell::new(Duration::from_millis(0))),
+            client_links: vec![]
         };
 
-        let mut server = crate::network::sim::SimServer::new(network_latency, Rc::clone(&result.network_time));
+        let mut server = crate::network::sim::SimServer::with_time(Rc::clone(&result.network_time));
 
-        result.client_1_engine = Some(LocalClientServerSetup::build_client(crate::input::p1_key_mapping(), &mut server, context)?);
-        result.client_2_engine = Some(LocalClientServerSetup::build_client(crate::input::p2_key_mapping(), &mut server, context)?);
+        let (client_1_engine, client_1_link) = LocalClientServerSetup::build_client(crate::input::p1_key_mapping(), client_1_link, &mut server, context)?;
+        let (client_2_engine, client_2_link) = LocalClientServerSetup::build_client(crate::input::p2_key_mapping(), client_2_link, &mut server, context)?;
+        result.client_1_engine = Some(client_1_engine);
+        result.client_2_engine = Some(client_2_engine);
+        result.client_links = vec![client_1_link, client_2_link];
 
         let server_systems: Vec<Box<dyn System<ggez::Context>>> = vec![
-            Box::new(crate::system::server::ServerSystem::new(server, is_latency_compensation_enabled)?),
+            Box::new(crate::system::server::ServerSystem::new(server, is_latency_compensation_enabled, crate::network::DEFAULT_CONNECTION_TIMEOUT)?),
             Box::new(crate::system::physics::PhysicsSystem{}),
-            Box::new(crate::system::gorilla::GorillaSystem{is_latency_compensation_enabled}),
+            Box::new(crate::system::gorilla::GorillaSystem{}),
             Box::new(crate::system::game::tag::TagGameSystem::new())
         ];
         result.server_engine = Some(Engine::new(server_systems, None, context)?);
@@ -45,16 +50,23 @@ impl LocalClientServerSetup {
         Ok(result)
     }
 
-    fn build_client(key_mapping: KeyMapping, server: &mut SimServer, context: &mut ggez::Context) -> GgResult::<Engine::<ggez::Context>> {
-        let client = server.connect();
+    fn build_client(key_mapping: KeyMapping, link_conditions: LinkConditions, server: &mut SimServer, context: &mut ggez::Context) -> GgResult::<(Engine::<ggez::Context>, SimLink)> {
+        let (client, link) = server.connect_with(link_conditions);
         let client_systems: Vec<Box<dyn System<ggez::Context>>> = vec![
-            Box::new(crate::system::client::ClientSystem::new(client, key_mapping)),
+            Box::new(crate::system::client::ClientSystem::new(client, key_mapping, crate::network::DEFAULT_CONNECTION_TIMEOUT)),
             Box::new(crate::system::physics::PhysicsSystem{}),
             Box::new(crate::system::render::RenderSystem::new(context)?),
         ];
         let client_engine = Engine::new(client_systems, None, context)?;
 
-        Ok(client_engine)
+        Ok((client_engine, link))
+    }
+
+    // to see how the same player copes on the other player's connection, without restarting
+    fn swap_client_links(&mut self) {
+        let client_1_conditions = self.client_links[0].get();
+        self.client_links[0].set(self.client_links[1].get());
+        self.client_links[1].set(client_1_conditions);
     }
 }
 
@@ -82,6 +94,11 @@ impl EventHandler for LocalClientServerSetup {
         keymod: KeyMods,
         repeat: bool,
     ) {
+        if keycode == KeyCode::F1 && !repeat {
+            self.swap_client_links();
+            return;
+        }
+
         self.client_1_engine.as_mut().unwrap().key_down_event(context, keycode, keymod, repeat);
         self.client_2_engine.as_mut().unwrap().key_down_event(context, keycode, keymod, repeat);
     }
@@ -7,7 +7,10 @@ use crate::setup::client::ClientSetup;
 use crate::setup::server::ServerSetup;
 use crate::setup::local::LocalSetup;
 use crate::setup::local_client_server::LocalClientServerSetup;
-use crate::err::GgResult;
+use crate::err::{GgError, GgResult};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::fmt;
 use std::time::Duration;
 use std::env;
 use std::path;
@@ -16,21 +19,56 @@ use ggez::event::EventsLoop;
 use ggez::Context;
 use ggez::event::EventHandler;
 
+pub use crate::system::client::DEFAULT_INTERPOLATION_DELAY;
+pub use crate::network::sim::{NetworkConditions, LinkConditions};
+pub use crate::system::server::PlayerLimit;
+pub use crate::network::discovery::{discover, FoundServer, ServerInfo, RoomInfo, DISCOVERY_PORT, DISCOVERY_WAIT};
+
 pub struct Setup<TSetup> where TSetup: EventHandler {
     context: ggez::Context,
     event_loop: ggez::event::EventsLoop,
     game: TSetup,
 }
 
-pub fn new_server() -> GgResult<ServerSetup>{
-    let setup = ServerSetup::new(Default::default())?;
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum Transport {
+    Tcp,
+    Udp
+}
+
+impl FromStr for Transport {
+    type Err = GgError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(Transport::Tcp),
+            "udp" => Ok(Transport::Udp),
+            _ => Err(GgError::Other(format!("unknown transport '{}', expected tcp or udp", s)))
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Tcp => write!(f, "tcp"),
+            Transport::Udp => write!(f, "udp")
+        }
+    }
+}
+
+pub fn new_server(addr: SocketAddr, transport: Transport, player_limit: Option<PlayerLimit>) -> GgResult<ServerSetup>{
+    let setup = ServerSetup::new(Default::default(), addr, transport, player_limit)?;
     Ok(setup)
 }
 
-pub fn new_client(server_addr: &str) -> GgResult<Setup<ClientSetup>>{
+pub fn new_client(server_addr: &str, transport: Transport, interpolation_delay: Duration, is_spectator: bool, room: Option<String>) -> GgResult<Setup<ClientSetup>>{
     let (mut context, event_loop) = build_context()?;
 
-    let game = ClientSetup::new(&mut context, server_addr)?;
+    let game = ClientSetup::new(&mut context, server_addr, transport, interpolation_delay, is_spectator, room)?;
 
     Ok(Setup{
         context,
@@ -51,10 +89,10 @@ pub fn new_local() -> GgResult<Setup<LocalSetup>> {
     })
 }
 
-pub fn new_local_client_server(latency: Duration, is_latency_compensation_enabled: bool) -> GgResult<Setup<LocalClientServerSetup>> {
+pub fn new_local_client_server(client_1_link: LinkConditions, client_2_link: LinkConditions, is_latency_compensation_enabled: bool) -> GgResult<Setup<LocalClientServerSetup>> {
     let (mut context, event_loop) = build_context()?;
 
-    let game = LocalClientServerSetup::new(&mut context, latency, is_latency_compensation_enabled)?;
+    let game = LocalClientServerSetup::new(&mut context, client_1_link, client_2_link, is_latency_compensation_enabled)?;
 
     Ok(Setup{
         context,
@@ -1,32 +1,276 @@
 use crate::context::server::ServerContext;
+use crate::context::TimerService;
 use crate::system::System;
 use crate::err::GgResult;
 use crate::engine::Engine;
+use crate::setup::Transport;
+use crate::system::server::{ServerSystem, PlayerLimit};
+use crate::network::{Server, TxChannel, RxChannel, ServerMsg, ClientMsg, Delivery};
+use crate::network::lobby::{Lobby, Door, Greeted};
+use crate::network::discovery::{DiscoveryResponder, ServerInfo, RoomInfo};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+#[cfg(test)]
+use crate::network::Hello;
+#[cfg(test)]
+use crate::component::gorilla::Gorilla;
+#[cfg(test)]
+use std::rc::Rc;
+#[cfg(test)]
+use std::cell::Cell;
+#[cfg(test)]
+use std::time::Duration;
+
+// Any client can open a room just by asking for one, so there is a limit to how many can be open
+const MAX_ROOMS: usize = 16;
 
 pub struct ServerSetup{
-    engine: Engine<ServerContext>,
-    context: ServerContext
+    rooms: Box<dyn RoomHost>,
+    context: ServerContext,
+    local_addr: SocketAddr,
+    transport: Transport,
+    // the name the server goes by, with what answers searches for it. None when it can't be found
+    discovery: Option<(String, DiscoveryResponder)>
 }
 
 impl ServerSetup{
-    pub fn new(mut context: ServerContext) -> GgResult<ServerSetup> {
-        let server = crate::network::real::RealServer::new()?;
-        let systems: Vec<Box<dyn System<ServerContext>>> = vec![
-            Box::new(crate::system::server::ServerSystem::new(server, true)?),
-            Box::new(crate::system::physics::PhysicsSystem{}),
-            Box::new(crate::system::gorilla::GorillaSystem{is_latency_compensation_enabled: true}),
-            Box::new(crate::system::game::tag::TagGameSystem::new()),
-        ];
-        let engine = Engine::new(systems, None, &mut context)?;
+    pub fn new(context: ServerContext, addr: SocketAddr, transport: Transport, player_limit: Option<PlayerLimit>) -> GgResult<ServerSetup> {
+        let (rooms, local_addr): (Box<dyn RoomHost>, SocketAddr) = match transport {
+            Transport::Tcp => {
+                let server = crate::network::real::RealServer::new(addr, crate::network::real::MAX_FRAME_LENGTH)?;
+                let local_addr = server.local_addr();
+                (Box::new(Rooms::new(server, player_limit)), local_addr)
+            },
+            Transport::Udp => {
+                let server = crate::network::udp::UdpServer::new(addr)?;
+                let local_addr = server.local_addr();
+                (Box::new(Rooms::new(server, player_limit)), local_addr)
+            }
+        };
         Ok(ServerSetup{
-            engine,
-            context
+            rooms,
+            context,
+            local_addr,
+            transport,
+            discovery: None
         })
     }
 
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // answers searches for servers arriving at the given address, which is usually a broadcast
+    // address on the DISCOVERY_PORT
+    pub fn enable_discovery(&mut self, name: &str, addr: SocketAddr) -> GgResult {
+        self.discovery = Some((name.to_string(), DiscoveryResponder::new(addr)?));
+        Ok(())
+    }
+
     pub fn step(&mut self) -> ggez::GameResult {
         self.context.step();
-        self.engine.update(&mut self.context)?;
+        self.rooms.step(&mut self.context)?;
+
+        if let Some((name, responder)) = self.discovery.as_mut() {
+            let (rooms, transport, port) = (&self.rooms, self.transport, self.local_addr.port());
+            let info = || ServerInfo::new(name, transport.to_string(), port, rooms.room_infos());
+            // finding servers is a convenience, and not worth stopping the game over
+            if let Err(e) = responder.respond(info) {
+                println!("warning: failed to answer a search for servers: {}", e);
+            }
+        }
+
         Ok(())
     }
-}
\ No newline at end of file
+
+    // tells every connected client why the server is going away
+    pub fn shutdown(&mut self, reason: &str) -> GgResult {
+        self.rooms.shutdown(&self.context, reason)
+    }
+}
+
+// what the setup needs of its rooms, whichever transport their clients came in on
+trait RoomHost {
+    fn step(&mut self, context: &mut ServerContext) -> GgResult;
+    fn shutdown(&mut self, context: &ServerContext, reason: &str) -> GgResult;
+    fn room_infos(&self) -> Vec<RoomInfo>;
+}
+
+// a world of its own, with its own players, anchors and game of tag
+struct Room<TNetwork> {
+    engine: Engine<ServerContext>,
+    door: Door<Greeted<TNetwork>>
+}
+
+// Sends each new client to the room it asked for, opening rooms as they are first asked for and
+// closing them once everyone has left
+struct Rooms<TServer, TNetwork> {
+    lobby: Lobby<TServer, TNetwork>,
+    rooms: BTreeMap<String, Room<TNetwork>>,
+    // applies to each room on its own
+    player_limit: Option<PlayerLimit>,
+    arrival_buffer: Vec<(String, Greeted<TNetwork>)>
+}
+
+impl<TServer, TNetwork> Rooms<TServer, TNetwork> where TServer: Server<TNetwork>, TNetwork: 'static + TxChannel<ServerMsg> + RxChannel<ClientMsg> {
+    fn new(server: TServer, player_limit: Option<PlayerLimit>) -> Rooms<TServer, TNetwork> {
+        Rooms{
+            lobby: Lobby::new(server),
+            rooms: BTreeMap::new(),
+            player_limit,
+            arrival_buffer: vec![]
+        }
+    }
+}
+
+fn open_room<TNetwork>(context: &mut ServerContext, player_limit: Option<PlayerLimit>) -> GgResult<Room<TNetwork>> where TNetwork: 'static + TxChannel<ServerMsg> + RxChannel<ClientMsg> {
+    let door = Door::default();
+    let mut server_system = ServerSystem::new(door.clone(), true, crate::network::DEFAULT_CONNECTION_TIMEOUT)?;
+    if let Some(player_limit) = player_limit {
+        server_system.set_player_limit(player_limit);
+    }
+    let systems: Vec<Box<dyn System<ServerContext>>> = vec![
+        Box::new(server_system),
+        Box::new(crate::system::physics::PhysicsSystem{}),
+        Box::new(crate::system::gorilla::GorillaSystem{}),
+        Box::new(crate::system::game::tag::TagGameSystem::new()),
+    ];
+    let engine = Engine::new(systems, None, context)?;
+    Ok(Room{
+        engine,
+        door
+    })
+}
+
+impl<TServer, TNetwork> RoomHost for Rooms<TServer, TNetwork> where TServer: Server<TNetwork>, TNetwork: 'static + TxChannel<ServerMsg> + RxChannel<ClientMsg> {
+    fn step(&mut self, context: &mut ServerContext) -> GgResult {
+        self.lobby.get_arrivals(context.time_since_start(), &mut self.arrival_buffer);
+        for (name, mut client) in self.arrival_buffer.drain(..) {
+            if !self.rooms.contains_key(&name) {
+                if self.rooms.len() >= MAX_ROOMS {
+                    let reason = format!("the server already has {} rooms open, join one of those instead", MAX_ROOMS);
+                    println!("rejected a client: {}", reason);
+                    let _ = client.enqueue(ServerMsg::Reject(reason), Delivery::ReliableOrdered);
+                    continue;
+                }
+                match open_room(context, self.player_limit) {
+                    Ok(room) => {
+                        println!("opened room '{}'", name);
+                        self.rooms.insert(name.clone(), room);
+                    },
+                    Err(e) => {
+                        let reason = format!("room '{}' could not be opened", name);
+                        println!("rejected a client: {}: {}", reason, e);
+                        let _ = client.enqueue(ServerMsg::Reject(reason), Delivery::ReliableOrdered);
+                        continue;
+                    }
+                }
+            }
+            println!("a client is joining room '{}'", name);
+            self.rooms[&name].door.admit(client);
+        }
+
+        // a room that fails is closed on its own and the others carry on. the world goes with the
+        // last of its clients
+        self.rooms.retain(|name, room| {
+            if let Err(e) = room.engine.update(context) {
+                println!("closed room '{}' as it failed: {}", name, e);
+                let _ = room.engine.shutdown(context, "the room has crashed");
+                return false;
+            }
+
+            let is_empty = room.door.is_empty();
+            if is_empty {
+                println!("closed room '{}' as everyone has left", name);
+            }
+            !is_empty
+        });
+
+        Ok(())
+    }
+
+    fn shutdown(&mut self, context: &ServerContext, reason: &str) -> GgResult {
+        self.lobby.shutdown(reason);
+        for room in self.rooms.values_mut() {
+            room.engine.shutdown(context, reason)?;
+        }
+
+        Ok(())
+    }
+
+    fn room_infos(&self) -> Vec<RoomInfo> {
+        self.rooms.iter()
+            .map(|(name, room)| RoomInfo{ name: name.clone(), player_count: room.door.client_count() })
+            .collect()
+    }
+}
+
+#[test]
+fn test_rooms() {
+    let time = Rc::new(Cell::new(Duration::from_millis(0u64)));
+    let mut server = crate::network::sim::SimServer::new(Duration::from_millis(0), Rc::clone(&time));
+    let mut clients = [server.connect(), server.connect(), server.connect()];
+    let mut late_client = server.connect();
+    let mut subject = Rooms::new(server, None);
+    let mut context = ServerContext::default();
+
+    let jungle_hello = Hello{ room: Some("jungle".to_string()), ..Hello::new(None) };
+    clients[0].enqueue(ClientMsg::Hello(Hello::new(None)), Delivery::ReliableOrdered).unwrap();
+    clients[1].enqueue(ClientMsg::Hello(jungle_hello.clone()), Delivery::ReliableOrdered).unwrap();
+    clients[2].enqueue(ClientMsg::Hello(jungle_hello), Delivery::ReliableOrdered).unwrap();
+    subject.step(&mut context).unwrap();
+
+    // each room has its own world with only its own players in it
+    let gorilla_count = |room: &Room<_>| {
+        let mut gorillas = vec![];
+        room.engine.get_state().collect_with(&component_filter!(Gorilla), &mut gorillas);
+        gorillas.len()
+    };
+    assert_eq!(vec!["jungle", crate::network::lobby::DEFAULT_ROOM], subject.rooms.keys().map(|name| name.as_str()).collect::<Vec<_>>());
+    assert_eq!(1, gorilla_count(&subject.rooms[crate::network::lobby::DEFAULT_ROOM]));
+    assert_eq!(2, gorilla_count(&subject.rooms["jungle"]));
+
+    let mut msgs = vec![];
+    for client in clients.iter_mut() {
+        client.dequeue(&mut msgs).unwrap();
+        assert!(matches!(msgs[0], ServerMsg::Welcome(_)));
+    }
+
+    // a room stays open until the last of its players has left
+    clients[1].enqueue(ClientMsg::Goodbye, Delivery::ReliableOrdered).unwrap();
+    subject.step(&mut context).unwrap();
+    assert_eq!(1, gorilla_count(&subject.rooms["jungle"]));
+
+    clients[2].enqueue(ClientMsg::Goodbye, Delivery::ReliableOrdered).unwrap();
+    subject.step(&mut context).unwrap();
+    assert!(!subject.rooms.contains_key("jungle"));
+    assert_eq!(vec![RoomInfo{ name: crate::network::lobby::DEFAULT_ROOM.to_string(), player_count: 1 }], subject.room_infos());
+
+    // and is opened afresh for whoever asks for it next
+    late_client.enqueue(ClientMsg::Hello(Hello{ room: Some("jungle".to_string()), ..Hello::new(None) }), Delivery::ReliableOrdered).unwrap();
+    subject.step(&mut context).unwrap();
+    assert_eq!(1, gorilla_count(&subject.rooms["jungle"]));
+}
+
+#[test]
+fn test_max_rooms() {
+    let time = Rc::new(Cell::new(Duration::from_millis(0u64)));
+    let mut server = crate::network::sim::SimServer::new(Duration::from_millis(0), Rc::clone(&time));
+    let mut clients = (0..=MAX_ROOMS).map(|_| server.connect()).collect::<Vec<_>>();
+    let mut subject = Rooms::new(server, None);
+    let mut context = ServerContext::default();
+
+    for (i, client) in clients.iter_mut().enumerate() {
+        client.enqueue(ClientMsg::Hello(Hello{ room: Some(format!("room {}", i)), ..Hello::new(None) }), Delivery::ReliableOrdered).unwrap();
+    }
+    subject.step(&mut context).unwrap();
+
+    // once the limit is reached no more rooms are opened
+    assert_eq!(MAX_ROOMS, subject.rooms.len());
+    let mut msgs = vec![];
+    clients[MAX_ROOMS].dequeue(&mut msgs).unwrap();
+    match &msgs[..] {
+        [ServerMsg::Reject(reason)] => assert!(reason.contains("rooms open")),
+        msgs => panic!("expected a Reject but got {:?}", msgs)
+    }
+}
@@ -3,37 +3,240 @@ use recs::Ecs;
 use crate::network::RxChannel;
 use crate::network::TxChannel;
 use crate::component::Focus;
+use crate::component::Anchor;
+use crate::component::body::Body;
+use crate::component::sprite::Sprite;
+use crate::component::client::Snapshots;
+use nalgebra::Vector2;
+use crate::network::InputAck;
+use crate::network::codec::Codec;
+use crate::network::clock::{ClockSync, CLOCK_SYNC_PERIOD, CLOCK_PING_STREAM, CLOCK_PONG_STREAM};
+use crate::network::Pong;
+use crate::system::gorilla::apply_input;
 use crate::network::ServerMsg;
-use crate::err::GgResult;
+use crate::err::{GgError, GgResult};
 use recs::EntityId;
 use ggez::event::KeyMods;
 use ggez::event::KeyCode;
 use crate::system::System;
 use crate::network::ClientMsg;
+use crate::network::Delivery;
+use crate::network::Hello;
+use crate::network::KEEPALIVE_PERIOD;
+use crate::context::TimerService;
+use std::time::Duration;
 use std::collections::HashMap;
+use std::collections::VecDeque;
 use crate::input::{KeyMapping};
+use crate::input::Button;
 #[cfg(test)]
 use crate::network::Server;
 #[cfg(test)]
-use std::time::Duration;
-#[cfg(test)]
 use std::rc::Rc;
 #[cfg(test)]
 use std::cell::Cell;
 
+// how far behind the latest snapshot other entities are drawn, so that there is usually
+// a later snapshot to interpolate towards
+pub const DEFAULT_INTERPOLATION_DELAY: Duration = Duration::from_millis(300);
+
 pub struct ClientSystem<TNetwork> where TNetwork: TxChannel<ClientMsg> + RxChannel<ServerMsg>{
     server: TNetwork,
     network_entity_id_mapping: HashMap<u64, EntityId>,
-    key_mapping: KeyMapping
+    key_mapping: KeyMapping,
+    is_welcomed: bool,
+    // watching rather than playing, in which case the focus is just the player the camera follows
+    is_spectator: bool,
+    // None for the server's default room. a reconnection goes back to the same one
+    room: Option<String>,
+    // set while the server is full and we are waiting for a slot
+    queue_position: Option<u32>,
+    connection_timeout: Duration,
+    last_receive_time: Duration,
+    next_keepalive_time: Duration,
+    session_token: Option<u64>,
+    reconnect: Option<Box<dyn FnMut() -> GgResult<TNetwork>>>,
+    next_input_seq: u32,
+    // inputs applied to our gorilla locally that the server's state does not reflect yet
+    pending_inputs: VecDeque<InputEvent>,
+    interpolation_delay: Duration,
+    clock_sync: ClockSync,
+    next_ping_time: Duration,
+    entity_buffer: Vec<EntityId>
 }
 
 impl<TNetwork> ClientSystem<TNetwork> where TNetwork: TxChannel<ClientMsg> + RxChannel<ServerMsg>{
-    pub fn new(server: TNetwork, key_mapping: KeyMapping) -> ClientSystem<TNetwork> {
+    pub fn new(server: TNetwork, key_mapping: KeyMapping, connection_timeout: Duration) -> ClientSystem<TNetwork> {
         ClientSystem{
             server,
             network_entity_id_mapping: HashMap::<u64, EntityId>::new(),
-            key_mapping
+            key_mapping,
+            is_welcomed: false,
+            is_spectator: false,
+            room: None,
+            queue_position: None,
+            connection_timeout,
+            last_receive_time: Duration::from_millis(0u64),
+            next_keepalive_time: Duration::from_millis(0u64),
+            session_token: None,
+            reconnect: None,
+            next_input_seq: 1,
+            pending_inputs: VecDeque::new(),
+            interpolation_delay: DEFAULT_INTERPOLATION_DELAY,
+            clock_sync: ClockSync::new(),
+            next_ping_time: Duration::from_millis(0u64),
+            entity_buffer: vec![]
+        }
+    }
+
+    // called to open a new connection if the current one is lost
+    pub fn set_reconnect(&mut self, reconnect: Box<dyn FnMut() -> GgResult<TNetwork>>) {
+        self.reconnect = Some(reconnect);
+    }
+
+    pub fn set_is_spectator(&mut self, is_spectator: bool) {
+        self.is_spectator = is_spectator;
+    }
+
+    pub fn set_room(&mut self, room: Option<String>) {
+        self.room = room;
+    }
+
+    pub fn set_interpolation_delay(&mut self, interpolation_delay: Duration) {
+        self.interpolation_delay = interpolation_delay;
+    }
+
+    // our round trip to the server and the difference between its clock and ours
+    pub fn clock_sync(&self) -> &ClockSync {
+        &self.clock_sync
+    }
+
+    fn receive(&mut self, time: Duration, buffer: &mut Vec<ServerMsg>) -> GgResult {
+        self.server.dequeue(buffer)?;
+        if buffer.is_empty() {
+            let silence = time - self.last_receive_time;
+            if silence > self.connection_timeout {
+                return Err(GgError::ConnectionTimedOut(silence));
+            }
+        } else {
+            self.last_receive_time = time;
+        }
+
+        Ok(())
+    }
+
+    // swaps in a new connection and asks the server for our old gorilla back,
+    // which is only possible once the server has given us a session
+    fn try_reconnect(&mut self, state: &mut Ecs, time: Duration, error: GgError) -> GgResult {
+        let (session_token, reconnect) = match (self.session_token, self.reconnect.as_mut()) {
+            (Some(session_token), Some(reconnect)) => (session_token, reconnect),
+            _ => return Err(error)
+        };
+
+        println!("lost the connection to the server: {}, reconnecting", error);
+        self.server = reconnect()?;
+        self.server.enqueue(ClientMsg::Hello(self.hello(Some(session_token))), Delivery::ReliableOrdered)?;
+        self.server.flush()?;
+        self.is_welcomed = false;
+        self.last_receive_time = time;
+        self.pending_inputs.clear();
+
+        // the server sends the whole world again once we are welcomed back
+        for (_, client_id) in self.network_entity_id_mapping.drain() {
+            let _ = state.destroy_entity(client_id);
+        }
+
+        Ok(())
+    }
+
+    fn hello(&self, session_token: Option<u64>) -> Hello {
+        Hello{ is_spectator: self.is_spectator, room: self.room.clone(), ..Hello::new(session_token) }
+    }
+
+    fn cycle_focus(&mut self, forward: bool) -> GgResult {
+        self.server.enqueue(ClientMsg::CycleFocus{ forward }, Delivery::ReliableOrdered)?;
+        self.server.flush()
+    }
+
+    fn send_input(&mut self, state: &mut Ecs, time: Duration, input_event: InputEvent) -> GgResult {
+        let input_event = InputEvent{ seq: self.next_input_seq, time, ..input_event };
+        self.next_input_seq += 1;
+        // inputs are sent straight away rather than waiting for the end of the tick
+        self.server.enqueue(ClientMsg::Input(input_event.clone()), Delivery::ReliableOrdered)?;
+        self.server.flush()?;
+
+        // show the result straight away rather than waiting a round trip for the server
+        let mut focused_entities = vec![];
+        state.collect_with(&component_filter!(Focus, Body), &mut focused_entities);
+        if let Some(&focused_entity) = focused_entities.first() {
+            apply_input(state, focused_entity, &input_event, None)?;
+            self.pending_inputs.push_back(input_event);
+        }
+        Ok(())
+    }
+
+    // rewinds our gorilla to the server's state and replays the inputs that the server had not
+    // yet seen, each at the time it happened, to bring it back up to the present
+    fn reconcile(&mut self, state: &mut Ecs, entity: EntityId, body: Body, ack: InputAck, time: Duration) -> GgResult {
+        while matches!(self.pending_inputs.front(), Some(input_event) if input_event.seq <= ack.seq) {
+            self.pending_inputs.pop_front();
+        }
+
+        state.set(entity, body)?;
+        let mut body_time = ack.time;
+        for input_event in self.pending_inputs.iter() {
+            if input_event.time > body_time {
+                state.borrow_mut::<Body>(entity)?.step((input_event.time - body_time).as_secs_f32());
+                body_time = input_event.time;
+            }
+            apply_input(state, entity, input_event, None)?;
+        }
+
+        if time > body_time {
+            state.borrow_mut::<Body>(entity)?.step((time - body_time).as_secs_f32());
+        }
+
+        Ok(())
+    }
+
+    fn add_snapshot(&mut self, state: &mut Ecs, entity: EntityId, time: Duration, body: Body) -> GgResult {
+        if !state.has::<Snapshots>(entity)? {
+            state.set(entity, Snapshots(VecDeque::new()))?;
+        }
+        let snapshots = &mut state.borrow_mut::<Snapshots>(entity)?.0;
+        // the estimate of the server's clock moves as it is refined, which can put a snapshot
+        // before those already taken. the newer state wins so that they stay in order
+        while matches!(snapshots.back(), Some((snapshot_time, _)) if *snapshot_time >= time) {
+            snapshots.pop_back();
         }
+        snapshots.push_back((time, body.clone()));
+        state.set(entity, body)?;
+        Ok(())
+    }
+
+    // moves the sprites of other entities to where they were a short while ago
+    fn interpolate_snapshots(&mut self, state: &mut Ecs, time: Duration) -> GgResult {
+        let render_time = match time.checked_sub(self.interpolation_delay) {
+            Some(render_time) => render_time,
+            None => return Ok(())
+        };
+
+        self.entity_buffer.clear();
+        state.collect_with(&component_filter!(Snapshots, Sprite), &mut self.entity_buffer);
+        for &entity in self.entity_buffer.iter() {
+            let snapshots = &mut state.borrow_mut::<Snapshots>(entity)?.0;
+
+            // only the last snapshot before the render time is needed from now on
+            while snapshots.len() > 1 && snapshots[1].0 <= render_time {
+                snapshots.pop_front();
+            }
+
+            if let Some(loc) = interpolate(snapshots, render_time) {
+                state.borrow_mut::<Sprite>(entity)?.location = loc;
+            }
+        }
+
+        Ok(())
     }
 
     fn get_client_entity_id(&mut self, state: &mut Ecs, server_id: u64) -> EntityId {
@@ -48,68 +251,190 @@ impl<TNetwork> ClientSystem<TNetwork> where TNetwork: TxChannel<ClientMsg> + RxC
     }
 }
 
-impl<TNetwork, TContext> System<TContext> for ClientSystem<TNetwork> where TNetwork: TxChannel<ClientMsg> + RxChannel<ServerMsg>{
+impl<TNetwork, TContext> System<TContext> for ClientSystem<TNetwork> where TNetwork: TxChannel<ClientMsg> + RxChannel<ServerMsg>, TContext: TimerService{
+    fn init(&mut self, _: &mut Ecs, context: &TContext) -> GgResult {
+        self.last_receive_time = context.time_since_start();
+        self.server.enqueue(ClientMsg::Hello(self.hello(None)), Delivery::ReliableOrdered)
+    }
+
+    fn shutdown(&mut self, _: &mut Ecs, _: &TContext, _: &str) -> GgResult {
+        self.server.enqueue(ClientMsg::Goodbye, Delivery::ReliableOrdered)?;
+        // the game is about to exit, which would otherwise cut the goodbye off
+        self.server.close();
+        Ok(())
+    }
+
     fn key_down(
         &mut self,
-        _: &mut Ecs,
-        _: &mut TContext,
+        state: &mut Ecs,
+        context: &mut TContext,
         keycode: KeyCode,
         _: KeyMods,
         repeat: bool) {
-            if repeat { return; }
+            if repeat || !self.is_welcomed { return; }
+
+            // a spectator's buttons move the camera back and forth between the players
+            if self.is_spectator {
+                if let Some(&button) = self.key_mapping.get(&keycode) {
+                    // a lost connection is noticed and dealt with by the next update
+                    let _ = self.cycle_focus(button == Button::Two);
+                }
+                return;
+            }
 
             if let Some(&button) = self.key_mapping.get(&keycode) {
-                self.server.enqueue(ClientMsg::Input(InputEvent{button, is_down: true})).unwrap();
+                // a lost connection is noticed and dealt with by the next update
+                let _ = self.send_input(state, context.time_since_start(), InputEvent{button, is_down: true, seq: 0, time: Duration::from_millis(0)});
             }
     }
 
     fn key_up(
         &mut self,
-        _: &mut Ecs,
-        _: &mut TContext,
+        state: &mut Ecs,
+        context: &mut TContext,
         keycode: KeyCode,
         _: KeyMods) {   
+            if !self.is_welcomed || self.is_spectator { return; }
+
             if let Some(&button) = self.key_mapping.get(&keycode) {
-                self.server.enqueue(ClientMsg::Input(InputEvent{button, is_down: false})).unwrap();
+                let _ = self.send_input(state, context.time_since_start(), InputEvent{button, is_down: false, seq: 0, time: Duration::from_millis(0)});
             }
     }
 
     fn update(
         &mut self, 
         state: &mut Ecs, 
-        _: &TContext) -> GgResult {
+        context: &TContext) -> GgResult {
+
+        let time = context.time_since_start();
 
         // read all network entities
         let mut buffer = vec![];
-        self.server.dequeue(&mut buffer)?;
+        if let Err(error) = self.receive(time, &mut buffer) {
+            return self.try_reconnect(state, time, error);
+        }
+
         for msg in buffer.drain(..) {
             match msg {
-                ServerMsg::SetBody(server_id, body) => {
+                ServerMsg::Welcome(welcome) => {
+                    self.is_welcomed = true;
+                    self.queue_position = None;
+                    self.session_token = Some(welcome.session_token);
+                    self.server.set_codec(Codec::negotiate(&welcome.capabilities));
+                    self.server.enqueue(ClientMsg::SetInterpolationDelay(self.interpolation_delay), Delivery::ReliableOrdered)?;
+                },
+                ServerMsg::Queued(position) => {
+                    println!("the server is full, waiting for a slot at position {} in the queue", position);
+                    self.queue_position = Some(position);
+                },
+                ServerMsg::Reject(reason) => {
+                    return Err(GgError::Other(format!("the server rejected the connection: {}", reason)));
+                },
+                ServerMsg::Shutdown{ reason } => {
+                    return Err(GgError::Other(format!("the server shut down: {}", reason)));
+                },
+                ServerMsg::SetBody(server_id, body, server_time) => {
+                    let client_id = self.get_client_entity_id(state, server_id);
+                    if self.is_spectator || !state.has::<Focus>(client_id).unwrap() {
+                        // when it was taken by our clock, or failing that when it arrived
+                        let snapshot_time = self.clock_sync.local_time(server_time).unwrap_or(time);
+                        self.add_snapshot(state, client_id, snapshot_time, body)?;
+                    } else if self.pending_inputs.is_empty() {
+                        // until the server acknowledges an input its view of our gorilla is behind our own
+                        state.set(client_id, body).unwrap();
+                    }
+                },
+                ServerMsg::SetOwnBody(server_id, body, ack) => {
                     let client_id = self.get_client_entity_id(state, server_id);
-                    state.set(client_id, body).unwrap();
+                    self.reconcile(state, client_id, body, ack, time)?;
                 },
                 ServerMsg::SetSprite(server_id, sprite) => {
                     let client_id = self.get_client_entity_id(state, server_id);
                     state.set(client_id, sprite).unwrap();
                 },
+                ServerMsg::UpdateSprite(server_id, delta) => {
+                    let client_id = self.get_client_entity_id(state, server_id);
+                    if let Ok(sprite) = state.borrow_mut::<Sprite>(client_id) {
+                        sprite.apply(&delta);
+                    }
+                },
                 ServerMsg::SetFocus(server_id) => {
                     let client_id = self.get_client_entity_id(state, server_id);
+                    // a spectator's camera moves from one player to another
+                    self.entity_buffer.clear();
+                    state.collect_with(&component_filter!(Focus), &mut self.entity_buffer);
+                    for &focused_entity in self.entity_buffer.iter() {
+                        state.unset::<Focus>(focused_entity).unwrap();
+                    }
                     state.set(client_id, Focus).unwrap();
+                    // our own gorilla is predicted rather than played back
+                    if !self.is_spectator && state.has::<Snapshots>(client_id).unwrap() {
+                        state.unset::<Snapshots>(client_id).unwrap();
+                    }
+                },
+                ServerMsg::SetAnchor(server_id) => {
+                    let client_id = self.get_client_entity_id(state, server_id);
+                    state.set(client_id, Anchor).unwrap();
                 },
-                ServerMsg::Kill(server_id) => {
+                ServerMsg::Kill(server_id) | ServerMsg::Despawn(server_id) => {
                     let client_id = self.get_client_entity_id(state, server_id);
                     self.network_entity_id_mapping.remove(&server_id);
                     state.destroy_entity(client_id).unwrap();
                 },
-                ServerMsg::Ping(tx_time) => {
-                    self.server.enqueue(ClientMsg::Pong(tx_time))?;
-                }
+                ServerMsg::Ping(ping_time) => {
+                    let pong = ClientMsg::Pong(Pong{ ping_time, reply_time: time });
+                    self.server.enqueue(pong, Delivery::UnreliableSequenced(CLOCK_PONG_STREAM))?;
+                },
+                ServerMsg::Pong(pong) => {
+                    self.clock_sync.add_sample(&pong, time);
+                },
+                ServerMsg::Heartbeat => {}
                 #[cfg(test)]
                 ServerMsg::Test(_) => {}
             }
         }
 
-        Ok(())
+        self.interpolate_snapshots(state, time)?;
+
+        // the server ignores everything but the Hello until it has welcomed us, except for the
+        // heartbeats that keep our place in its queue
+        if (self.is_welcomed || self.queue_position.is_some()) && time >= self.next_keepalive_time {
+            self.server.enqueue(ClientMsg::Heartbeat, Delivery::ReliableOrdered)?;
+            self.next_keepalive_time = time + KEEPALIVE_PERIOD;
+        }
+
+        if self.is_welcomed && time >= self.next_ping_time {
+            self.server.enqueue(ClientMsg::Ping(time), Delivery::UnreliableSequenced(CLOCK_PING_STREAM))?;
+            self.next_ping_time = time + CLOCK_SYNC_PERIOD;
+        }
+
+        self.server.flush()
+    }
+}
+
+// where an entity was at the given time, going by the snapshots either side of it. each snapshot is
+// stepped to that time and the results are blended, so that curved paths stay curved
+fn interpolate(snapshots: &VecDeque<(Duration, Body)>, time: Duration) -> Option<Vector2<f32>> {
+    match snapshots.iter().position(|(snapshot_time, _)| *snapshot_time > time) {
+        // no later snapshot has arrived yet, so carry on from the latest one
+        None => snapshots.back().map(|(snapshot_time, body)| {
+            let mut body = body.clone();
+            body.step((time - *snapshot_time).as_secs_f32());
+            body.get_loc()
+        }),
+        Some(0) => snapshots.front().map(|(_, body)| body.get_loc()),
+        Some(i) => {
+            let (time_0, body_0) = &snapshots[i - 1];
+            let (time_1, body_1) = &snapshots[i];
+
+            let mut from_0 = body_0.clone();
+            from_0.step((time - *time_0).as_secs_f32());
+            let mut from_1 = body_1.clone();
+            from_1.step(-(*time_1 - time).as_secs_f32());
+
+            let alpha = (time - *time_0).as_secs_f32() / (*time_1 - *time_0).as_secs_f32();
+            Some(from_0.get_loc() + (from_1.get_loc() - from_0.get_loc()) * alpha)
+        }
     }
 }
 
@@ -121,20 +446,433 @@ fn test_ping_pong() {
     let network = server.connect();
     
     // create a new ClientSystem to test and connect it to the network
-    let mut subject = ClientSystem::new(network, crate::input::default_key_mapping());
+    let mut subject = ClientSystem::new(network, crate::input::default_key_mapping(), crate::network::DEFAULT_CONNECTION_TIMEOUT);
     
     // send the ClientSystem a Ping message
     let mut new_clients = vec![];
     server.get_new_clients(&mut new_clients);
-    new_clients[0].enqueue(ServerMsg::Ping(std::time::Duration::from_millis(42u64))).unwrap();
+    new_clients[0].enqueue(ServerMsg::Ping(std::time::Duration::from_millis(42u64)), Delivery::UnreliableSequenced(CLOCK_PING_STREAM)).unwrap();
 
     // Step the ClientSystem so that it can process the Ping message 
+    let mut context = crate::testing::MockContext::new(Duration::from_millis(16));
+    context.time_since_start = Duration::from_millis(100);
     let mut state = Ecs::new();
-    subject.update(&mut state, &0).unwrap();
+    subject.update(&mut state, &context).unwrap();
 
     // Check that it correctly responded with a Pong message
     let mut client_msgs = vec![];
     new_clients[0].dequeue(&mut client_msgs).unwrap();
     assert_eq!(1, client_msgs.len());
-    assert_eq!(ClientMsg::Pong(std::time::Duration::from_millis(42u64)), client_msgs[0]);
-}
\ No newline at end of file
+    let pong = Pong{ ping_time: Duration::from_millis(42u64), reply_time: Duration::from_millis(100) };
+    assert_eq!(ClientMsg::Pong(pong), client_msgs[0]);
+}
+
+#[test]
+fn test_ping_and_pong_together() {
+    let mut server = crate::network::real::RealServer::new("127.0.0.1:0".parse().unwrap(), crate::network::real::MAX_FRAME_LENGTH).unwrap();
+    let stream = std::net::TcpStream::connect(server.local_addr()).unwrap();
+    let network = crate::network::real::RealNetwork::new(stream, crate::network::real::MAX_FRAME_LENGTH).unwrap();
+    let mut subject = ClientSystem::new(network, crate::input::default_key_mapping(), crate::network::DEFAULT_CONNECTION_TIMEOUT);
+    std::thread::sleep(Duration::from_millis(50));
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+
+    // the server's ping arrives just as the client is due to send its own
+    new_clients[0].enqueue(ServerMsg::Welcome(crate::network::Welcome{ capabilities: vec![], session_token: 1 }), Delivery::ReliableOrdered).unwrap();
+    new_clients[0].enqueue(ServerMsg::Ping(Duration::from_millis(42)), Delivery::UnreliableSequenced(CLOCK_PING_STREAM)).unwrap();
+    new_clients[0].flush().unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    let mut context = crate::testing::MockContext::new(Duration::from_millis(16));
+    context.time_since_start = Duration::from_millis(100);
+    let mut state = Ecs::new();
+    subject.update(&mut state, &context).unwrap();
+
+    // and neither the ping nor the pong that go out together crowds out the other
+    std::thread::sleep(Duration::from_millis(50));
+    let mut client_msgs = vec![];
+    new_clients[0].dequeue(&mut client_msgs).unwrap();
+    assert!(client_msgs.contains(&ClientMsg::Pong(Pong{ ping_time: Duration::from_millis(42), reply_time: Duration::from_millis(100) })));
+    assert!(client_msgs.contains(&ClientMsg::Ping(Duration::from_millis(100))));
+}
+
+#[test]
+fn test_clock_sync() {
+    let time = Rc::new(Cell::new(Duration::from_millis(0u64)));
+    let mut server = crate::network::sim::SimServer::new(Duration::from_millis(50), Rc::clone(&time));
+    let network = server.connect();
+    let mut subject = ClientSystem::new(network, crate::input::default_key_mapping(), crate::network::DEFAULT_CONNECTION_TIMEOUT);
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+    new_clients[0].enqueue(ServerMsg::Welcome(crate::network::Welcome{ capabilities: vec![], session_token: 1 }), Delivery::ReliableOrdered).unwrap();
+
+    // the server's clock started a minute before ours, and it replies to pings straight away
+    let server_clock_offset = Duration::from_secs(60);
+    let mut context = crate::testing::MockContext::new(Duration::from_millis(10));
+    let mut state = Ecs::new();
+    let mut client_msgs = vec![];
+    for _ in 0..500 {
+        subject.update(&mut state, &context).unwrap();
+        new_clients[0].dequeue(&mut client_msgs).unwrap();
+        for msg in client_msgs.drain(..) {
+            if let ClientMsg::Ping(ping_time) = msg {
+                let pong = Pong{ ping_time, reply_time: context.time_since_start + server_clock_offset };
+                new_clients[0].enqueue(ServerMsg::Pong(pong), Delivery::UnreliableSequenced(CLOCK_PONG_STREAM)).unwrap();
+            }
+        }
+        context.step();
+        time.set(context.time_since_start);
+    }
+
+    // the round trip is 100ms, give or take a tick, and the server's clock is known to within that
+    let rtt = subject.clock_sync().rtt().unwrap();
+    assert!(rtt >= Duration::from_millis(100) && rtt <= Duration::from_millis(120), "{:?}", rtt);
+    let server_time = subject.clock_sync().remote_time(context.time_since_start).unwrap();
+    crate::testing::assert_roughly_eq("server time",
+        (context.time_since_start + server_clock_offset).as_secs_f32(),
+        server_time.as_secs_f32());
+}
+
+#[test]
+fn test_rejection() {
+    let time = Rc::new(Cell::new(Duration::from_millis(0u64)));
+    let mut server = crate::network::sim::SimServer::new(Duration::from_millis(0), Rc::clone(&time));
+    let network = server.connect();
+    let mut subject = ClientSystem::new(network, crate::input::default_key_mapping(), crate::network::DEFAULT_CONNECTION_TIMEOUT);
+
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+    new_clients[0].enqueue(ServerMsg::Reject("go away".to_string()), Delivery::ReliableOrdered).unwrap();
+
+    let context = crate::testing::MockContext::new(Duration::from_millis(16));
+    let mut state = Ecs::new();
+    let result = subject.update(&mut state, &context);
+
+    assert_eq!("the server rejected the connection: go away", result.unwrap_err().to_string());
+}
+
+#[test]
+fn test_goodbye_and_shutdown() {
+    let time = Rc::new(Cell::new(Duration::from_millis(0u64)));
+    let mut server = crate::network::sim::SimServer::new(Duration::from_millis(0), Rc::clone(&time));
+    let network = server.connect();
+    let mut subject = ClientSystem::new(network, crate::input::default_key_mapping(), crate::network::DEFAULT_CONNECTION_TIMEOUT);
+
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+
+    let context = crate::testing::MockContext::new(Duration::from_millis(16));
+    let mut state = Ecs::new();
+    subject.shutdown(&mut state, &context, "window closed").unwrap();
+
+    let mut client_msgs = vec![];
+    new_clients[0].dequeue(&mut client_msgs).unwrap();
+    assert_eq!(vec![ClientMsg::Goodbye], client_msgs);
+
+    new_clients[0].enqueue(ServerMsg::Shutdown{ reason: "maintenance".to_string() }, Delivery::ReliableOrdered).unwrap();
+    let result = subject.update(&mut state, &context);
+
+    assert_eq!("the server shut down: maintenance", result.unwrap_err().to_string());
+}
+
+#[test]
+fn test_connection_timeout() {
+    let time = Rc::new(Cell::new(Duration::from_millis(0u64)));
+    let mut server = crate::network::sim::SimServer::new(Duration::from_millis(0), Rc::clone(&time));
+    let network = server.connect();
+    let mut subject = ClientSystem::new(network, crate::input::default_key_mapping(), Duration::from_secs(3));
+
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+    new_clients[0].enqueue(ServerMsg::Welcome(crate::network::Welcome{ capabilities: vec![], session_token: 1 }), Delivery::ReliableOrdered).unwrap();
+
+    let mut context = crate::testing::MockContext::new(Duration::from_millis(500));
+    let mut state = Ecs::new();
+    for _ in 0..6 {
+        subject.update(&mut state, &context).unwrap();
+        context.step();
+        time.set(context.time_since_start());
+    }
+
+    // once welcomed the client keeps the connection alive even though the player is idle
+    let mut client_msgs = vec![];
+    new_clients[0].dequeue(&mut client_msgs).unwrap();
+    client_msgs.retain(|msg| !matches!(msg, ClientMsg::Ping(_)));
+    let expected = vec![ClientMsg::SetInterpolationDelay(DEFAULT_INTERPOLATION_DELAY), ClientMsg::Heartbeat, ClientMsg::Heartbeat, ClientMsg::Heartbeat];
+    assert_eq!(expected, client_msgs);
+
+    context.step();
+    time.set(context.time_since_start());
+    match subject.update(&mut state, &context) {
+        Err(GgError::ConnectionTimedOut(_)) => {},
+        result => panic!("expected a timeout but got {:?}", result)
+    }
+}
+
+#[test]
+fn test_reconnect() {
+    let time = Rc::new(Cell::new(Duration::from_millis(0u64)));
+    let server = Rc::new(std::cell::RefCell::new(crate::network::sim::SimServer::new(Duration::from_millis(0), Rc::clone(&time))));
+    let network = server.borrow_mut().connect();
+    let mut subject = ClientSystem::new(network, crate::input::default_key_mapping(), Duration::from_secs(3));
+    let reconnect_server = Rc::clone(&server);
+    subject.set_reconnect(Box::new(move || Ok(reconnect_server.borrow_mut().connect())));
+
+    let mut new_clients = vec![];
+    server.borrow_mut().get_new_clients(&mut new_clients);
+    new_clients[0].enqueue(ServerMsg::Welcome(crate::network::Welcome{ capabilities: vec![], session_token: 42 }), Delivery::ReliableOrdered).unwrap();
+    new_clients[0].enqueue(ServerMsg::SetFocus(7), Delivery::ReliableOrdered).unwrap();
+
+    let mut context = crate::testing::MockContext::new(Duration::from_millis(500));
+    let mut state = Ecs::new();
+    subject.update(&mut state, &context).unwrap();
+
+    // the server goes quiet for longer than the timeout
+    for _ in 0..7 {
+        context.step();
+        time.set(context.time_since_start());
+        subject.update(&mut state, &context).unwrap();
+    }
+
+    // so the client opens a new connection and presents its session token
+    server.borrow_mut().get_new_clients(&mut new_clients);
+    assert_eq!(1, new_clients.len());
+
+    let mut client_msgs = vec![];
+    new_clients[0].dequeue(&mut client_msgs).unwrap();
+    assert_eq!(vec![ClientMsg::Hello(Hello::new(Some(42)))], client_msgs);
+
+    let mut entities = vec![];
+    state.collect_with(&component_filter!(Focus), &mut entities);
+    assert!(entities.is_empty());
+}
+
+#[test]
+fn test_prediction() {
+    let time = Rc::new(Cell::new(Duration::from_millis(0u64)));
+    let mut server = crate::network::sim::SimServer::new(Duration::from_millis(0), Rc::clone(&time));
+    let network = server.connect();
+    let mut subject = ClientSystem::new(network, crate::input::default_key_mapping(), crate::network::DEFAULT_CONNECTION_TIMEOUT);
+
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+    let detached = Body::new_dynamic([0.0, -1.0].into(), nalgebra::Vector2::zeros(), [0.0, -10.0].into());
+    let attached = detached.to_attached([0.0, 0.0].into());
+    new_clients[0].enqueue(ServerMsg::Welcome(crate::network::Welcome{ capabilities: vec![], session_token: 1 }), Delivery::ReliableOrdered).unwrap();
+    new_clients[0].enqueue(ServerMsg::SetFocus(1), Delivery::ReliableOrdered).unwrap();
+    new_clients[0].enqueue(ServerMsg::SetBody(1, detached.clone(), time.get()), Delivery::UnreliableSequenced(1)).unwrap();
+    new_clients[0].enqueue(ServerMsg::SetAnchor(2), Delivery::ReliableOrdered).unwrap();
+    new_clients[0].enqueue(ServerMsg::SetBody(2, Body::new_static([0.0, 0.0].into()), time.get()), Delivery::UnreliableSequenced(2)).unwrap();
+
+    let mut context = crate::testing::MockContext::new(Duration::from_millis(16));
+    let mut state = Ecs::new();
+    subject.update(&mut state, &context).unwrap();
+
+    let mut focused_entities = vec![];
+    state.collect_with(&component_filter!(Focus), &mut focused_entities);
+    let gorilla = focused_entities[0];
+
+    // the rope is thrown locally as soon as the key goes down
+    subject.key_down(&mut state, &mut context, KeyCode::Space, KeyMods::empty(), false);
+    assert!(state.borrow::<Body>(gorilla).unwrap().get_is_attached());
+
+    let mut client_msgs = vec![];
+    new_clients[0].dequeue(&mut client_msgs).unwrap();
+    assert!(client_msgs.contains(&ClientMsg::Input(InputEvent{ button: Button::One, is_down: true, seq: 1, time: Duration::from_millis(0) })));
+
+    // state the server sent before it saw the input does not undo the prediction
+    new_clients[0].enqueue(ServerMsg::SetBody(1, detached.clone(), time.get()), Delivery::UnreliableSequenced(1)).unwrap();
+    subject.update(&mut state, &context).unwrap();
+    assert!(state.borrow::<Body>(gorilla).unwrap().get_is_attached());
+
+    // the server's state is taken as soon as it acknowledges the input
+    new_clients[0].enqueue(ServerMsg::SetOwnBody(1, attached.clone(), InputAck{ seq: 1, time: Duration::from_millis(0) }), Delivery::UnreliableSequenced(1)).unwrap();
+    subject.update(&mut state, &context).unwrap();
+    assert_eq!(&attached, state.borrow::<Body>(gorilla).unwrap());
+
+    // but inputs it has not seen yet are replayed on top
+    subject.key_up(&mut state, &mut context, KeyCode::Space, KeyMods::empty());
+    new_clients[0].enqueue(ServerMsg::SetOwnBody(1, attached.clone(), InputAck{ seq: 1, time: Duration::from_millis(0) }), Delivery::UnreliableSequenced(1)).unwrap();
+    subject.update(&mut state, &context).unwrap();
+    assert!(!state.borrow::<Body>(gorilla).unwrap().get_is_attached());
+}
+
+#[test]
+fn test_reconciliation() {
+    let time = Rc::new(Cell::new(Duration::from_millis(0u64)));
+    let mut server = crate::network::sim::SimServer::new(Duration::from_millis(0), Rc::clone(&time));
+    let network = server.connect();
+    let mut subject = ClientSystem::new(network, crate::input::default_key_mapping(), crate::network::DEFAULT_CONNECTION_TIMEOUT);
+
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+    let body = Body::new_dynamic([0.0, 0.0].into(), [1.0, 0.0].into(), [0.0, -10.0].into());
+    new_clients[0].enqueue(ServerMsg::Welcome(crate::network::Welcome{ capabilities: vec![], session_token: 1 }), Delivery::ReliableOrdered).unwrap();
+    new_clients[0].enqueue(ServerMsg::SetFocus(1), Delivery::ReliableOrdered).unwrap();
+    new_clients[0].enqueue(ServerMsg::SetBody(1, body.clone(), time.get()), Delivery::UnreliableSequenced(1)).unwrap();
+
+    let mut context = crate::testing::MockContext::new(Duration::from_millis(100));
+    let mut state = Ecs::new();
+    subject.update(&mut state, &context).unwrap();
+
+    let mut focused_entities = vec![];
+    state.collect_with(&component_filter!(Focus), &mut focused_entities);
+    let gorilla = focused_entities[0];
+
+    context.step();
+    subject.key_down(&mut state, &mut context, KeyCode::Return, KeyMods::empty(), false);
+    context.step();
+    subject.key_up(&mut state, &mut context, KeyCode::Return, KeyMods::empty());
+    context.step();
+
+    // the server had applied the key press by 150ms but not yet the release at 200ms
+    let mut acked_body = body.clone();
+    acked_body.set_acc([0.0, -20.0].into());
+    acked_body.step(0.05);
+    new_clients[0].enqueue(ServerMsg::SetOwnBody(1, acked_body.clone(), InputAck{ seq: 1, time: Duration::from_millis(150) }), Delivery::UnreliableSequenced(2)).unwrap();
+    subject.update(&mut state, &context).unwrap();
+
+    let mut expected = acked_body;
+    expected.step(0.05);
+    expected.set_acc([0.0, -10.0].into());
+    expected.step(0.1);
+    assert_eq!(&expected, state.borrow::<Body>(gorilla).unwrap());
+}
+
+#[test]
+fn test_interpolate() {
+    let snapshots: VecDeque<_> = vec![
+        (Duration::from_millis(100), Body::new_dynamic([0.0, 0.0].into(), [1.0, 0.0].into(), Vector2::zeros())),
+        // the second snapshot disagrees with where the first was heading
+        (Duration::from_millis(200), Body::new_dynamic([0.2, 0.0].into(), [1.0, 0.0].into(), Vector2::zeros()))
+    ].into_iter().collect();
+
+    assert_eq!(Some([0.0, 0.0].into()), interpolate(&snapshots, Duration::from_millis(50)));
+    assert_eq!(Some([0.0, 0.0].into()), interpolate(&snapshots, Duration::from_millis(100)));
+    crate::testing::assert_roughly_eq("x", 0.1, interpolate(&snapshots, Duration::from_millis(150)).unwrap().x);
+    crate::testing::assert_roughly_eq("x", 0.2, interpolate(&snapshots, Duration::from_millis(200)).unwrap().x);
+    crate::testing::assert_roughly_eq("x", 0.3, interpolate(&snapshots, Duration::from_millis(300)).unwrap().x);
+    assert_eq!(None, interpolate(&VecDeque::new(), Duration::from_millis(300)));
+}
+
+#[test]
+fn test_snapshot_interpolation() {
+    let time = Rc::new(Cell::new(Duration::from_millis(0u64)));
+    let mut server = crate::network::sim::SimServer::new(Duration::from_millis(0), Rc::clone(&time));
+    let network = server.connect();
+    let mut subject = ClientSystem::new(network, crate::input::default_key_mapping(), crate::network::DEFAULT_CONNECTION_TIMEOUT);
+    subject.set_interpolation_delay(Duration::from_millis(250));
+
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+    let sprite = Sprite::new(crate::colors::WHITE, [1.0, 1.0].into(), Vector2::zeros(), [1.0, 1.0].into());
+    new_clients[0].enqueue(ServerMsg::Welcome(crate::network::Welcome{ capabilities: vec![], session_token: 1 }), Delivery::ReliableOrdered).unwrap();
+    new_clients[0].enqueue(ServerMsg::SetSprite(1, sprite), Delivery::ReliableOrdered).unwrap();
+
+    // another gorilla moving steadily to the right, with a keyframe every 250ms
+    let mut context = crate::testing::MockContext::new(Duration::from_millis(50));
+    let mut state = Ecs::new();
+    let mut xs = vec![];
+    for step in 0..20 {
+        if step % 5 == 0 {
+            let x = step as f32 * 0.05;
+            let body = Body::new_dynamic([x, 0.0].into(), [1.0, 0.0].into(), Vector2::zeros());
+            new_clients[0].enqueue(ServerMsg::SetBody(1, body, time.get()), Delivery::UnreliableSequenced(1)).unwrap();
+        }
+        subject.update(&mut state, &context).unwrap();
+
+        let mut entities = vec![];
+        state.collect_with(&component_filter!(Sprite), &mut entities);
+        xs.push(state.borrow::<Sprite>(entities[0]).unwrap().location.x);
+
+        context.step();
+        time.set(context.time_since_start());
+    }
+
+    // it is drawn where it was 250ms ago, moving smoothly rather than jumping at each keyframe
+    for (step, &x) in xs.iter().enumerate().skip(5) {
+        crate::testing::assert_roughly_eq("x", (step - 5) as f32 * 0.05, x);
+    }
+}
+
+#[test]
+fn test_snapshot_times() {
+    let time = Rc::new(Cell::new(Duration::from_millis(0u64)));
+    let mut server = crate::network::sim::SimServer::new(Duration::from_millis(0), Rc::clone(&time));
+    let network = server.connect();
+    let mut subject = ClientSystem::new(network, crate::input::default_key_mapping(), crate::network::DEFAULT_CONNECTION_TIMEOUT);
+
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+    let body = Body::new_dynamic([0.0, 0.0].into(), [1.0, 0.0].into(), Vector2::zeros());
+    new_clients[0].enqueue(ServerMsg::Welcome(crate::network::Welcome{ capabilities: vec![], session_token: 1 }), Delivery::ReliableOrdered).unwrap();
+    new_clients[0].enqueue(ServerMsg::SetBody(1, body.clone(), Duration::from_millis(10_000)), Delivery::UnreliableSequenced(1)).unwrap();
+
+    let mut context = crate::testing::MockContext::new(Duration::from_millis(100));
+    let mut state = Ecs::new();
+    subject.update(&mut state, &context).unwrap();
+    let entity = subject.network_entity_id_mapping[&1];
+
+    // until the clocks are synced a snapshot is taken to be as old as its arrival
+    let snapshot_times = |state: &Ecs| state.borrow::<Snapshots>(entity).unwrap().0.iter().map(|(time, _)| *time).collect::<Vec<_>>();
+    assert_eq!(vec![Duration::from_millis(0)], snapshot_times(&state));
+
+    // the server's clock is 10s ahead of ours
+    new_clients[0].enqueue(ServerMsg::Pong(Pong{ ping_time: Duration::from_millis(0), reply_time: Duration::from_millis(10_000) }), Delivery::UnreliableSequenced(CLOCK_PONG_STREAM)).unwrap();
+    subject.update(&mut state, &context).unwrap();
+
+    // after which it is stamped with when the server took it, however late it arrives
+    context.step();
+    context.step();
+    context.step();
+    new_clients[0].enqueue(ServerMsg::SetBody(1, body, Duration::from_millis(10_100)), Delivery::UnreliableSequenced(1)).unwrap();
+    subject.update(&mut state, &context).unwrap();
+    assert_eq!(vec![Duration::from_millis(0), Duration::from_millis(100)], snapshot_times(&state));
+}
+
+#[test]
+fn test_spectator() {
+    let time = Rc::new(Cell::new(Duration::from_millis(0u64)));
+    let mut server = crate::network::sim::SimServer::new(Duration::from_millis(0), Rc::clone(&time));
+    let network = server.connect();
+    let mut subject = ClientSystem::new(network, crate::input::default_key_mapping(), crate::network::DEFAULT_CONNECTION_TIMEOUT);
+    subject.set_is_spectator(true);
+
+    let mut context = crate::testing::MockContext::new(Duration::from_millis(16));
+    let mut state = Ecs::new();
+    subject.init(&mut state, &context).unwrap();
+    subject.server.flush().unwrap();
+    let mut new_clients = vec![];
+    server.get_new_clients(&mut new_clients);
+    let mut client_msgs = vec![];
+    new_clients[0].dequeue(&mut client_msgs).unwrap();
+    assert_eq!(vec![ClientMsg::Hello(Hello{ is_spectator: true, ..Hello::new(None) })], client_msgs);
+
+    let body = Body::new_dynamic([0.0, 0.0].into(), [1.0, 0.0].into(), Vector2::zeros());
+    new_clients[0].enqueue(ServerMsg::Welcome(crate::network::Welcome{ capabilities: vec![], session_token: 1 }), Delivery::ReliableOrdered).unwrap();
+    new_clients[0].enqueue(ServerMsg::SetFocus(1), Delivery::ReliableOrdered).unwrap();
+    new_clients[0].enqueue(ServerMsg::SetBody(1, body.clone(), time.get()), Delivery::UnreliableSequenced(1)).unwrap();
+    new_clients[0].enqueue(ServerMsg::SetBody(2, body, time.get()), Delivery::UnreliableSequenced(2)).unwrap();
+    subject.update(&mut state, &context).unwrap();
+
+    // the player the camera follows is played back like any other
+    let mut focused_entities = vec![];
+    state.collect_with(&component_filter!(Focus), &mut focused_entities);
+    assert_eq!(1, focused_entities.len());
+    assert!(state.has::<Snapshots>(focused_entities[0]).unwrap());
+
+    // the buttons ask to follow someone else instead of moving a gorilla
+    subject.key_down(&mut state, &mut context, KeyCode::Return, KeyMods::empty(), false);
+    subject.key_up(&mut state, &mut context, KeyCode::Return, KeyMods::empty());
+    subject.key_down(&mut state, &mut context, KeyCode::Space, KeyMods::empty(), false);
+    new_clients[0].dequeue(&mut client_msgs).unwrap();
+    client_msgs.retain(|msg| !matches!(msg, ClientMsg::SetInterpolationDelay(_) | ClientMsg::Heartbeat | ClientMsg::Ping(_)));
+    assert_eq!(vec![ClientMsg::CycleFocus{ forward: true }, ClientMsg::CycleFocus{ forward: false }], client_msgs);
+
+    // and the camera moves when the server says so
+    new_clients[0].enqueue(ServerMsg::SetFocus(2), Delivery::ReliableOrdered).unwrap();
+    subject.update(&mut state, &context).unwrap();
+    let mut now_focused_entities = vec![];
+    state.collect_with(&component_filter!(Focus), &mut now_focused_entities);
+    assert_eq!(1, now_focused_entities.len());
+    assert_ne!(focused_entities[0], now_focused_entities[0]);
+}
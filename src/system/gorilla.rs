@@ -1,4 +1,3 @@
-use crate::component::client::Latency;
 use crate::input::KeyMapping;
 use crate::colors::Color;
 use crate::component::Keyboard;
@@ -8,27 +7,27 @@ use crate::component::Anchor;
 use crate::system::System;
 use nalgebra::Vector2;
 use crate::component::Focus;
-use crate::component::body::Body;
+use crate::component::body::{Body, History, HISTORY_LENGTH};
+use crate::component::client::{InterpolationDelay, LastInput};
+use crate::network::clock::ClockSync;
 use crate::component::gorilla::Gorilla;
 use crate::err::GgResult;
 use recs::EntityId;
 use crate::input::Button;
+use crate::input::InputEvent;
 use crate::component::sprite::Sprite;
 use crate::colors::WHITE;
 use crate::component::gorilla::GorillaEvent;
+use std::time::Duration;
 
 #[cfg(test)]
-use std::time::Duration;
+use crate::network::Pong;
 #[cfg(test)]
 use ggez::input::keyboard::KeyCode;
 #[cfg(test)]
 use ggez::event::KeyMods;
-#[cfg(test)]
-use crate::testing::assert_roughly_eq;
 
-pub struct GorillaSystem {
-    pub is_latency_compensation_enabled: bool
-}
+pub struct GorillaSystem {}
 
 pub fn spawn_gorilla(ecs: &mut recs::Ecs, loc: Vector2<f32>, color: Color, key_mapping: Option<KeyMapping>, with_focus: bool) -> GgResult<EntityId> {
     let gorilla = ecs.create_entity();
@@ -36,7 +35,6 @@ pub fn spawn_gorilla(ecs: &mut recs::Ecs, loc: Vector2<f32>, color: Color, key_m
     ecs.set(gorilla, Gorilla::new(loc))?;
     ecs.set(gorilla, Body::new_dynamic(loc, Vector2::zeros(), Vector2::new(0.0, -10.0)))?;
     ecs.set(gorilla, Network)?;
-    ecs.set(gorilla, Latency(0.0))?;
 
     if with_focus {
         ecs.set(gorilla, Focus)?;
@@ -51,6 +49,86 @@ pub fn spawn_gorilla(ecs: &mut recs::Ecs, loc: Vector2<f32>, color: Color, key_m
     Ok(gorilla)
 }
 
+// applies a player's input to their gorilla, both on the server and as a prediction on the client.
+// the view time is the server time of the world the player was looking at, if not the present
+pub fn apply_input(state: &mut Ecs, gorilla: EntityId, input_event: &InputEvent, view_time: Option<Duration>) -> GgResult {
+    match input_event.button {
+        Button::One =>
+            if input_event.is_down {
+                try_add_rope(state, gorilla, view_time)
+            } else {
+                try_remove_rope(state, gorilla)
+            },
+        Button::Two => {
+            let body = state.borrow_mut::<Body>(gorilla)?;
+            if input_event.is_down {
+                body.set_acc(Vector2::new(0.0, -20.0))
+            } else {
+                body.set_acc(Vector2::new(0.0, -10.0))
+            }
+        }
+    };
+
+    Ok(())
+}
+
+fn try_add_rope(
+    state: &mut Ecs, 
+    gorilla: EntityId,
+    view_time: Option<Duration>
+) {
+    let gorilla_body = state.borrow::<Body>(gorilla).unwrap();
+    if gorilla_body.get_is_attached() {
+        return;
+    }
+    let loc = gorilla_body.get_loc();
+
+    let mut ids: Vec<EntityId> = Vec::new();
+    let filter = component_filter!(Anchor, Body);
+    state.collect_with(&filter, &mut ids);
+    let closest_anchor = ids
+        .iter()
+        .map(|&id| (id, (loc - anchor_loc(state, id, view_time)).norm()))
+        .min_by(|a, b| {
+            if a.1 > b.1 { std::cmp::Ordering::Greater } else { std::cmp::Ordering::Less }
+        })
+        .map(|a| a.0);
+
+    if let Some(anchor) = closest_anchor {
+        let anchor_loc = anchor_loc(state, anchor, view_time);
+        let attached_body = state.borrow::<Body>(gorilla).unwrap().to_attached(anchor_loc);
+        state.set(gorilla, attached_body).unwrap();
+
+        // a predicted gorilla on the client has no Gorilla component
+        if let Ok(gorilla_component) = state.borrow_mut::<Gorilla>(gorilla) {
+            gorilla_component.events.push(GorillaEvent::AttachToAnchor(anchor));
+        }
+    }
+}
+
+// where the player saw the anchor, going by its history if it has one
+fn anchor_loc(state: &Ecs, anchor: EntityId, view_time: Option<Duration>) -> Vector2<f32> {
+    view_time
+        .and_then(|time| state.borrow::<History>(anchor).ok()?.at(time))
+        .map_or_else(|| state.borrow::<Body>(anchor).unwrap().get_loc(), |body| body.get_loc())
+}
+
+fn try_remove_rope(
+    state: &mut Ecs, 
+    gorilla: EntityId
+) {
+    let gorilla_body = state.borrow::<Body>(gorilla).unwrap();
+    if !gorilla_body.get_is_attached() {
+        return;
+    }
+    let detached_body = gorilla_body.to_detached();
+    state.set(gorilla, detached_body).unwrap();
+
+    if let Ok(gorilla_component) = state.borrow_mut::<Gorilla>(gorilla) {
+        gorilla_component.events.push(GorillaEvent::DetachFromAnchor());
+    }
+}
+
 pub fn spawn_anchor(ecs: &mut recs::Ecs, loc: Vector2<f32>) -> GgResult<EntityId> {
     let anchor = ecs.create_entity();
     ecs.set(anchor, Anchor)?;
@@ -112,144 +190,139 @@ impl GorillaSystem {
             return Ok(())
         }
 
-        self.apply_latency_compensation(entity, state, false)?;
-
-        for input_event in events {
-            match input_event.button {
-                Button::One =>
-                    if input_event.is_down {
-                        self.try_add_rope(state, entity)
-                    } else {
-                        self.try_remove_rope(state, entity)
-                    },
-                Button::Two => {
-                    let body = state.borrow_mut::<Body>(entity)?;
-                    if input_event.is_down {
-                        body.set_acc(Vector2::new(0.0, -20.0))
-                    } else {
-                        body.set_acc(Vector2::new(0.0, -10.0))
-                    }
-                }
-            };
+        let view_time = Self::view_time(entity, state);
+        for input_event in events.iter() {
+            apply_input(state, entity, input_event, view_time)?;
         }
 
-        self.apply_latency_compensation(entity, state, true)?;
-
         Ok(())
     }
 
-    fn apply_latency_compensation(&mut self, entity: EntityId, state: &mut Ecs, is_forward: bool) -> GgResult {
-        if !self.is_latency_compensation_enabled {
-            return Ok(());
-        }
-
-        let mut latency = state.borrow::<Latency>(entity).unwrap().0 / 2.0;
-        if !is_forward {
-            latency = -latency;
-        }
+    // the server time of the world the player was looking at when they sent their input. it took a
+    // round trip for what they saw to reach them and their input to get back, and they draw other
+    // entities an interpolation delay behind what they have received. their own gorilla is already
+    // where they saw it, since the server applies their inputs as late as they arrive
+    fn view_time(entity: EntityId, state: &Ecs) -> Option<Duration> {
+        let receive_time = state.borrow::<LastInput>(entity).ok()?.server_time;
+        let rtt = state.borrow::<ClockSync>(entity).ok()?.rtt()?;
+        let interpolation_delay = state.borrow::<InterpolationDelay>(entity).map_or(Duration::from_millis(0), |delay| delay.0);
+        Some(receive_time.saturating_sub(rtt.saturating_add(interpolation_delay).min(HISTORY_LENGTH)))
+    }
+}
 
-        #[cfg(debug)]
-        println!("apply latency comp of {}s", latency);
+#[test]
+fn test_prediction_and_reconciliation() {
+    expect_prediction_and_reconciliation(crate::testing::MockSetup::new(Duration::from_millis(50), Duration::from_millis(16), true), None);
+}
 
-        let body = state.borrow_mut::<Body>(entity).unwrap();
-        body.step(latency);
+#[test]
+fn test_asymmetric_prediction_and_reconciliation() {
+    // the client's prediction holds however the round trip is split between the two directions
+    let ideal = |ms| crate::network::sim::NetworkConditions::ideal(Duration::from_millis(ms));
+    let fibre_up_mobile_down = crate::network::sim::LinkConditions{ uplink: ideal(10), downlink: ideal(150) };
+    let mobile_up_fibre_down = crate::network::sim::LinkConditions{ uplink: ideal(150), downlink: ideal(10) };
+    expect_prediction_and_reconciliation(crate::testing::MockSetup::with_link(fibre_up_mobile_down.clone(), Duration::from_millis(16), true), None);
+    expect_prediction_and_reconciliation(crate::testing::MockSetup::with_link(mobile_up_fibre_down.clone(), Duration::from_millis(16), true), None);
+
+    // and when it changes while the server is catching up
+    expect_prediction_and_reconciliation(crate::testing::MockSetup::with_link(fibre_up_mobile_down, Duration::from_millis(16), true), Some(mobile_up_fibre_down));
+}
 
-        Ok(())
+#[cfg(test)]
+fn expect_prediction_and_reconciliation(mut setup: crate::testing::MockSetup, handover: Option<crate::network::sim::LinkConditions>) {
+    for _ in 0..10 {
+        setup.step();
     }
 
-    fn try_add_rope(
-        &mut self, 
-        state: &mut Ecs, 
-        gorilla: EntityId
-    ) {
-        let gorilla_body = state.borrow::<Body>(gorilla).unwrap();
-        if gorilla_body.get_is_attached() {
-            return;
-        }
-        let loc = gorilla_body.get_loc();
+    let mut focused_entities = vec![];
+    setup.client1_engine.get_state().collect_with(&component_filter!(Focus, Body), &mut focused_entities);
+    let client_gorilla = focused_entities[0];
 
-        let mut ids: Vec<EntityId> = Vec::new();
-        let filter = component_filter!(Anchor, Body);
-        state.collect_with(&filter, &mut ids);
-        let closest_anchor = ids
-            .iter()
-            .map(|&id| (id, (loc - state.borrow::<Body>(id).unwrap().get_loc()).norm()))
-            .min_by(|a, b| {
-                if a.1 > b.1 { std::cmp::Ordering::Greater } else { std::cmp::Ordering::Less }
-            })
-            .map(|a| a.0);
-
-        if let Some(anchor) = closest_anchor {
-            let anchor_loc = state.borrow::<Body>(anchor).unwrap().get_loc();
-            let attached_body = state.borrow::<Body>(gorilla).unwrap().to_attached(anchor_loc);
-            state.set(gorilla, attached_body).unwrap();
-            state.borrow_mut::<Gorilla>(gorilla).unwrap().events.push(GorillaEvent::AttachToAnchor(anchor));
-        }
+    // the client throws its rope straight away
+    setup.client1_engine.key_down_event(&mut setup.context, KeyCode::Space, KeyMods::empty(), false);
+    assert!(setup.client1_engine.get_state().borrow::<Body>(client_gorilla).unwrap().get_is_attached());
+
+    // the server applies the input when it arrives, so its gorilla runs this far behind the client's
+    let input_latency = setup.client1_link.get().uplink.latency;
+
+    if let Some(conditions) = handover {
+        setup.client1_link.set(conditions);
     }
 
-    fn try_remove_rope(
-        &mut self, 
-        state: &mut Ecs, 
-        gorilla: EntityId
-    ) {
-        let gorilla_body = state.borrow::<Body>(gorilla).unwrap();
-        if !gorilla_body.get_is_attached() {
-            return;
-        }
-        let detached_body = gorilla_body.to_detached();
-        state.set(gorilla, detached_body).unwrap();
-        state.borrow_mut::<Gorilla>(gorilla).unwrap().events.push(GorillaEvent::DetachFromAnchor());        
+    // and stays attached while the server catches up, rather than snapping back
+    for _ in 0..30 {
+        setup.step();
+        assert!(setup.client1_engine.get_state().borrow::<Body>(client_gorilla).unwrap().get_is_attached());
     }
+
+    // by which time the server agrees
+    let mut server_gorillas = vec![];
+    setup.server_engine.get_state().collect_with(&component_filter!(Gorilla), &mut server_gorillas);
+    assert!(setup.server_engine.get_state().borrow::<Body>(server_gorillas[0]).unwrap().get_is_attached());
+
+    let mut server_body = setup.server_engine.get_state().get::<Body>(server_gorillas[0]).unwrap();
+    server_body.step(input_latency.as_secs_f32());
+    let server_loc = server_body.get_loc();
+    let client_loc = setup.client1_engine.get_state().borrow::<Body>(client_gorilla).unwrap().get_loc();
+    assert!((server_loc - client_loc).norm() < 0.5, "{} != {}", server_loc, client_loc);
 }
 
 #[test]
-fn test_latency_compensation() {
-    expect_latency_compensation(KeyCode::Return);
-    expect_latency_compensation(KeyCode::Space);
+fn test_view_time() {
+    let mut state = Ecs::new();
+    let entity = state.create_entity();
+    let mut clock_sync = ClockSync::new();
+    clock_sync.add_sample(&Pong{ ping_time: Duration::from_millis(0), reply_time: Duration::from_millis(50) }, Duration::from_millis(100));
+    state.set(entity, clock_sync).unwrap();
+    state.set(entity, LastInput{ seq: 1, client_time: Duration::from_secs(5), server_time: Duration::from_secs(5) }).unwrap();
+
+    state.set(entity, InterpolationDelay(Duration::from_millis(200))).unwrap();
+    assert_eq!(Some(Duration::from_millis(4700)), GorillaSystem::view_time(entity, &state));
+
+    // however far behind the client says it is, the server only looks back as far as it remembers
+    state.set(entity, InterpolationDelay(Duration::MAX)).unwrap();
+    assert_eq!(Some(Duration::from_secs(5) - HISTORY_LENGTH), GorillaSystem::view_time(entity, &state));
 }
 
-#[cfg(test)]
-fn expect_latency_compensation(user_input: KeyCode) {
-    // create two setups, one with zero latency and no latency comp and
-    // one with non-zero latency and latency comp
-    let mut setups = vec![
-        crate::testing::MockSetup::new(Duration::from_millis(0), Duration::from_millis(16), false),
-        crate::testing::MockSetup::new(Duration::from_millis(50), Duration::from_millis(16), true)
-    ];
-
-    // allow time for server to measure latency
-    for setup in setups.iter_mut() {
-        for _ in 0..10 {
-            setup.step();
-        }
-    }
-
-    // in each setup a client issues the same user input at the same time
-    for setup in setups.iter_mut() {
-        setup.client1_engine.key_down_event(
-            &mut setup.context,
-            user_input,
-            KeyMods::empty(),
-            false,
-        );
-    }
+#[test]
+fn test_lag_compensation() {
+    expect_rope_thrown_at(true, 0);
+    expect_rope_thrown_at(false, 1);
+}
 
-    // after both servers have received the user input
-    for setup in setups.iter_mut() {
-        for _ in 0..10 {
-            setup.step();
-        }
+#[cfg(test)]
+fn expect_rope_thrown_at(is_lagging: bool, expected_anchor: usize) {
+    let mut state = Ecs::new();
+    let gorilla = spawn_gorilla(&mut state, [0.0, 0.0].into(), WHITE, None, false).unwrap();
+
+    // one anchor is passing close by half a second ago but has since moved away, leaving
+    // another that never moved as the closest
+    let anchors = [([1.0, 0.0], [10.0, 0.0]), ([3.0, 0.0], [3.0, 0.0])].iter().map(|&(then, now)| {
+        let anchor = state.create_entity();
+        state.set(anchor, Anchor).unwrap();
+        state.set(anchor, Body::new_static(now.into())).unwrap();
+        let mut history = History::new();
+        history.record(Duration::from_millis(500), Body::new_static(then.into()));
+        history.record(Duration::from_millis(1000), Body::new_static(now.into()));
+        state.set(anchor, history).unwrap();
+        anchor
+    }).collect::<Vec<_>>();
+
+    // the player has a 200ms round trip and draws others 300ms behind that, so they were looking
+    // at the world as it was half a second before their input arrived
+    if is_lagging {
+        let mut clock_sync = ClockSync::new();
+        clock_sync.add_sample(&Pong{ ping_time: Duration::from_millis(0), reply_time: Duration::from_millis(100) }, Duration::from_millis(200));
+        state.set(gorilla, clock_sync).unwrap();
+        state.set(gorilla, InterpolationDelay(Duration::from_millis(300))).unwrap();
     }
+    state.set(gorilla, LastInput{ seq: 1, client_time: Duration::from_millis(900), server_time: Duration::from_millis(1000) }).unwrap();
+    let input_event = InputEvent{ button: Button::One, is_down: true, seq: 1, time: Duration::from_millis(900) };
+    state.borrow_mut::<Gorilla>(gorilla).unwrap().input_events.push(input_event);
 
-    // both should be in roughly the same state 
-    // (despite the fact that they recevied it at different times)
-    let bodies = setups.iter().map(|s| {
-        let mut body_entities = vec![];
-        s.server_engine.get_state().collect_with(&component_filter!(crate::component::gorilla::Gorilla), &mut body_entities);
-        body_entities.iter().map(|&e| s.server_engine.get_state().borrow::<Body>(e).unwrap().get_loc().y).collect::<Vec::<_>>()
-    }).collect::<Vec::<_>>();
+    let context = crate::testing::MockContext::new(Duration::from_millis(16));
+    System::update(&mut GorillaSystem{}, &mut state, &context).unwrap();
 
-    for b in 0..bodies[0].len() {
-        assert_roughly_eq("body.y", bodies[0][b], bodies[1][b]);
-    }
-}
\ No newline at end of file
+    let events = &state.borrow::<Gorilla>(gorilla).unwrap().events;
+    assert!(events.iter().any(|event| matches!(event, GorillaEvent::AttachToAnchor(anchor) if *anchor == anchors[expected_anchor])));
+}
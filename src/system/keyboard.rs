@@ -5,6 +5,7 @@ use crate::component::gorilla::Gorilla;
 use ggez::event::KeyMods;
 use ggez::event::KeyCode;
 use recs::Ecs;
+use std::time::Duration;
 use crate::system::System;
 
 pub struct KeyboardSystem {
@@ -18,7 +19,8 @@ fn process_keyboard_event(state: &mut Ecs, keycode: KeyCode, is_down: bool) -> G
         let keyboard_component: &Keyboard = state.borrow(gorilla_entity).unwrap();
         if let Some(&button) = keyboard_component.0.get(&keycode) {
             let gorilla_component = state.borrow_mut::<Gorilla>(gorilla_entity).unwrap();
-            gorilla_component.input_events.push(InputEvent{button, is_down});
+            // local input is applied straight away so there is nothing to reconcile
+            gorilla_component.input_events.push(InputEvent{button, is_down, seq: 0, time: Duration::from_millis(0)});
             break;
         }
     }
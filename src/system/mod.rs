@@ -31,6 +31,10 @@ pub trait System<TContext> {
         Ok(())
     }
 
+    fn shutdown(&mut self, _: &mut Ecs, _: &TContext, _: &str) -> GgResult {
+        Ok(())
+    }
+
     fn key_down(&mut self,
         _: &mut Ecs,
         _: &mut TContext,
@@ -5,6 +5,7 @@ use recs::EntityId;
 use crate::err::GgResult;
 use crate::system::System;
 use crate::component::sprite::Sprite;
+use crate::component::client::Snapshots;
 
 pub struct PhysicsSystem {
 }
@@ -18,6 +19,11 @@ impl<TContext> System<TContext> for PhysicsSystem where TContext: TimerService {
         let filter = component_filter!(Body);
         state.collect_with(&filter, &mut ids);
         for &entity in ids.iter() {
+            // ClientSystem plays these back from what the server sent instead
+            if state.has::<Snapshots>(entity).unwrap() {
+                continue;
+            }
+
             let body : &mut Body = state.borrow_mut(entity).unwrap();
 
             let t_delta = context.average_delta().as_secs_f32();
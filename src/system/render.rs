@@ -8,6 +8,9 @@ use ggez::graphics::spritebatch::SpriteBatch;
 use crate::err::GgResult;
 use ggez::graphics;
 
+// How much of the world a player sees, centred on their gorilla
+pub const VIEW_SIZE: [f32; 2] = [12.0, 9.0];
+
 pub struct RenderSystem {
     sprite_batch: SpriteBatch,
 }
@@ -62,13 +65,13 @@ impl RenderSystem {
         state.collect_with(&component_filter!(Focus), &mut focus_entities);
         if let Some(&focus_entity) = focus_entities.first() {
             if let Ok(sprite) = state.borrow::<Sprite>(focus_entity) {
-                let x_min = sprite.location.x - 6.0;
-                let y_min = sprite.location.y + 4.5;
+                let x_min = sprite.location.x - VIEW_SIZE[0] / 2.0;
+                let y_min = sprite.location.y + VIEW_SIZE[1] / 2.0;
                 let screen_rect = graphics::Rect::new(
                     x_min,
                     y_min,
-                    12.0, 
-                    -9.0
+                    VIEW_SIZE[0], 
+                    -VIEW_SIZE[1]
                 );
                 context.set_screen_coordinates(screen_rect)?;
             }
@@ -1,5 +1,4 @@
-use crate::component::client::Latency;
-use crate::network::ServerMsg::Ping;
+use crate::component::client::{Baseline, InputGuard, InterpolationDelay, LastHeard, LastInput, Session, Parked, Spectator};
 use crate::context::TimerService;
 use std::time::Duration;
 use crate::system::gorilla::spawn_anchor;
@@ -10,103 +9,372 @@ use crate::network::RxChannel;
 use crate::network::TxChannel;
 use crate::network::Server;
 use crate::component::Focus;
+use crate::component::Anchor;
 use recs::EntityId;
 use crate::component::sprite::Sprite;
-use crate::component::body::Body;
+use crate::component::body::{Body, History, HISTORY_LENGTH};
 use crate::component::Network;
 use crate::component::gorilla::Gorilla;
-use crate::network::{ClientMsg, ServerMsg};
+use crate::system::render::VIEW_SIZE;
+use nalgebra::Vector2;
+use crate::network::{ClientMsg, ServerMsg, Delivery, Hello, Welcome, InputAck, PROTOCOL_VERSION, CAPABILITIES, KEEPALIVE_PERIOD};
+use crate::network::lobby::{PendingClient, timeout_reason};
+use crate::network::codec::Codec;
+use crate::network::clock::{ClockSync, CLOCK_SYNC_PERIOD, CLOCK_PING_STREAM, CLOCK_PONG_STREAM};
+use crate::network::Pong;
 use crate::err::GgResult;
 use crate::system::System;
 use crate::component::client::Client;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::collections::VecDeque;
+#[cfg(test)]
+use crate::input::{Button, InputEvent};
+#[cfg(test)]
+use crate::network::sim::SimNetworkEnd;
+#[cfg(test)]
+use crate::testing::receive;
 
-const LATENCY_MEASUREMENT_PERIOD: Duration = Duration::from_secs(5);
+const RECONNECT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+// How often each client's traffic so far is logged
+const STATS_LOG_PERIOD: Duration = Duration::from_secs(60);
+
+// How far beyond the edge of a player's screen entities are sent, so that they are already there by
+// the time they come into view, and how much further they must go before being despawned, so that
+// one lingering at the edge isn't spawned and despawned over and over
+const INTEREST_MARGIN: f32 = 4.0;
+const DISINTEREST_MARGIN: f32 = 6.0;
+
+// a client that has completed the handshake but is waiting for a free slot
+struct QueuedClient<TNetwork> {
+    network: TNetwork,
+    capabilities: Vec<String>,
+    last_heard: Duration,
+    // the position it was last told it was at
+    position: usize
+}
+
+// what a client is given once it has been welcomed
+enum Role {
+    // a gorilla, either a new one or the one held for a returning client
+    Player(Option<EntityId>),
+    Spectator
+}
+
+// How many players the server takes, counting those whose gorillas are being held for them to
+// reconnect, and how many more may wait for a slot. Any more than that are rejected
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct PlayerLimit {
+    pub max_players: usize,
+    pub max_queue_length: usize
+}
 
 pub struct ServerSystem<TServer, TNetwork> where TServer: Server<TNetwork>, TNetwork: TxChannel<ServerMsg> + RxChannel<ClientMsg> {
     server: TServer,
     new_client_buffer: Vec::<TNetwork>,
+    pending_clients: Vec::<PendingClient<TNetwork>>,
+    queued_clients: VecDeque::<QueuedClient<TNetwork>>,
+    // None when any number may play
+    player_limit: Option<PlayerLimit>,
     entity_buffer_1: Vec::<EntityId>,
     entity_buffer_2: Vec::<EntityId>,
     msg_buffer: Vec::<ClientMsg>,
     colors: Colors,
-    next_latency_measurement_time: Option<Duration>
+    // None when the server doesn't need to know its clients' latency
+    next_ping_time: Option<Duration>,
+    next_keepalive_time: Duration,
+    next_stats_time: Duration,
+    connection_timeout: Duration
 }
 
 impl<TServer, TNetwork> ServerSystem<TServer, TNetwork> where TServer: Server<TNetwork>, TNetwork: 'static + TxChannel<ServerMsg> + RxChannel<ClientMsg> {
     
-    pub fn new(server: TServer, is_latency_compensation_enabled: bool) -> GgResult<ServerSystem<TServer, TNetwork>> {
+    pub fn new(server: TServer, is_latency_compensation_enabled: bool, connection_timeout: Duration) -> GgResult<ServerSystem<TServer, TNetwork>> {
         Ok(ServerSystem{
             server,
             new_client_buffer: vec![],
+            pending_clients: vec![],
+            queued_clients: VecDeque::new(),
+            player_limit: None,
             entity_buffer_1: vec![],
             entity_buffer_2: vec![],
             msg_buffer: vec![],
             colors: Colors::new(),
-            next_latency_measurement_time: if is_latency_compensation_enabled { Some(Duration::from_millis(0u64)) } else { None }
+            next_ping_time: if is_latency_compensation_enabled { Some(Duration::from_millis(0u64)) } else { None },
+            next_keepalive_time: Duration::from_millis(0u64),
+            next_stats_time: STATS_LOG_PERIOD,
+            connection_timeout
         })
     }
 
-    fn process_new_clients(&mut self, state: &mut Ecs) -> GgResult {
+    pub fn set_player_limit(&mut self, player_limit: PlayerLimit) {
+        self.player_limit = Some(player_limit);
+    }
+
+    #[cfg(test)]
+    pub fn server_mut(&mut self) -> &mut TServer {
+        &mut self.server
+    }
+
+    fn process_new_clients<TContext>(&mut self, context: &TContext) where TContext: TimerService {
         self.new_client_buffer.clear();
         self.server.get_new_clients(&mut self.new_client_buffer);
-        for mut new_client in self.new_client_buffer.drain(..) {
-            let client_entity = crate::system::gorilla::spawn_gorilla(state, [-1.5, 5.0].into(), self.colors.next(), None, false)?;
+        let connect_time = context.time_since_start();
+        for network in self.new_client_buffer.drain(..) {
+            self.pending_clients.push(PendingClient{ network, connect_time });
+        }
+    }
 
-            let msg = ServerMsg::SetFocus(client_entity.get_id_number());
-            new_client.enqueue(msg)?;
+    fn process_handshakes<TContext>(&mut self, context: &TContext, state: &mut Ecs) -> GgResult where TContext: TimerService {
+        let time = context.time_since_start();
+        let pending_clients = std::mem::take(&mut self.pending_clients);
+        for mut pending_client in pending_clients {
+            if pending_client.network.dequeue(&mut self.msg_buffer).is_err() {
+                println!("a client disconnected during the handshake");
+                continue;
+            }
 
-            state.set(client_entity, Client(new_client))?;
-            println!("client #{} has connected", client_entity.get_id_number());
+            // the client sends nothing else until it has been welcomed
+            let hello = self.msg_buffer.drain(..).find_map(|msg| match msg {
+                ClientMsg::Hello(hello) => Some(hello),
+                _ => None
+            });
 
-            // send current state to new client
-            self.entity_buffer_2.clear();
-            state.collect_with(&component_filter!(Network), &mut self.entity_buffer_2);     
-            for &network_entity in self.entity_buffer_2.iter() {
+            match hello {
+                Some(hello) => match check_hello(&hello) {
+                    Ok(capabilities) => {
+                        let parked_entity = hello.session_token.and_then(|token| self.find_parked_entity(state, token));
+                        let session_token = match parked_entity {
+                            Some(entity) => state.borrow::<Session>(entity)?.0,
+                            None => new_session_token()
+                        };
 
-                if let Ok(body) = state.get::<Body>(network_entity) {
-                    let msg = ServerMsg::SetBody(network_entity.get_id_number(), body);
-                    state.borrow_mut::<Client<TNetwork>>(client_entity).unwrap().0.enqueue(msg)?;
-                }
-                
-                if let Ok(sprite) = state.get::<Sprite>(network_entity) {
-                    let msg = ServerMsg::SetSprite(network_entity.get_id_number(), sprite);
-                    state.borrow_mut::<Client<TNetwork>>(client_entity).unwrap().0.enqueue(msg)?;
+                        // spectators don't take a slot, a returning player's slot has been kept for them,
+                        // and a new one waits its turn behind anyone already queued
+                        if hello.is_spectator {
+                            self.welcome_client(context, state, pending_client.network, capabilities, Role::Spectator, session_token)?;
+                        } else if parked_entity.is_some() || (self.queued_clients.is_empty() && self.has_free_slot(state)) {
+                            self.welcome_client(context, state, pending_client.network, capabilities, Role::Player(parked_entity), session_token)?;
+                        } else {
+                            self.queue_client(time, pending_client.network, capabilities);
+                        }
+                    },
+                    Err(reason) => pending_client.reject(reason)
+                },
+                None => {
+                    if pending_client.has_timed_out(time) {
+                        pending_client.reject(timeout_reason());
+                    } else {
+                        self.pending_clients.push(pending_client);
+                    }
                 }
+            }
+        }
 
-                if state.has::<Focus>(network_entity).unwrap() {
-                    let msg = ServerMsg::SetFocus(network_entity.get_id_number());
-                    state.borrow_mut::<Client<TNetwork>>(client_entity).unwrap().0.enqueue(msg)?;
-                }
+        Ok(())
+    }
+
+    fn welcome_client<TContext>(&mut self, context: &TContext, state: &mut Ecs, mut network: TNetwork, capabilities: Vec<String>, role: Role, session_token: u64) -> GgResult where TContext: TimerService {
+        let codec = Codec::negotiate(&capabilities);
+        let welcome = Welcome{ capabilities, session_token };
+        // the Welcome still goes the way the rest of the handshake did, and only what follows it
+        // uses the codec it agrees
+        if network.enqueue(ServerMsg::Welcome(welcome), Delivery::ReliableOrdered).and_then(|_| network.flush()).is_err() {
+            println!("a client disconnected during the handshake");
+            return Ok(());
+        }
+        network.set_codec(codec);
+        self.admit_client(context, state, network, role, session_token)
+    }
+
+    fn has_free_slot(&self, state: &Ecs) -> bool {
+        let player_limit = match self.player_limit {
+            Some(player_limit) => player_limit,
+            None => return true
+        };
+
+        // a player who has quit keeps their slot until their gorilla is gone at the end of the tick
+        let mut players = vec![];
+        state.collect_with(&component_filter!(Session), &mut players);
+        let player_count = players.iter().filter(|&&entity| !state.has::<Dead>(entity).unwrap()).count();
+        player_count < player_limit.max_players
+    }
+
+    fn queue_client(&mut self, time: Duration, mut network: TNetwork, capabilities: Vec<String>) {
+        let max_queue_length = self.player_limit.map_or(0, |player_limit| player_limit.max_queue_length);
+        if self.queued_clients.len() >= max_queue_length {
+            println!("rejected a client: the server is full");
+            let _ = network.enqueue(ServerMsg::Reject("the server is full".to_string()), Delivery::ReliableOrdered);
+            return;
+        }
+
+        let position = self.queued_clients.len() + 1;
+        if network.enqueue(ServerMsg::Queued(position as u32), Delivery::ReliableOrdered).is_err() {
+            println!("a client disconnected during the handshake");
+            return;
+        }
+        println!("the server is full, a client is waiting at position {}", position);
+        self.queued_clients.push_back(QueuedClient{ network, capabilities, last_heard: time, position });
+    }
+
+    // lets queued clients in as slots free up, and tells the rest how far they have moved up
+    fn process_queue<TContext>(&mut self, context: &TContext, state: &mut Ecs) -> GgResult where TContext: TimerService {
+        let time = context.time_since_start();
+        let queued_clients = std::mem::take(&mut self.queued_clients);
+        for mut queued_client in queued_clients {
+            if queued_client.network.dequeue(&mut self.msg_buffer).is_err() {
+                println!("a queued client disconnected");
+                continue;
+            }
+
+            // nothing but a Goodbye matters until it has been welcomed
+            if !self.msg_buffer.is_empty() {
+                queued_client.last_heard = time;
+            }
+            if self.msg_buffer.drain(..).any(|msg| msg == ClientMsg::Goodbye) {
+                println!("a queued client gave up waiting");
+                continue;
+            }
+            if time - queued_client.last_heard > self.connection_timeout {
+                println!("a queued client timed out");
+                continue;
+            }
+
+            self.queued_clients.push_back(queued_client);
+        }
+
+        while !self.queued_clients.is_empty() && self.has_free_slot(state) {
+            let queued_client = self.queued_clients.pop_front().unwrap();
+            self.welcome_client(context, state, queued_client.network, queued_client.capabilities, Role::Player(None), new_session_token())?;
+        }
+
+        for (i, queued_client) in self.queued_clients.iter_mut().enumerate() {
+            if queued_client.position != i + 1 {
+                queued_client.position = i + 1;
+                let _ = queued_client.network.enqueue(ServerMsg::Queued(queued_client.position as u32), Delivery::ReliableOrdered);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn admit_client<TContext>(&mut self, context: &TContext, state: &mut Ecs, mut new_client: TNetwork, role: Role, session_token: u64) -> GgResult where TContext: TimerService {
+        let client_entity = match role {
+            // a returning client gets their old gorilla back, with its colour and tag state intact
+            Role::Player(Some(entity)) => {
+                state.unset::<Parked>(entity)?;
+                println!("client #{} has reconnected", entity.get_id_number());
+                entity
+            },
+            Role::Player(None) => {
+                let entity = crate::system::gorilla::spawn_gorilla(state, [-1.5, 5.0].into(), self.colors.next(), None, false)?;
+                state.set(entity, Session(session_token))?;
+                println!("client #{} has connected", entity.get_id_number());
+                entity
+            },
+            // told which player to follow once there is one
+            Role::Spectator => {
+                let entity = state.create_entity();
+                state.set(entity, Spectator{ following: None })?;
+                println!("client #{} has connected as a spectator", entity.get_id_number());
+                entity
             }
+        };
+
+        if !state.has::<Spectator>(client_entity)? {
+            let msg = ServerMsg::SetFocus(client_entity.get_id_number());
+            new_client.enqueue(msg, Delivery::ReliableOrdered)?;
+        }
+
+        // measure latency straight away rather than waiting for the next periodic measurement
+        if self.next_ping_time.is_some() {
+            new_client.enqueue(ServerMsg::Ping(context.time_since_start()), Delivery::UnreliableSequenced(CLOCK_PING_STREAM))?;
         }
 
+        state.set(client_entity, Client(new_client))?;
+        state.set(client_entity, LastHeard(context.time_since_start()))?;
+        // the last connection's latency may have nothing to do with this one's
+        state.set(client_entity, ClockSync::new())?;
+        // a returning client has thrown away everything it was sent before. whatever is near it is
+        // spawned when state is next broadcast
+        state.set(client_entity, Baseline::default())?;
+        // a returning client's buttons were all let go when it lost the connection
+        state.set(client_entity, InputGuard::new(context.time_since_start()))?;
+
         Ok(())
     }
 
     fn process_client_msgs<TContext>(&mut self, context: &TContext, state: &mut Ecs) -> GgResult where TContext: TimerService {
+        let time = context.time_since_start();
         self.entity_buffer_1.clear();
         state.collect_with(&component_filter!(Client<TNetwork>), &mut self.entity_buffer_1);
         for &client_entity in self.entity_buffer_1.iter() {
             let client_component = state.borrow_mut::<Client<TNetwork>>(client_entity).unwrap();
 
             if client_component.0.dequeue(&mut self.msg_buffer).is_err() {
-                self.disconnect_client(state, client_entity);
+                Self::drop_client(state, client_entity, time, "connection lost");
                 continue;
             }
 
+            if self.msg_buffer.is_empty() {
+                let last_heard = state.borrow::<LastHeard>(client_entity).unwrap().0;
+                if time - last_heard > self.connection_timeout {
+                    Self::drop_client(state, client_entity, time, "timed out");
+                }
+                continue;
+            }
+            state.borrow_mut::<LastHeard>(client_entity).unwrap().0 = time;
+
             for msg in self.msg_buffer.drain(..) {
                 match msg {
+                    ClientMsg::Goodbye => {
+                        Self::disconnect_client(state, client_entity, "client quit");
+                        break;
+                    },
+                    ClientMsg::Input(_) if state.has::<Spectator>(client_entity)? => {},
                     ClientMsg::Input(input_event) => {
+                        let check = state.borrow_mut::<InputGuard>(client_entity).unwrap().check(&input_event, time);
+                        if let Err(violation) = check {
+                            println!("warning: client #{} sent an invalid input: {}", client_entity.get_id_number(), violation);
+                            if state.borrow_mut::<InputGuard>(client_entity).unwrap().add_violation(time) {
+                                Self::kick_client(state, client_entity, "too many invalid inputs");
+                                break;
+                            }
+                            continue;
+                        }
+
+                        // GorillaSystem applies the input on its next update
+                        let last_input = LastInput{ seq: input_event.seq, client_time: input_event.time, server_time: time };
+                        state.set(client_entity, last_input).unwrap();
+
                         let gorilla_component = state.borrow_mut::<Gorilla>(client_entity).unwrap();
                         gorilla_component.input_events.push(input_event);
                     },
-                    ClientMsg::Pong(tx_time) => {
-                        let latency = context.time_since_start() - tx_time;
-                        let latency_component = state.borrow_mut::<Latency>(client_entity).unwrap();
-                        latency_component.0 = latency.as_secs_f32();
+                    ClientMsg::CycleFocus{ forward } => {
+                        if state.has::<Spectator>(client_entity)? {
+                            Self::cycle_focus(state, client_entity, forward)?;
+                        }
+                    },
+                    ClientMsg::Hello(_) => {},
+                    ClientMsg::Heartbeat => {},
+                    ClientMsg::SetInterpolationDelay(interpolation_delay) => {
+                        // there is no looking further back than the server remembers
+                        state.set(client_entity, InterpolationDelay(interpolation_delay.min(HISTORY_LENGTH))).unwrap();
+                    },
+                    ClientMsg::Ping(ping_time) => {
+                        let pong = ServerMsg::Pong(Pong{ ping_time, reply_time: time });
+                        state.borrow_mut::<Client<TNetwork>>(client_entity).unwrap().0.enqueue(pong, Delivery::UnreliableSequenced(CLOCK_PONG_STREAM))?;
+                    },
+                    ClientMsg::Pong(pong) => {
+                        let clock_sync = state.borrow_mut::<ClockSync>(client_entity).unwrap();
+                        clock_sync.add_sample(&pong, time);
 
                         #[cfg(debug)]
-                        println!("Client #{} ping: {:#?}", client_entity.get_id_number(), latency_component.0);
+                        println!("Client #{} rtt: {:?} jitter: {:?}", client_entity.get_id_number(), clock_sync.rtt(), clock_sync.jitter());
                     }
                     #[cfg(test)]
                     ClientMsg::Test(_) => {}
@@ -117,72 +385,373 @@ impl<TServer, TNetwork> ServerSystem<TServer, TNetwork> where TServer: Server<TN
         Ok(())
     }
 
-    fn broadcast_state(&mut self, state: &mut Ecs) -> GgResult {
+    // points each spectator's camera at a player, should the one it was following have gone
+    fn update_spectators(&mut self, state: &mut Ecs) -> GgResult {
+        self.entity_buffer_2.clear();
+        state.collect_with(&component_filter!(Spectator, Client<TNetwork>), &mut self.entity_buffer_2);
+        for &spectator_entity in self.entity_buffer_2.iter() {
+            let following = state.borrow::<Spectator>(spectator_entity)?.following;
+            let is_following_player = matches!(following, Some(entity) if is_playing(state, entity));
+            if !is_following_player {
+                Self::cycle_focus(state, spectator_entity, true)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // moves a spectator's camera on to the next player in order of id, or back to the previous one
+    fn cycle_focus(state: &mut Ecs, spectator_entity: EntityId, forward: bool) -> GgResult {
+        let mut players = vec![];
+        state.collect_with(&component_filter!(Gorilla, Network), &mut players);
+        players.retain(|&entity| is_playing(state, entity));
+        players.sort_by_key(|entity| entity.get_id_number());
+        if players.is_empty() {
+            return Ok(());
+        }
+
+        let following = state.borrow::<Spectator>(spectator_entity)?.following;
+        let next = match following.and_then(|entity| players.iter().position(|&player| player == entity)) {
+            Some(i) if forward => (i + 1) % players.len(),
+            Some(i) => (i + players.len() - 1) % players.len(),
+            None => 0
+        };
+
+        state.borrow_mut::<Spectator>(spectator_entity)?.following = Some(players[next]);
+        let msg = ServerMsg::SetFocus(players[next].get_id_number());
+        let _ = state.borrow_mut::<Client<TNetwork>>(spectator_entity)?.0.enqueue(msg, Delivery::ReliableOrdered);
+        Ok(())
+    }
+
+    fn broadcast_state<TContext>(&mut self, context: &TContext, state: &mut Ecs) -> GgResult where TContext: TimerService {
         self.entity_buffer_2.clear();
         state.collect_with(&component_filter!(Network), &mut self.entity_buffer_2);        
+        let spawns = self.update_interests(state)?;
+
         for &network_entity in self.entity_buffer_2.iter() {
+            let interested_clients = self.interested_clients(state, network_entity);
 
             let is_keyframe = state.borrow_mut::<Body>(network_entity).unwrap().get_is_keyframe_and_reset();
-            if !is_keyframe { continue; }   
 
-            if let Ok(body) = state.get::<Body>(network_entity) {
-                let msg = ServerMsg::SetBody(network_entity.get_id_number(), body);
-                self.broadcast(state, &self.entity_buffer_1, msg)?;
+            // clients that have only just been sent the entity need its body whether or not it's time
+            // for everyone else to get it
+            let body_recipients = if is_keyframe {
+                interested_clients.clone()
+            } else {
+                spawns.iter().filter(|(_, entity)| *entity == network_entity).map(|(client_entity, _)| *client_entity).collect()
+            };
+            if !body_recipients.is_empty() {
+                if let Ok(body) = state.get::<Body>(network_entity) {
+                    self.broadcast_body(context, state, &body_recipients, network_entity, body)?;
+                }
             }
-            
-            if let Ok(sprite) = state.get::<Sprite>(network_entity) {
-                let msg = ServerMsg::SetSprite(network_entity.get_id_number(), sprite);
-                self.broadcast(state, &self.entity_buffer_1, msg)?;
+
+            self.send_changes(state, &interested_clients, network_entity)?;
+        }
+
+        Ok(())
+    }
+
+    // spawns the network entities that have come near enough to each client's gorilla to be seen,
+    // and despawns those that have gone far enough away. returns the client and entity of each
+    // spawn, which broadcast_state then sends in full
+    fn update_interests(&self, state: &mut Ecs) -> GgResult<Vec<(EntityId, EntityId)>> {
+        let mut spawns = vec![];
+        for &client_entity in self.entity_buffer_1.iter() {
+            // spectators watch the whole game
+            let centre = match state.borrow::<Body>(client_entity) {
+                Ok(body) => Some(body.get_loc()),
+                Err(_) if state.has::<Spectator>(client_entity)? => None,
+                Err(_) => continue
+            };
+
+            for &entity in self.entity_buffer_2.iter() {
+                let id = entity.get_id_number();
+                let is_spawned = match state.borrow::<Baseline>(client_entity) {
+                    Ok(baseline) => baseline.entities.contains(&id),
+                    Err(_) => break
+                };
+
+                let margin = if is_spawned { DISINTEREST_MARGIN } else { INTEREST_MARGIN };
+                let is_interesting = entity == client_entity || match (centre, state.borrow::<Body>(entity)) {
+                    (Some(centre), Ok(body)) => is_in_view(centre, body.get_loc(), margin),
+                    _ => true
+                };
+
+                if is_interesting && !is_spawned {
+                    state.borrow_mut::<Baseline>(client_entity)?.entities.insert(id);
+                    spawns.push((client_entity, entity));
+                } else if !is_interesting && is_spawned {
+                    state.borrow_mut::<Baseline>(client_entity)?.forget(id);
+                    self.broadcast(state, &[client_entity], ServerMsg::Despawn(id), Delivery::ReliableOrdered)?;
+                }
             }
+        }
+
+        Ok(spawns)
+    }
+
+    // the clients that the entity has been spawned on
+    fn interested_clients(&self, state: &Ecs, entity: EntityId) -> Vec<EntityId> {
+        let id = entity.get_id_number();
+        self.entity_buffer_1.iter()
+            .cloned()
+            .filter(|&client_entity| matches!(state.borrow::<Baseline>(client_entity), Ok(baseline) if baseline.entities.contains(&id)))
+            .collect()
+    }
 
-            if state.has::<Focus>(network_entity).unwrap() {
-                let msg = ServerMsg::SetFocus(network_entity.get_id_number());
-                self.broadcast(state, &self.entity_buffer_1, msg)?;
+    // the owner of a gorilla also needs to know which of its inputs the body reflects, and when
+    // that was by its own clock, so that it can replay any later inputs on top
+    fn broadcast_body<TContext>(&self, context: &TContext, state: &mut Ecs, to: &[EntityId], entity: EntityId, body: Body) -> GgResult where TContext: TimerService {
+        let id = entity.get_id_number();
+        let delivery = Delivery::UnreliableSequenced(id);
+        // an input is only acknowledged once GorillaSystem has applied it to the body being sent
+        let is_input_applied = state.borrow::<Gorilla>(entity).map_or(true, |gorilla| gorilla.input_events.is_empty());
+        let owner_msg = state.borrow::<LastInput>(entity).ok().filter(|_| is_input_applied).map(|last_input| {
+            let time = last_input.client_time + (context.time_since_start() - last_input.server_time);
+            ServerMsg::SetOwnBody(id, body.clone(), InputAck{ seq: last_input.seq, time })
+        });
+
+        match owner_msg {
+            Some(owner_msg) => {
+                let others = to.iter().cloned().filter(|&client_entity| client_entity != entity).collect::<Vec<_>>();
+                self.broadcast(state, &others, ServerMsg::SetBody(id, body, context.time_since_start()), delivery)?;
+                if to.contains(&entity) {
+                    self.broadcast(state, &[entity], owner_msg, delivery)?;
+                }
+                Ok(())
+            },
+            None => self.broadcast(state, to, ServerMsg::SetBody(id, body, context.time_since_start()), delivery)
+        }
+    }
+
+    // sends each client whatever it has not yet been told about an entity's sprite, focus and anchor
+    fn send_changes(&self, state: &mut Ecs, to: &[EntityId], entity: EntityId) -> GgResult {
+        let id = entity.get_id_number();
+        let sprite = state.get::<Sprite>(entity).ok();
+        let is_focus = state.has::<Focus>(entity)?;
+        let is_anchor = state.has::<Anchor>(entity)?;
+
+        for &client_entity in to.iter() {
+            let baseline = match state.borrow_mut::<Baseline>(client_entity) {
+                Ok(baseline) => baseline,
+                Err(_) => continue
+            };
+
+            let mut msgs = vec![];
+            if let Some(sprite) = sprite.as_ref() {
+                let msg = match baseline.sprites.get(&id) {
+                    Some(sent_sprite) => sprite.diff(sent_sprite).map(|delta| ServerMsg::UpdateSprite(id, delta)),
+                    None => Some(ServerMsg::SetSprite(id, sprite.clone()))
+                };
+                if let Some(msg) = msg {
+                    baseline.sprites.insert(id, sprite.clone());
+                    msgs.push(msg);
+                }
+            }
+
+            if is_focus && baseline.focuses.insert(id) {
+                msgs.push(ServerMsg::SetFocus(id));
+            }
+
+            if is_anchor && baseline.anchors.insert(id) {
+                msgs.push(ServerMsg::SetAnchor(id));
+            }
+
+            for msg in msgs {
+                self.broadcast(state, &[client_entity], msg, Delivery::ReliableOrdered)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // remembers where everything is now, so that a lagging player's input can later be resolved
+    // against the world as they saw it
+    fn record_history<TContext>(&mut self, context: &TContext, state: &mut Ecs) -> GgResult where TContext: TimerService {
+        // without pings there is no knowing how far to look back
+        if self.next_ping_time.is_none() {
+            return Ok(());
+        }
+
+        let time = context.time_since_start();
+        self.entity_buffer_2.clear();
+        state.collect_with(&component_filter!(Network, Body), &mut self.entity_buffer_2);
+        for &entity in self.entity_buffer_2.iter() {
+            let body = state.get::<Body>(entity)?;
+            if !state.has::<History>(entity)? {
+                state.set(entity, History::new())?;
             }
+            state.borrow_mut::<History>(entity)?.record(time, body);
         }
 
         Ok(())
     }
 
-    fn measure_client_latencies<TContext>(&mut self, context: &TContext, state: &mut Ecs) -> GgResult where TContext: TimerService {
+    fn ping_clients<TContext>(&mut self, context: &TContext, state: &mut Ecs) -> GgResult where TContext: TimerService {
 
-        if let Some(next_latency_measurement_time) = self.next_latency_measurement_time {
+        if let Some(next_ping_time) = self.next_ping_time {
             let time = context.time_since_start();
-            if time < next_latency_measurement_time {
+            if time < next_ping_time {
                 return Ok(())
             }
-            self.next_latency_measurement_time = Some(time + LATENCY_MEASUREMENT_PERIOD);
+            self.next_ping_time = Some(time + CLOCK_SYNC_PERIOD);
     
-            let ping_msg = Ping(time);    
+            let ping_msg = ServerMsg::Ping(time);    
     
             self.entity_buffer_2.clear();
             state.collect_with(&component_filter!(Client<TNetwork>), &mut self.entity_buffer_2); 
             for &network_entity in self.entity_buffer_2.iter() {
                 let network_component: &mut Client<TNetwork> = state.borrow_mut(network_entity)?;
-                network_component.0.enqueue(ping_msg.clone())?;
+                network_component.0.enqueue(ping_msg.clone(), Delivery::UnreliableSequenced(CLOCK_PING_STREAM))?;
             }
         }
 
         Ok(())
     }
 
-    fn broadcast(&self, state: &mut Ecs, to: &[EntityId], msg: ServerMsg) -> GgResult {
+    fn log_stats<TContext>(&mut self, context: &TContext, state: &mut Ecs) where TContext: TimerService {
+        let time = context.time_since_start();
+        if time < self.next_stats_time {
+            return;
+        }
+        self.next_stats_time = time + STATS_LOG_PERIOD;
+
+        self.entity_buffer_2.clear();
+        state.collect_with(&component_filter!(Client<TNetwork>), &mut self.entity_buffer_2);
+        for &client_entity in self.entity_buffer_2.iter() {
+            let stats = state.borrow::<Client<TNetwork>>(client_entity).unwrap().0.stats();
+            println!("client #{}: {}", client_entity.get_id_number(), stats);
+        }
+    }
+
+    // parked gorillas count until they are released, so that a room whose players have all dropped
+    // out at once stays open for them to come back to until the grace period is up
+    fn report_client_count(&mut self, state: &Ecs) {
+        self.entity_buffer_1.clear();
+        state.collect_with(&component_filter!(Client<TNetwork>), &mut self.entity_buffer_1);
+        self.entity_buffer_2.clear();
+        state.collect_with(&component_filter!(Parked), &mut self.entity_buffer_2);
+        self.entity_buffer_2.retain(|&entity| !state.has::<Dead>(entity).unwrap());
+        let client_count = self.pending_clients.len() + self.queued_clients.len() + self.entity_buffer_1.len() + self.entity_buffer_2.len();
+        self.server.set_client_count(client_count);
+    }
+
+    fn release_parked_clients<TContext>(&mut self, context: &TContext, state: &mut Ecs) where TContext: TimerService {
+        let time = context.time_since_start();
+        self.entity_buffer_2.clear();
+        state.collect_with(&component_filter!(Parked), &mut self.entity_buffer_2);
+        for &parked_entity in self.entity_buffer_2.iter() {
+            if time - state.borrow::<Parked>(parked_entity).unwrap().0 > RECONNECT_GRACE_PERIOD {
+                state.set(parked_entity, Dead{}).unwrap();
+                println!("client #{} did not reconnect in time", parked_entity.get_id_number());
+            }
+        }
+    }
+
+    fn find_parked_entity(&mut self, state: &Ecs, session_token: u64) -> Option<EntityId> {
+        self.entity_buffer_2.clear();
+        state.collect_with(&component_filter!(Parked, Session), &mut self.entity_buffer_2);
+        self.entity_buffer_2
+            .iter()
+            .find(|&&entity| state.borrow::<Session>(entity).unwrap().0 == session_token)
+            .cloned()
+    }
+
+    fn send_keepalives<TContext>(&mut self, context: &TContext, state: &mut Ecs) -> GgResult where TContext: TimerService {
+        let time = context.time_since_start();
+        if time < self.next_keepalive_time {
+            return Ok(())
+        }
+        self.next_keepalive_time = time + KEEPALIVE_PERIOD;
+
+        for queued_client in self.queued_clients.iter_mut() {
+            let _ = queued_client.network.enqueue(ServerMsg::Heartbeat, Delivery::ReliableOrdered);
+        }
+
+        self.entity_buffer_2.clear();
+        state.collect_with(&component_filter!(Client<TNetwork>), &mut self.entity_buffer_2);
+        self.broadcast(state, &self.entity_buffer_2, ServerMsg::Heartbeat, Delivery::ReliableOrdered)
+    }
+
+    fn broadcast(&self, state: &mut Ecs, to: &[EntityId], msg: ServerMsg, delivery: Delivery) -> GgResult {
         for &client_entity in to.iter() {
             if let Ok(client_component) = state.borrow_mut::<Client<TNetwork>>(client_entity){
-                if client_component.0.enqueue(msg.clone()).is_err() {
-                    self.disconnect_client(state, client_entity);
-                }
+                // a closed connection also fails to dequeue, and the client is parked when that happens
+                let _ = client_component.0.enqueue(msg.clone(), delivery);
             }
         }
 
         Ok(())
     }
 
-    fn disconnect_client(&self, state: &mut Ecs, entity: EntityId) {
+    // everything sent to a client during a tick goes out together
+    fn flush_clients(&mut self, state: &mut Ecs) {
+        for pending_client in self.pending_clients.iter_mut() {
+            let _ = pending_client.network.flush();
+        }
+        for queued_client in self.queued_clients.iter_mut() {
+            let _ = queued_client.network.flush();
+        }
+
+        self.entity_buffer_1.clear();
+        state.collect_with(&component_filter!(Client<TNetwork>), &mut self.entity_buffer_1);
+        for &client_entity in self.entity_buffer_1.iter() {
+            // as with broadcast, a closed connection is dealt with when it next fails to dequeue
+            let _ = state.borrow_mut::<Client<TNetwork>>(client_entity).unwrap().0.flush();
+        }
+    }
+
+    // there is nothing to hold for a spectator that drops out
+    fn drop_client(state: &mut Ecs, entity: EntityId, time: Duration, reason: &str) {
+        if state.has::<Spectator>(entity).unwrap() {
+            Self::disconnect_client(state, entity, reason);
+        } else {
+            Self::park_client(state, entity, time, reason);
+        }
+    }
+
+    fn park_client(state: &mut Ecs, entity: EntityId, time: Duration, reason: &str) {
+        state.set(entity, Parked(time)).unwrap();
+        let client = state.unset::<Client::<TNetwork>>(entity).unwrap();
+        println!("client #{} has disconnected: {}, holding its gorilla for {:?}", entity.get_id_number(), reason, RECONNECT_GRACE_PERIOD);
+        println!("client #{}: {}", entity.get_id_number(), client.0.stats());
+    }
+
+    // a rejected client doesn't try to reconnect
+    fn kick_client(state: &mut Ecs, entity: EntityId, reason: &str) {
+        let client = state.borrow_mut::<Client::<TNetwork>>(entity).unwrap();
+        let _ = client.0.enqueue(ServerMsg::Reject(reason.to_string()), Delivery::ReliableOrdered);
+        Self::disconnect_client(state, entity, &format!("kicked for {}", reason));
+    }
+
+    fn disconnect_client(state: &mut Ecs, entity: EntityId, reason: &str) {
         state.set(entity, Dead{}).unwrap();
-        state.unset::<Client::<TNetwork>>(entity).unwrap();
-        println!("client #{} has disconnected", entity.get_id_number());
+        let client = state.unset::<Client::<TNetwork>>(entity).unwrap();
+        println!("client #{} has disconnected: {}", entity.get_id_number(), reason);
+        println!("client #{}: {}", entity.get_id_number(), client.0.stats());
+    }
+}
+
+fn check_hello(hello: &Hello) -> Result<Vec<String>, String> {
+    if hello.version != PROTOCOL_VERSION {
+        return Err(format!("client speaks protocol version {} but server speaks version {}", hello.version, PROTOCOL_VERSION));
     }
+
+    let capabilities = hello.capabilities
+        .iter()
+        .filter(|c| CAPABILITIES.contains(&c.as_str()))
+        .cloned()
+        .collect();
+
+    Ok(capabilities)
+}
+
+fn new_session_token() -> u64 {
+    // each RandomState is seeded with fresh randomness from the OS
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos());
+    hasher.finish()
 }
 
 impl<TServer, TNetwork, TContext> System<TContext> for ServerSystem<TServer, TNetwork>  
@@ -203,22 +772,528 @@ impl<TServer, TNetwork, TContext> System<TContext> for ServerSystem<TServer, TNe
     }
 
     fn update(&mut self, state: &mut Ecs, context: &TContext) -> GgResult {
-        self.process_new_clients(state)?;
+        self.process_new_clients(context);
+        self.process_handshakes(context, state)?;
         self.process_client_msgs(context, state)?;
-        self.broadcast_state(state)?;
-        self.measure_client_latencies(context, state)?;
+        self.release_parked_clients(context, state);
+        self.process_queue(context, state)?;
+        self.record_history(context, state)?;
+        self.update_spectators(state)?;
+        self.broadcast_state(context, state)?;
+        self.ping_clients(context, state)?;
+        self.send_keepalives(context, state)?;
+        self.flush_clients(state);
+        self.log_stats(context, state);
+        self.report_client_count(state);
+
+        Ok(())
+    }
+
+    fn shutdown(&mut self, state: &mut Ecs, _: &TContext, reason: &str) -> GgResult {
+        let msg = ServerMsg::Shutdown{ reason: reason.to_string() };
+
+        // the process is about to exit, so each connection is closed rather than just dropped, to give
+        // whatever is still queued on it a chance to go
+        for mut pending_client in self.pending_clients.drain(..) {
+            let _ = pending_client.network.enqueue(msg.clone(), Delivery::ReliableOrdered);
+            pending_client.network.close();
+        }
+        for mut queued_client in self.queued_clients.drain(..) {
+            let _ = queued_client.network.enqueue(msg.clone(), Delivery::ReliableOrdered);
+            queued_client.network.close();
+        }
+
+        self.entity_buffer_1.clear();
+        state.collect_with(&component_filter!(Client<TNetwork>), &mut self.entity_buffer_1);
+        for &client_entity in self.entity_buffer_1.iter() {
+            let mut client = state.unset::<Client<TNetwork>>(client_entity)?;
+            let _ = client.0.enqueue(msg.clone(), Delivery::ReliableOrdered);
+            client.0.close();
+        }
+
+        println!("server has shut down: {}", reason);
 
         Ok(())
     }
 
     fn teardown_entity(&mut self, entity: EntityId, state: &mut Ecs, _: &TContext) -> GgResult {
         if state.has::<Network>(entity).unwrap() {
-            let msg = ServerMsg::Kill(entity.get_id_number());
+            // only the clients that have the entity need telling
+            let id = entity.get_id_number();
             self.entity_buffer_1.clear();
             state.collect_with(&component_filter!(Client<TNetwork>), &mut self.entity_buffer_1);
-            self.broadcast(state, &self.entity_buffer_1, msg)?;
+            let interested_clients = self.interested_clients(state, entity);
+            self.broadcast(state, &interested_clients, ServerMsg::Kill(id), Delivery::ReliableOrdered)?;
+            self.flush_clients(state);
+
+            // the id may be reused for a new entity, which must be sent in full
+            self.entity_buffer_2.clear();
+            state.collect_with(&component_filter!(Baseline), &mut self.entity_buffer_2);
+            for &client_entity in self.entity_buffer_2.iter() {
+                state.borrow_mut::<Baseline>(client_entity)?.forget(id);
+            }
         }
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+// whether the entity is a gorilla still in the game, though its player may have dropped out
+fn is_playing(state: &Ecs, entity: EntityId) -> bool {
+    state.has::<Gorilla>(entity).unwrap_or(false) && !state.has::<Dead>(entity).unwrap_or(true)
+}
+
+// whether a location is on the screen of a player whose gorilla is at the centre, or within the
+// given margin of it
+fn is_in_view(centre: Vector2<f32>, loc: Vector2<f32>, margin: f32) -> bool {
+    (loc.x - centre.x).abs() <= VIEW_SIZE[0] / 2.0 + margin && (loc.y - centre.y).abs() <= VIEW_SIZE[1] / 2.0 + margin
+}
+
+#[test]
+fn test_handshake() {
+    let mut harness = crate::testing::ServerHarness::new(Duration::from_millis(16));
+    let mut good_client = harness.join(Hello::new(None));
+    let mut bad_client = harness.join(Hello{ version: PROTOCOL_VERSION + 1, ..Hello::new(None) });
+    harness.update();
+
+    // only the client that speaks the same protocol gets a gorilla
+    let mut gorillas = vec![];
+    harness.state.collect_with(&component_filter!(Gorilla), &mut gorillas);
+    assert_eq!(1, gorillas.len());
+
+    let msgs = receive(&mut good_client);
+    match &msgs[0] {
+        ServerMsg::Welcome(welcome) => assert_eq!(Codec::CompressedBincode, Codec::negotiate(&welcome.capabilities)),
+        msg => panic!("expected Welcome but got {:?}", msg)
+    }
+    assert_eq!(ServerMsg::SetFocus(gorillas[0].get_id_number()), msgs[1]);
+
+    let msgs = receive(&mut bad_client);
+    assert_eq!(1, msgs.len());
+    match &msgs[0] {
+        ServerMsg::Reject(reason) => assert!(reason.contains("protocol version")),
+        msg => panic!("expected Reject but got {:?}", msg)
+    }
+}
+
+#[test]
+fn test_goodbye_and_shutdown() {
+    let mut harness = crate::testing::ServerHarness::new(Duration::from_millis(16));
+    let mut leaving_client = harness.join(Hello::new(None));
+    let mut staying_client = harness.join(Hello::new(None));
+    harness.update();
+
+    leaving_client.enqueue(ClientMsg::Goodbye, Delivery::ReliableOrdered).unwrap();
+    harness.update();
+
+    // the client that said goodbye is marked for teardown
+    let mut dead = vec![];
+    harness.state.collect_with(&component_filter!(Dead), &mut dead);
+    assert_eq!(1, dead.len());
+
+    receive(&mut staying_client);
+    harness.subject.shutdown(&mut harness.state, &harness.context, "maintenance").unwrap();
+    assert_eq!(vec![ServerMsg::Shutdown{ reason: "maintenance".to_string() }], receive(&mut staying_client));
+
+    let mut clients = vec![];
+    harness.state.collect_with(&component_filter!(Client<SimNetworkEnd<ServerMsg, ClientMsg>>), &mut clients);
+    assert_eq!(0, clients.len());
+}
+
+#[test]
+fn test_connection_timeout() {
+    let mut harness = crate::testing::ServerHarness::with_options(Duration::from_millis(500), Duration::from_secs(3), false);
+    let mut silent_client = harness.join(Hello::new(None));
+    let mut chatty_client = harness.join(Hello::new(None));
+    harness.update();
+    for _ in 0..7 {
+        chatty_client.enqueue(ClientMsg::Heartbeat, Delivery::ReliableOrdered).unwrap();
+        harness.step();
+    }
+
+    // only the client that went quiet is disconnected, and its gorilla is held for it
+    let mut parked = vec![];
+    harness.state.collect_with(&component_filter!(Parked), &mut parked);
+    assert_eq!(1, parked.len());
+    assert!(!harness.state.has::<Client<SimNetworkEnd<ServerMsg, ClientMsg>>>(parked[0]).unwrap());
+
+    // and both were sent keepalives while they were connected
+    assert!(receive(&mut silent_client).contains(&ServerMsg::Heartbeat));
+}
+
+#[test]
+fn test_client_count() {
+    let context = crate::testing::MockContext::new(Duration::from_millis(500));
+    let mut server = crate::network::sim::SimServer::new(Duration::from_millis(0), context.network_time());
+    let door = crate::network::lobby::Door::default();
+    let mut harness = crate::testing::ServerHarness::with_server(context, door.clone(), Duration::from_secs(3), false);
+    let mut join = |hello: Hello| {
+        let mut client = server.connect();
+        let mut new_clients = vec![];
+        server.get_new_clients(&mut new_clients);
+        door.admit(new_clients.pop().unwrap());
+        client.enqueue(ClientMsg::Hello(hello), Delivery::ReliableOrdered).unwrap();
+        client
+    };
+
+    let mut client = join(Hello::new(None));
+    harness.update();
+    assert_eq!(1, door.client_count());
+    let (session_token, gorilla) = welcome(&mut client);
+
+    // the room's only player drops out, but it stays open while their gorilla is held for them
+    for _ in 0..8 {
+        harness.step();
+    }
+    let mut parked = vec![];
+    harness.state.collect_with(&component_filter!(Parked), &mut parked);
+    assert_eq!(1, parked.len());
+    assert_eq!(1, door.client_count());
+
+    // so that they get the same gorilla back on reconnecting
+    let mut client = join(Hello::new(Some(session_token)));
+    harness.step();
+    assert_eq!(ServerMsg::SetFocus(gorilla), receive(&mut client)[1]);
+    assert_eq!(1, door.client_count());
+
+    // it is only once the grace period is up that the room has no one left
+    for _ in 0..8 + 2 * RECONNECT_GRACE_PERIOD.as_secs() + 2 {
+        harness.step();
+    }
+    assert!(door.is_empty());
+}
+
+#[test]
+fn test_reconnect() {
+    let mut harness = crate::testing::ServerHarness::with_options(Duration::from_millis(500), Duration::from_secs(3), false);
+    let mut first_connection = harness.join(Hello::new(None));
+    harness.update();
+    let (session_token, gorilla) = welcome(&mut first_connection);
+
+    // the connection goes quiet until the client is parked
+    let mut parked = vec![];
+    while parked.is_empty() {
+        harness.step();
+        harness.state.collect_with(&component_filter!(Parked), &mut parked);
+    }
+
+    // reconnecting with the token gets the same gorilla back
+    let mut second_connection = harness.join(Hello::new(Some(session_token)));
+    harness.update();
+
+    let msgs = receive(&mut second_connection);
+    assert_eq!(ServerMsg::Welcome(Welcome{ capabilities: Hello::new(None).capabilities, session_token }), msgs[0]);
+    assert_eq!(ServerMsg::SetFocus(gorilla), msgs[1]);
+    assert!(!harness.state.has::<Parked>(parked[0]).unwrap());
+
+    let mut gorillas = vec![];
+    harness.state.collect_with(&component_filter!(Gorilla), &mut gorillas);
+    assert_eq!(1, gorillas.len());
+
+    // a client that stays away longer than the grace period loses its gorilla
+    let _third_connection = harness.join(Hello::new(None));
+    for _ in 0..80 {
+        second_connection.enqueue(ClientMsg::Heartbeat, Delivery::ReliableOrdered).unwrap();
+        harness.step();
+    }
+
+    let mut dead = vec![];
+    harness.state.collect_with(&component_filter!(Dead), &mut dead);
+    assert_eq!(1, dead.len());
+    assert!(harness.state.has::<Parked>(dead[0]).unwrap());
+    assert_ne!(gorillas[0], dead[0]);
+}
+
+#[test]
+fn test_input_ack() {
+    let mut harness = crate::testing::ServerHarness::new(Duration::from_millis(100));
+    let mut player = harness.join(Hello::new(None));
+    let mut watcher = harness.join(Hello::new(None));
+    harness.update();
+
+    let (_, gorilla) = welcome(&mut player);
+    receive(&mut watcher);
+
+    // the input arrives 100ms after the client sent it, by the client's clock
+    let input_event = crate::input::InputEvent{ button: crate::input::Button::Two, is_down: true, seq: 7, time: Duration::from_millis(50) };
+    player.enqueue(ClientMsg::Input(input_event), Delivery::ReliableOrdered).unwrap();
+    harness.step();
+
+    // and the next keyframe of the player's gorilla goes out 300ms after that
+    for _ in 0..3 {
+        let mut gorillas = vec![];
+        harness.state.collect_with(&component_filter!(Gorilla), &mut gorillas);
+        // standing in for PhysicsSystem and GorillaSystem
+        for &entity in gorillas.iter() {
+            harness.state.borrow_mut::<Body>(entity).unwrap().step(0.1);
+            harness.state.borrow_mut::<Gorilla>(entity).unwrap().input_events.clear();
+        }
+        harness.step();
+    }
+
+    let acks = receive(&mut player).into_iter().filter_map(|msg| match msg {
+        ServerMsg::SetOwnBody(id, _, ack) if id == gorilla => Some(ack),
+        _ => None
+    }).collect::<Vec<_>>();
+    assert_eq!(vec![InputAck{ seq: 7, time: Duration::from_millis(350) }], acks);
+
+    // other clients just get the body
+    let msgs = receive(&mut watcher);
+    assert!(msgs.iter().any(|msg| match msg { ServerMsg::SetBody(id, _, _) => *id == gorilla, _ => false }));
+    assert!(!msgs.iter().any(|msg| matches!(msg, ServerMsg::SetOwnBody(_, _, _))));
+}
+
+#[test]
+fn test_delta_compression() {
+    let mut harness = crate::testing::ServerHarness::new(Duration::from_millis(100));
+    let mut client = harness.join(Hello::new(None));
+    harness.update();
+
+    // the first time round everything is sent in full
+    let count = |msgs: &Vec<ServerMsg>, predicate: &dyn Fn(&ServerMsg) -> bool| msgs.iter().filter(|&msg| predicate(msg)).count();
+    let msgs = receive(&mut client);
+    assert_eq!(6, count(&msgs, &|msg| matches!(msg, ServerMsg::SetSprite(_, _))));
+    assert_eq!(5, count(&msgs, &|msg| matches!(msg, ServerMsg::SetAnchor(_))));
+
+    // after which nothing that has not changed is sent again
+    for _ in 0..5 {
+        client.enqueue(ClientMsg::Heartbeat, Delivery::ReliableOrdered).unwrap();
+        harness.step();
+    }
+    let msgs = receive(&mut client);
+    assert_eq!(0, count(&msgs, &|msg| matches!(msg, ServerMsg::SetSprite(_, _) | ServerMsg::UpdateSprite(_, _) | ServerMsg::SetAnchor(_) | ServerMsg::SetFocus(_))));
+
+    // and a change only carries the fields that differ
+    let mut gorillas = vec![];
+    harness.state.collect_with(&component_filter!(Gorilla), &mut gorillas);
+    harness.state.borrow_mut::<Sprite>(gorillas[0]).unwrap().src_loc = [0.0, 0.5].into();
+    harness.step();
+    let msgs = receive(&mut client);
+    let delta = crate::component::sprite::SpriteDelta{ color: None, size: None, src_loc: Some([0.0, 0.5].into()), src_size: None };
+    assert_eq!(1, count(&msgs, &|msg| *msg == ServerMsg::UpdateSprite(gorillas[0].get_id_number(), delta.clone())));
+    assert_eq!(0, count(&msgs, &|msg| matches!(msg, ServerMsg::SetSprite(_, _))));
+}
+
+#[test]
+fn test_player_limit() {
+    let mut harness = crate::testing::ServerHarness::new(Duration::from_millis(100));
+    harness.subject.set_player_limit(PlayerLimit{ max_players: 1, max_queue_length: 2 });
+    let mut clients = (0..4).map(|_| harness.join(Hello::new(None))).collect::<Vec<_>>();
+    let is_welcome = |msg: &ServerMsg| matches!(msg, ServerMsg::Welcome(_));
+
+    // the first client plays, the next two wait their turn and the last is turned away
+    harness.update();
+    let msgs = clients.iter_mut().map(receive).collect::<Vec<_>>();
+    assert!(msgs[0].iter().any(is_welcome));
+    assert!(msgs[1].contains(&ServerMsg::Queued(1)));
+    assert!(msgs[2].contains(&ServerMsg::Queued(2)));
+    assert_eq!(vec![ServerMsg::Reject("the server is full".to_string())], msgs[3]);
+
+    // when the player leaves the first in the queue takes their place and the other moves up
+    clients[0].enqueue(ClientMsg::Goodbye, Delivery::ReliableOrdered).unwrap();
+    harness.step();
+    let msgs = clients.iter_mut().map(receive).collect::<Vec<_>>();
+    assert!(msgs[1].iter().any(is_welcome));
+    assert!(msgs[2].contains(&ServerMsg::Queued(1)));
+
+    // and a queued client that gives up makes no difference to anyone else
+    clients[2].enqueue(ClientMsg::Goodbye, Delivery::ReliableOrdered).unwrap();
+    harness.step();
+    assert!(harness.subject.queued_clients.is_empty());
+    let mut players = vec![];
+    harness.state.collect_with(&component_filter!(Session), &mut players);
+    players.retain(|&entity| !harness.state.has::<Dead>(entity).unwrap());
+    assert_eq!(1, players.len());
+}
+
+#[test]
+fn test_spectator() {
+    let mut harness = crate::testing::ServerHarness::new(Duration::from_millis(100));
+    harness.subject.set_player_limit(PlayerLimit{ max_players: 2, max_queue_length: 0 });
+    let far_anchor = spawn_anchor(&mut harness.state, [100.0, 0.0].into()).unwrap();
+    let mut spectator = harness.join(Hello{ is_spectator: true, ..Hello::new(None) });
+
+    // a spectator has no gorilla and sees the whole world, but has no one to follow yet
+    harness.update();
+    let msgs = receive(&mut spectator);
+    assert!(msgs.iter().any(|msg| matches!(msg, ServerMsg::Welcome(_))));
+    assert!(msgs.contains(&ServerMsg::SetAnchor(far_anchor.get_id_number())));
+    assert!(!msgs.iter().any(|msg| matches!(msg, ServerMsg::SetFocus(_))));
+    let mut gorillas = vec![];
+    harness.state.collect_with(&component_filter!(Gorilla), &mut gorillas);
+    assert!(gorillas.is_empty());
+
+    // nor does it take a player's slot
+    let mut players = (0..2).map(|_| harness.join(Hello::new(None))).collect::<Vec<_>>();
+    harness.step();
+    let msgs = receive(&mut spectator);
+    harness.state.collect_with(&component_filter!(Gorilla), &mut gorillas);
+    gorillas.sort_by_key(|entity| entity.get_id_number());
+    assert_eq!(2, gorillas.len());
+
+    // once there are players it follows the first of them
+    let focus = |id: EntityId| ServerMsg::SetFocus(id.get_id_number());
+    assert!(msgs.contains(&focus(gorillas[0])));
+
+    // and cycles through them on request, while anything it sends as input is ignored
+    let input_event = InputEvent{ button: Button::One, is_down: true, seq: 1, time: Duration::from_millis(0) };
+    spectator.enqueue(ClientMsg::Input(input_event), Delivery::ReliableOrdered).unwrap();
+    let mut cycle_focus = |forward: bool| {
+        spectator.enqueue(ClientMsg::CycleFocus{ forward }, Delivery::ReliableOrdered).unwrap();
+        harness.step();
+        receive(&mut spectator)
+    };
+    assert!(cycle_focus(true).contains(&focus(gorillas[1])));
+    assert!(cycle_focus(true).contains(&focus(gorillas[0])));
+    assert!(cycle_focus(false).contains(&focus(gorillas[1])));
+    assert!(gorillas.iter().all(|&gorilla| harness.state.borrow::<Gorilla>(gorilla).unwrap().input_events.is_empty()));
+
+    // when the player it follows leaves it moves straight on to another
+    players[1].enqueue(ClientMsg::Goodbye, Delivery::ReliableOrdered).unwrap();
+    harness.step();
+    assert!(receive(&mut spectator).contains(&focus(gorillas[0])));
+}
+
+#[test]
+fn test_input_validation() {
+    let mut harness = crate::testing::ServerHarness::new(Duration::from_millis(100));
+    let mut client = harness.join(Hello::new(None));
+    harness.update();
+    let mut gorillas = vec![];
+    harness.state.collect_with(&component_filter!(Gorilla), &mut gorillas);
+    let input = |button: Button, is_down: bool, seq: u32| ClientMsg::Input(InputEvent{ button, is_down, seq, time: Duration::from_millis(0) });
+
+    // an invalid input is dropped with a warning, and the valid ones around it still count
+    client.enqueue(input(Button::One, false, 1), Delivery::ReliableOrdered).unwrap();
+    client.enqueue(input(Button::Two, true, 2), Delivery::ReliableOrdered).unwrap();
+    harness.update();
+    let input_events = &harness.state.borrow::<Gorilla>(gorillas[0]).unwrap().input_events;
+    assert_eq!(1, input_events.len());
+    assert_eq!(2, input_events[0].seq);
+
+    // while a flood gets the client kicked
+    for seq in 3..1000 {
+        client.enqueue(input(Button::One, seq % 2 == 1, seq), Delivery::ReliableOrdered).unwrap();
+    }
+    harness.update();
+    assert!(harness.state.has::<Dead>(gorillas[0]).unwrap());
+    assert!(!harness.state.has::<Client<SimNetworkEnd<ServerMsg, ClientMsg>>>(gorillas[0]).unwrap());
+    assert!(receive(&mut client).contains(&ServerMsg::Reject("too many invalid inputs".to_string())));
+}
+
+#[test]
+fn test_interest_management() {
+    let mut harness = crate::testing::ServerHarness::new(Duration::from_millis(100));
+    let far_anchor = spawn_anchor(&mut harness.state, [100.0, 0.0].into()).unwrap();
+    let doomed_anchor = spawn_anchor(&mut harness.state, [-100.0, 0.0].into()).unwrap();
+    let mut client = harness.join(Hello::new(None));
+    harness.update();
+
+    // entities out of sight are not sent
+    let msgs = receive(&mut client);
+    let far_id = far_anchor.get_id_number();
+    assert_eq!(5, msgs.iter().filter(|msg| matches!(msg, ServerMsg::SetAnchor(_))).count());
+    assert!(!msgs.iter().any(|msg| matches!(msg, ServerMsg::SetSprite(id, _) if *id == far_id)));
+
+    let step = |harness: &mut crate::testing::ServerHarness<_>, client: &mut SimNetworkEnd<ClientMsg, ServerMsg>| {
+        client.enqueue(ClientMsg::Heartbeat, Delivery::ReliableOrdered).unwrap();
+        harness.step();
+        receive(client)
+    };
+
+    // until the player comes near them, when they are sent in full
+    let mut gorillas = vec![];
+    harness.state.collect_with(&component_filter!(Gorilla), &mut gorillas);
+    harness.state.set(gorillas[0], Body::new_static([95.0, 0.0].into())).unwrap();
+    let msgs = step(&mut harness, &mut client);
+    assert!(msgs.contains(&ServerMsg::SetAnchor(far_id)));
+    assert!(msgs.iter().any(|msg| matches!(msg, ServerMsg::SetSprite(id, _) if *id == far_id)));
+    assert!(msgs.iter().any(|msg| matches!(msg, ServerMsg::Despawn(_))));
+
+    // and despawned, rather than killed, once the player has gone well away again
+    harness.state.set(gorillas[0], Body::new_static([0.0, 0.0].into())).unwrap();
+    let msgs = step(&mut harness, &mut client);
+    assert!(msgs.contains(&ServerMsg::Despawn(far_id)));
+    assert!(!msgs.iter().any(|msg| matches!(msg, ServerMsg::Kill(_))));
+
+    // destroying an entity the client never had tells it nothing
+    harness.subject.teardown_entity(doomed_anchor, &mut harness.state, &harness.context).unwrap();
+    harness.state.destroy_entity(doomed_anchor).unwrap();
+    let msgs = step(&mut harness, &mut client);
+    assert!(!msgs.iter().any(|msg| matches!(msg, ServerMsg::Kill(_) | ServerMsg::Despawn(_))));
+}
+
+#[test]
+fn test_bandwidth_budget() {
+    let mut harness = crate::testing::ServerHarness::with_options(Duration::from_millis(20), crate::network::DEFAULT_CONNECTION_TIMEOUT, true);
+    let mut client = harness.join(Hello::new(None));
+    let mut run = |seconds: u32| {
+        for _ in 0..seconds * 50 {
+            harness.update();
+            harness.context.step();
+            receive(&mut client);
+        }
+        client.stats()
+    };
+
+    // joining costs a burst for the initial state
+    let joined = run(1);
+    assert_eq!(6, joined.received.by_kind["SetSprite"].msgs);
+    assert!(joined.received.bytes < 2_000, "{}", joined);
+
+    // after which a quiet game costs little more than keeping the connection alive
+    let idle = run(10);
+    let bytes_per_second = (idle.received.bytes - joined.received.bytes) / 10;
+    assert!(bytes_per_second < 250, "{}", idle);
+    assert_eq!(joined.received.by_kind["SetSprite"], idle.received.by_kind["SetSprite"]);
+}
+
+#[test]
+fn test_clock_sync() {
+    let link = crate::network::sim::LinkConditions{
+        uplink: crate::network::sim::NetworkConditions::ideal(Duration::from_millis(30)),
+        downlink: crate::network::sim::NetworkConditions::ideal(Duration::from_millis(70))
+    };
+    let mut setup = crate::testing::MockSetup::with_link(link, Duration::from_millis(10), true);
+    for _ in 0..1000 {
+        setup.step();
+    }
+
+    // the server knows each client's round trip, give or take the tick on either end
+    let mut clients = vec![];
+    setup.server_engine.get_state().collect_with(&component_filter!(ClockSync), &mut clients);
+    assert_eq!(1, clients.len());
+    let clock_sync = setup.server_engine.get_state().borrow::<ClockSync>(clients[0]).unwrap();
+    let rtt = clock_sync.rtt().unwrap();
+    assert!(rtt >= Duration::from_millis(100) && rtt <= Duration::from_millis(120), "{:?}", rtt);
+    assert!(clock_sync.jitter() < Duration::from_millis(10));
+
+    // both ends share a clock here, so the only error is half the difference between the uplink and
+    // downlink latency, which can't be told apart from a difference between the clocks
+    let time = setup.context.time_since_start;
+    let client_time = clock_sync.remote_time(time).unwrap();
+    assert!(client_time >= time && client_time <= time + Duration::from_millis(30), "{:?} vs {:?}", client_time, time);
+}
+
+#[test]
+fn test_interpolation_delay() {
+    let mut harness = crate::testing::ServerHarness::new(Duration::from_millis(16));
+    let mut client = harness.join(Hello::new(None));
+    harness.update();
+    client.enqueue(ClientMsg::SetInterpolationDelay(Duration::MAX), Delivery::ReliableOrdered).unwrap();
+    harness.update();
+
+    // a client can't ask to be judged by a world older than the server remembers
+    let mut clients = vec![];
+    harness.state.collect_with(&component_filter!(InterpolationDelay), &mut clients);
+    assert_eq!(HISTORY_LENGTH, harness.state.borrow::<InterpolationDelay>(clients[0]).unwrap().0);
+}
+
+// the session token and gorilla a newly connected client was welcomed with
+#[cfg(test)]
+fn welcome(client: &mut SimNetworkEnd<ClientMsg, ServerMsg>) -> (u64, u64) {
+    match &receive(client)[..] {
+        [ServerMsg::Welcome(welcome), ServerMsg::SetFocus(gorilla), ..] => (welcome.session_token, *gorilla),
+        msgs => panic!("expected Welcome and SetFocus but got {:?}", msgs)
+    }
+}
@@ -3,6 +3,11 @@ use std::rc::Rc;
 use std::time::Duration;
 use crate::context::TimerService;
 use crate::engine::Engine;
+use crate::network::{Server, TxChannel, RxChannel, ServerMsg, ClientMsg, Hello, Delivery};
+use crate::network::sim::{SimServer, SimNetworkEnd};
+use crate::system::System;
+use crate::system::server::ServerSystem;
+use recs::Ecs;
 
 pub struct MockContext{
     pub average_delta: Duration,
@@ -34,7 +39,7 @@ impl MockContext {
         self.network_time().set(self.time_since_start());
     }
 
-    fn network_time(&self) -> Rc::<Cell::<Duration>> {
+    pub fn network_time(&self) -> Rc::<Cell::<Duration>> {
         Rc::clone(&self.network_time)
     }
 }
@@ -43,39 +48,54 @@ pub struct MockSetup{
     pub context: MockContext,
     pub server_engine: Engine<MockContext>,
     pub client1_engine: Engine<MockContext>,
+    pub client1_link: crate::network::sim::SimLink,
 }
 
 impl MockSetup{
     pub fn new(network_latency: Duration, event_loop_period: Duration, is_latency_compensation_enabled: bool) -> MockSetup{
+        let link_conditions = crate::network::sim::LinkConditions::symmetric(crate::network::sim::NetworkConditions::ideal(network_latency));
+        MockSetup::with_link(link_conditions, event_loop_period, is_latency_compensation_enabled)
+    }
+
+    pub fn with_link(link_conditions: crate::network::sim::LinkConditions, event_loop_period: Duration, is_latency_compensation_enabled: bool) -> MockSetup{
         let mut context = MockContext::new(event_loop_period);
 
-        let mut server = crate::network::sim::SimServer::new(network_latency, context.network_time());
-        let client1_network = server.connect();
+        let mut server = crate::network::sim::SimServer::new(Duration::from_millis(0), context.network_time());
+        let (client1_network, client1_link) = server.connect_with(link_conditions);
     
         let server_engine: Engine::<MockContext> = crate::engine::Engine::new(vec![
             Box::new(crate::system::physics::PhysicsSystem{}),
-            Box::new(crate::system::gorilla::GorillaSystem{is_latency_compensation_enabled}),
-            Box::new(crate::system::server::ServerSystem::new(server, is_latency_compensation_enabled).unwrap())
+            Box::new(crate::system::gorilla::GorillaSystem{}),
+            Box::new(crate::system::server::ServerSystem::new(server, is_latency_compensation_enabled, crate::network::DEFAULT_CONNECTION_TIMEOUT).unwrap())
         ], None, &mut context).unwrap();
 
         let client1_engine: Engine::<MockContext> = crate::engine::Engine::new(vec![
             Box::new(crate::system::physics::PhysicsSystem{}),
             Box::new(crate::system::keyboard::KeyboardSystem{}),
-            Box::new(crate::system::client::ClientSystem::new(client1_network, crate::input::default_key_mapping()))
+            Box::new(crate::system::client::ClientSystem::new(client1_network, crate::input::default_key_mapping(), crate::network::DEFAULT_CONNECTION_TIMEOUT))
         ], None, &mut context).unwrap();
 
         let mut result = MockSetup{
             context,
             server_engine,
-            client1_engine
+            client1_engine,
+            client1_link
         };
 
-        // process new clients
-        result.step();
+        // complete the handshake so that the client has a gorilla
+        while !result.is_client_admitted() {
+            result.step();
+        }
 
         result
     }
 
+    fn is_client_admitted(&self) -> bool {
+        let mut gorillas = vec![];
+        self.server_engine.get_state().collect_with(&component_filter!(crate::component::gorilla::Gorilla), &mut gorillas);
+        !gorillas.is_empty()
+    }
+
     pub fn step(&mut self){
         self.client1_engine.update(&mut self.context).unwrap();
         self.server_engine.update(&mut self.context).unwrap();
@@ -83,6 +103,63 @@ impl MockSetup{
     }
 }
 
+// A ServerSystem on its own, with an initialised world, updated one tick at a time so that tests can
+// look at what it sends each client
+pub struct ServerHarness<TServer> where TServer: Server<SimNetworkEnd<ServerMsg, ClientMsg>> {
+    pub subject: ServerSystem<TServer, SimNetworkEnd<ServerMsg, ClientMsg>>,
+    pub state: Ecs,
+    pub context: MockContext
+}
+
+impl ServerHarness<SimServer> {
+    pub fn new(event_loop_period: Duration) -> ServerHarness<SimServer> {
+        ServerHarness::with_options(event_loop_period, crate::network::DEFAULT_CONNECTION_TIMEOUT, false)
+    }
+
+    pub fn with_options(event_loop_period: Duration, connection_timeout: Duration, is_latency_compensation_enabled: bool) -> ServerHarness<SimServer> {
+        let context = MockContext::new(event_loop_period);
+        let server = SimServer::new(Duration::from_millis(0), context.network_time());
+        ServerHarness::with_server(context, server, connection_timeout, is_latency_compensation_enabled)
+    }
+
+    // the client end of a new connection that has said the given hello
+    pub fn join(&mut self, hello: Hello) -> SimNetworkEnd<ClientMsg, ServerMsg> {
+        let mut client = self.subject.server_mut().connect();
+        client.enqueue(ClientMsg::Hello(hello), Delivery::ReliableOrdered).unwrap();
+        client
+    }
+}
+
+impl<TServer> ServerHarness<TServer> where TServer: Server<SimNetworkEnd<ServerMsg, ClientMsg>> {
+    pub fn with_server(context: MockContext, server: TServer, connection_timeout: Duration, is_latency_compensation_enabled: bool) -> ServerHarness<TServer> {
+        let mut subject = ServerSystem::new(server, is_latency_compensation_enabled, connection_timeout).unwrap();
+        let mut state = Ecs::new();
+        subject.init(&mut state, &context).unwrap();
+        ServerHarness{
+            subject,
+            state,
+            context
+        }
+    }
+
+    pub fn update(&mut self) {
+        self.subject.update(&mut self.state, &self.context).unwrap();
+    }
+
+    // moves time on by a tick and then updates
+    pub fn step(&mut self) {
+        self.context.step();
+        self.update();
+    }
+}
+
+// everything the server has sent the client since it last looked
+pub fn receive(client: &mut SimNetworkEnd<ClientMsg, ServerMsg>) -> Vec<ServerMsg> {
+    let mut msgs = vec![];
+    client.dequeue(&mut msgs).unwrap();
+    msgs
+}
+
 pub fn assert_roughly_eq(name: &'static str, expected: f32, actual: f32) {
     assert!((expected - actual).abs() < 0.002, "{}: {} != {}", name, expected, actual);
 }
\ No newline at end of file